@@ -1,27 +1,27 @@
 static SECONDS: usize = 1;
 
 fn main() {
-  rportaudio::initialize().unwrap();
-  print_devs();
-  println!("{:?}", demo());
-  rportaudio::terminate().unwrap();
+  let pa = rportaudio::PortAudio::new().unwrap();
+  print_devs(&pa);
+  println!("{:?}", demo(&pa));
+  pa.terminate().unwrap();
 }
 
-fn print_devs() {
-  for i in 0..rportaudio::device::count().unwrap() {
-    match rportaudio::device::info(i) {
+fn print_devs(pa: &rportaudio::PortAudio) {
+  for i in 0..rportaudio::device::count(pa).unwrap() {
+    match rportaudio::device::info(pa, i) {
       None => {}
       Some(info) => println!("{}: {}", i, info.name),
     }
   }
 }
 
-fn demo() -> rportaudio::rpa_error::PaResult {
-  let stream = rportaudio::stream::Stream::open_default(1, 1, 44100.0, rportaudio::stream::FRAMES_PER_BUFFER_UNSPECIFIED, None)?;
+fn demo(pa: &rportaudio::PortAudio) -> rportaudio::rpa_error::PaResult {
+  let stream = rportaudio::stream::Stream::open_default(pa, 1, 1, 44100.0, rportaudio::stream::FRAMES_PER_BUFFER_UNSPECIFIED, None)?;
 
   stream.start()?;
 
-  let input = stream.read(44100)?;
+  let (input, _status) = stream.read(44100)?;
 
   let mut phase = 0.0f32;
   let mut buffer = Vec::with_capacity(44100 * SECONDS);
@@ -38,12 +38,12 @@ fn demo() -> rportaudio::rpa_error::PaResult {
 
   match stream.write(&*buffer) {
     Err(e) => { println!("write 1: Err({:?})", e); }
-    Ok(()) => {}
+    Ok(_status) => {}
   }
 
   match stream.write(&*input) {
     Err(e) => { println!("write 2: Err({:?})", e); }
-    Ok(()) => {}
+    Ok(_status) => {}
   }
 
   let _ = waiter.join();
@@ -1,22 +1,28 @@
-use rportaudio::{device, hostapi, stream, types};
+use rportaudio::{device, hostapi, stream, types, PortAudio};
 use rportaudio::types::{PaStreamCallbackResult, PaStreamParameters, PaStreamTimeInfo};
 use rportaudio::types::PaStreamCallbackResult::Continue;
 
 fn main() {
   println!("version: {} \"{}\"", rportaudio::version(), rportaudio::version_text());
-  println!("init: {:?}", rportaudio::initialize());
+  let pa = match PortAudio::new() {
+    Ok(pa) => pa,
+    Err(e) => {
+      println!("init: Err({:?})", e);
+      return;
+    }
+  };
 
-  print_info();
-  doit();
+  print_info(&pa);
+  doit(&pa);
 
-  println!("term: {:?}", rportaudio::terminate());
+  println!("term: {:?}", pa.terminate());
 }
 
-fn print_info() {
-  match hostapi::count() {
+fn print_info(pa: &PortAudio) {
+  match hostapi::count(pa) {
     Ok(api_count) => {
       for i in 0..api_count {
-        let name = match hostapi::info(i) {
+        let name = match hostapi::info(pa, i) {
           None => "???".to_string(),
           Some(ha) => ha.name,
         };
@@ -26,27 +32,18 @@ fn print_info() {
     _ => {}
   }
 
-  match device::count() {
-    Ok(device_count) => {
-      for i in 0..device_count {
-        let name = match device::info(i) {
-          None => "???".to_string(),
-          Some(d) => d.name,
-        };
-        println!("dev {}: {}", i, name);
-      }
-    }
-    _ => {}
+  for (i, d) in device::all(pa) {
+    println!("dev {}: {}", i, d.name);
   }
 }
 
-fn doit() {
-  callback_demo();
-  write_demo();
-  mixed_demo();
+fn doit(pa: &PortAudio) {
+  callback_demo(pa);
+  write_demo(pa);
+  mixed_demo(pa);
 }
 
-fn callback_demo() {
+fn callback_demo(pa: &PortAudio) {
   let callback = Box::new(|_input: &[f32], output: &mut [f32], _time: PaStreamTimeInfo, _flags: types::PaStreamCallbackFlags| -> PaStreamCallbackResult{
     static mut LP: f32 = 0.0;
     static mut RP: f32 = 0.0;
@@ -72,7 +69,7 @@ fn callback_demo() {
   });
 
   let finished_callback = Box::new(|| println!("Finshed callback called"));
-  let mut stream = match stream::Stream::open_default(0, 2, 44100f64, stream::FRAMES_PER_BUFFER_UNSPECIFIED, Some(callback)) {
+  let mut stream = match stream::Stream::open_default(pa, 0, 2, 44100f64, stream::FRAMES_PER_BUFFER_UNSPECIFIED, Some(callback)) {
     Err(v) => {
       println!("Err({:?})", v);
       return;
@@ -90,8 +87,8 @@ fn callback_demo() {
   println!("stop: {:?}", stream.stop());
 }
 
-fn write_demo() {
-  let stream = match stream::Stream::open_default(0, 2, 44100f64, stream::FRAMES_PER_BUFFER_UNSPECIFIED, None) {
+fn write_demo(pa: &PortAudio) {
+  let stream = match stream::Stream::open_default(pa, 0, 2, 44100f64, stream::FRAMES_PER_BUFFER_UNSPECIFIED, None) {
     Err(v) => {
       println!("Err({:?})", v);
       return;
@@ -119,22 +116,22 @@ fn get_buffer(len: usize) -> Vec<f32> {
   result
 }
 
-fn mixed_demo() {
-  let out_idx = match device::default_output() {
+fn mixed_demo(pa: &PortAudio) {
+  let out_idx = match device::default_output(pa) {
     Some(o) => o,
     None => return,
   };
-  let out_lat = match device::info(out_idx) {
+  let out_lat = match device::info(pa, out_idx) {
     None => return,
     Some(d) => d.default_low_output_latency,
   };
   let output = PaStreamParameters { device: out_idx, channel_count: 2, suggested_latency: out_lat, data: 0i8 };
 
-  let supported = stream::is_format_supported::<i8, _>(None, Some(output), 44100f64);
+  let supported = stream::is_format_supported::<i8, _>(pa, None, Some(output), 44100f64);
   println!("support? {:?}", supported);
   if supported.is_err() { return; }
 
-  let stream = match stream::Stream::<i8, _>::open(None, Some(output), 44100f64, stream::FRAMES_PER_BUFFER_UNSPECIFIED, types::PaStreamFlags::empty(), None) {
+  let stream = match stream::Stream::<i8, _>::open(pa, None, Some(output), 44100f64, stream::FRAMES_PER_BUFFER_UNSPECIFIED, types::PaStreamFlags::empty(), None) {
     Ok(s) => s,
     Err(o) => {
       println!("stream: Err({:?})", o);
@@ -0,0 +1,250 @@
+//! Audio backends the device, host API and stream layers run on
+//!
+//! `PortAudioBackend` talks to the real PortAudio library and is what `PortAudio::new()` uses.
+//! `VirtualBackend` is a pure Rust stand-in with fake devices, so streams can run headless.
+//! `OfflineRenderer` skips the devices altogether and runs a stream callback as fast as it can,
+//! and `SimulatedStream` runs one from a script of buffers, status flags and timestamp jitter.
+
+use std::time::Duration;
+
+use libc::{c_int, c_ulong, c_void};
+
+pub use crate::raw_portaudio::PaStreamCallbackTimeInfo;
+use crate::rpa_error::{ErrorCode, PaError, PaResult};
+use crate::types::{DeviceIndex, HostApiIndex, PaDeviceInfo, PaHostApiInfo, PaHostErrorInfo, PaStreamFlags, PaStreamInfo};
+
+pub use self::offline::{write_wav, OfflineRenderer, WavSample};
+pub use self::portaudio::PortAudioBackend;
+pub use self::sim::{SimulatedBuffer, SimulatedCallback, SimulatedStream};
+pub use self::virt::{VirtualBackend, VirtualDevice};
+
+mod offline;
+mod portaudio;
+mod sim;
+mod virt;
+
+/// Opaque handle of a stream opened by a backend
+pub type StreamHandle = *mut c_void;
+
+/// Buffer callback, using the calling convention of PortAudio's PaStreamCallback
+pub type RawStreamCallback = extern "C" fn(*const c_void, *mut c_void, c_ulong, *const PaStreamCallbackTimeInfo, c_ulong, *mut c_void) -> c_int;
+
+/// Finished callback, using the calling convention of PortAudio's PaStreamFinishedCallback
+pub type RawStreamFinishedCallback = extern "C" fn(*mut c_void);
+
+
+/// Set in the sample format of non-interleaved streams, as PortAudio's paNonInterleaved
+///
+/// Their buffers are arrays of one pointer per channel instead of interleaved frames, for the
+/// callback as well as for `read_stream` and `write_stream`.
+pub const NON_INTERLEAVED: u64 = 0x80000000;
+
+/// Parameters for one direction of a stream, as handed to a backend
+#[derive(Copy, Clone, Debug)]
+pub struct RawStreamParameters {
+  /// Index of the device to use
+  pub device: DeviceIndex,
+
+  /// Requested number of channels
+  pub channel_count: u32,
+
+  /// PortAudio sample format flags, see `SampleType::sample_format`, and `NON_INTERLEAVED`
+  pub sample_format: u64,
+
+  /// Desired latency of the stream
+  pub suggested_latency: Duration,
+}
+
+
+/// The operations the rest of the crate needs from an audio system
+///
+/// The methods mirror the PortAudio C API one to one, and have to follow its semantics: the same
+/// error codes, the stream callback is called from another thread with the user data given to
+/// `open_stream`, and once `close_stream` returns the callbacks are never invoked again.
+pub trait AudioBackend: Send + Sync {
+  /// Called once when a `PortAudio` session is created
+  fn initialize(&self) -> PaResult;
+
+  /// Called once when the last handle of a `PortAudio` session is released
+  fn terminate(&self) -> PaResult;
+
+  /// Get the number of host API's available
+  fn hostapi_count(&self) -> Result<u32, PaError>;
+
+  /// Get the default Host API
+  fn default_hostapi(&self) -> Result<HostApiIndex, PaError>;
+
+  /// Get information about a specific Host API
+  fn hostapi_info(&self, index: HostApiIndex) -> Option<PaHostApiInfo>;
+
+  /// Convert a static host API unique identifier, into a runtime host API index
+  fn hostapi_type_id_to_hostapi_index(&self, type_id: u32) -> Result<HostApiIndex, PaError>;
+
+  /// Converts a device index from a specific host API to a global device index
+  fn hostapi_device_index_to_device_index(&self, hostapi: HostApiIndex, hostapi_device_index: u32) -> Result<DeviceIndex, PaError>;
+
+  /// Return information about the last host error encountered
+  fn last_host_error(&self) -> Option<PaHostErrorInfo>;
+
+  /// Retrieve the number of available devices
+  fn device_count(&self) -> Result<u32, PaError>;
+
+  /// Retrieve the index of the default input device
+  fn default_input_device(&self) -> Option<DeviceIndex>;
+
+  /// Retrieve the index of the default output device
+  fn default_output_device(&self) -> Option<DeviceIndex>;
+
+  /// Get info about a particular device, the default flags are filled in by the caller
+  fn device_info(&self, index: DeviceIndex) -> Option<PaDeviceInfo>;
+
+  /// Returns Ok when the parameters are supported, ignoring the latency field
+  fn is_format_supported(&self, input: Option<&RawStreamParameters>, output: Option<&RawStreamParameters>, sample_rate: f64) -> PaResult;
+
+  /// Open a stream, invoking `callback` with `user_data` when it is given
+  ///
+  /// # Safety
+  ///
+  /// `user_data` must stay valid until the stream is closed.
+  #[allow(clippy::too_many_arguments)]
+  unsafe fn open_stream(&self,
+                        input: Option<&RawStreamParameters>,
+                        output: Option<&RawStreamParameters>,
+                        sample_rate: f64,
+                        frames_per_buffer: u64,
+                        flags: PaStreamFlags,
+                        callback: Option<RawStreamCallback>,
+                        user_data: *mut c_void) -> Result<StreamHandle, PaError>;
+
+  /// Open a stream on the default devices, using their default high latency
+  ///
+  /// # Safety
+  ///
+  /// `user_data` must stay valid until the stream is closed.
+  #[allow(clippy::too_many_arguments)]
+  unsafe fn open_default_stream(&self,
+                                num_input_channels: u32,
+                                num_output_channels: u32,
+                                sample_format: u64,
+                                sample_rate: f64,
+                                frames_per_buffer: u64,
+                                callback: Option<RawStreamCallback>,
+                                user_data: *mut c_void) -> Result<StreamHandle, PaError> {
+    let input = match num_input_channels {
+      0 => None,
+      channel_count => {
+        let device = self.default_input_device().ok_or(ErrorCode::PaDeviceUnavailable)?;
+        let info = self.device_info(device).ok_or(ErrorCode::PaDeviceUnavailable)?;
+        Some(RawStreamParameters { device, channel_count, sample_format, suggested_latency: info.default_high_input_latency })
+      }
+    };
+    let output = match num_output_channels {
+      0 => None,
+      channel_count => {
+        let device = self.default_output_device().ok_or(ErrorCode::PaDeviceUnavailable)?;
+        let info = self.device_info(device).ok_or(ErrorCode::PaDeviceUnavailable)?;
+        Some(RawStreamParameters { device, channel_count, sample_format, suggested_latency: info.default_high_output_latency })
+      }
+    };
+    self.open_stream(input.as_ref(), output.as_ref(), sample_rate, frames_per_buffer, PaStreamFlags::empty(), callback, user_data)
+  }
+
+  /// Set or clear the callback invoked when the stream becomes inactive
+  ///
+  /// # Safety
+  ///
+  /// `stream` must be a handle returned by `open_stream` of this backend which is not closed yet.
+  /// This goes for all the methods taking a stream handle.
+  unsafe fn set_stream_finished_callback(&self, stream: StreamHandle, callback: Option<RawStreamFinishedCallback>) -> PaResult;
+
+  /// Close the stream, after which the handle is no longer valid
+  ///
+  /// # Safety
+  ///
+  /// See `set_stream_finished_callback`.
+  unsafe fn close_stream(&self, stream: StreamHandle) -> PaResult;
+
+  /// Starts the stream
+  ///
+  /// # Safety
+  ///
+  /// See `set_stream_finished_callback`.
+  unsafe fn start_stream(&self, stream: StreamHandle) -> PaResult;
+
+  /// Stops the stream once all pending buffers are played
+  ///
+  /// # Safety
+  ///
+  /// See `set_stream_finished_callback`.
+  unsafe fn stop_stream(&self, stream: StreamHandle) -> PaResult;
+
+  /// Stops the stream immediately
+  ///
+  /// # Safety
+  ///
+  /// See `set_stream_finished_callback`.
+  unsafe fn abort_stream(&self, stream: StreamHandle) -> PaResult;
+
+  /// Returns wether the stream is stopped
+  ///
+  /// # Safety
+  ///
+  /// See `set_stream_finished_callback`.
+  unsafe fn is_stream_stopped(&self, stream: StreamHandle) -> Result<bool, PaError>;
+
+  /// Returns wether the stream is active
+  ///
+  /// # Safety
+  ///
+  /// See `set_stream_finished_callback`.
+  unsafe fn is_stream_active(&self, stream: StreamHandle) -> Result<bool, PaError>;
+
+  /// Get the number of frames that can be read from the stream without waiting
+  ///
+  /// # Safety
+  ///
+  /// See `set_stream_finished_callback`.
+  unsafe fn stream_read_available(&self, stream: StreamHandle) -> Result<u32, PaError>;
+
+  /// Get the number of frames that can be written to the stream without waiting
+  ///
+  /// # Safety
+  ///
+  /// See `set_stream_finished_callback`.
+  unsafe fn stream_write_available(&self, stream: StreamHandle) -> Result<u32, PaError>;
+
+  /// Read `frames` frames into `buffer`, blocking until they are available
+  ///
+  /// # Safety
+  ///
+  /// See `set_stream_finished_callback`, and `buffer` must have room for `frames` frames.
+  unsafe fn read_stream(&self, stream: StreamHandle, buffer: *mut c_void, frames: u64) -> PaResult;
+
+  /// Write `frames` frames from `buffer`, blocking until they are consumed
+  ///
+  /// # Safety
+  ///
+  /// See `set_stream_finished_callback`, and `buffer` must hold `frames` frames.
+  unsafe fn write_stream(&self, stream: StreamHandle, buffer: *const c_void, frames: u64) -> PaResult;
+
+  /// Returns the cpu load the stream callback consumes
+  ///
+  /// # Safety
+  ///
+  /// See `set_stream_finished_callback`.
+  unsafe fn stream_cpu_load(&self, stream: StreamHandle) -> f64;
+
+  /// Get the current timestamp of the stream
+  ///
+  /// # Safety
+  ///
+  /// See `set_stream_finished_callback`.
+  unsafe fn stream_time(&self, stream: StreamHandle) -> Duration;
+
+  /// Get the actual latencies and sample rate
+  ///
+  /// # Safety
+  ///
+  /// See `set_stream_finished_callback`.
+  unsafe fn stream_info(&self, stream: StreamHandle) -> Option<PaStreamInfo>;
+}
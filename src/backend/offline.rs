@@ -0,0 +1,332 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::ptr;
+use std::sync::Arc;
+
+use libc::{c_ulong, c_void};
+
+use crate::{kit, raw_portaudio, rportaudio};
+use crate::backend::PaStreamCallbackTimeInfo;
+use crate::stats::StreamStatistics;
+use crate::types::*;
+
+/// Frames per buffer used when the renderer is asked to pick one
+const DEFAULT_FRAMES_PER_BUFFER: u64 = 512;
+
+
+/// Runs a stream callback without a device, as fast as it can compute
+///
+/// The callback sees the same arguments as on a real stream. The timestamps are derived from the
+/// number of frames rendered so far, without any latency. Input is taken from the samples given
+/// to `with_input`, followed by silence.
+///
+/// ```
+/// use rportaudio::backend::OfflineRenderer;
+/// use rportaudio::types::PaStreamCallbackResult;
+///
+/// let mut phase = 0.0f32;
+/// let mut renderer = OfflineRenderer::new(0, 1, 44100.0, 64, Box::new(move |_input: &[f32], output: &mut [f32], _time, _flags| {
+///   for sample in output.iter_mut() {
+///     *sample = phase;
+///     phase = (phase + 0.01) % 1.0;
+///   }
+///   PaStreamCallbackResult::Continue
+/// }));
+/// assert_eq!(renderer.render(44100).len(), 44160);
+/// ```
+pub struct OfflineRenderer<'a, I: SampleType, O: SampleType> {
+  user_data: Box<StreamUserData<'a, I, O>>,
+  sample_rate: f64,
+  frames_per_buffer: u64,
+  input: Vec<I>,
+  input_position: usize,
+  frame_position: u64,
+}
+
+impl<'a, I, O> OfflineRenderer<'a, I, O>
+  where I: SampleType + Copy + Default, O: SampleType + Copy + Default {
+  /// Prepare to render a callback
+  ///
+  /// ## Arguments
+  /// * num_input_channels: Number of interleaved input channels the callback receives
+  /// * num_output_channels: Number of interleaved output channels the callback produces
+  /// * sample_rate: Sample rate used for the timestamps and the WAV header
+  /// * frames_per_buffer: Number of frames per callback. FRAMES_PER_BUFFER_UNSPECIFIED picks one.
+  /// * callback: The callback to render
+  pub fn new(num_input_channels: u32,
+             num_output_channels: u32,
+             sample_rate: f64,
+             frames_per_buffer: u64,
+             callback: Box<StreamCallback<'a, I, O>>) -> Self {
+    let user_data = Box::new(StreamUserData::new(num_input_channels, num_output_channels, Some(callback)));
+    user_data.stats.set_sample_rate(sample_rate);
+    OfflineRenderer {
+      user_data,
+      sample_rate,
+      frames_per_buffer: match frames_per_buffer {
+        0 => DEFAULT_FRAMES_PER_BUFFER,
+        n => n,
+      },
+      input: Vec::new(),
+      input_position: 0,
+      frame_position: 0,
+    }
+  }
+
+  /// Interleaved samples to feed to the callback as input
+  pub fn with_input(mut self, input: Vec<I>) -> Self {
+    self.input = input;
+    self.input_position = 0;
+    self
+  }
+
+  /// Set a callback which is called every time a render finishes
+  pub fn set_finished_callback(&mut self, finished_callback: Box<StreamFinishedCallback<'a>>) {
+    unsafe { *self.user_data.finished_callback.get() = Some(finished_callback); }
+  }
+
+  /// Statistics of the callback
+  pub fn statistics(&self) -> Arc<StreamStatistics> {
+    self.user_data.stats.clone()
+  }
+
+  /// Take the payload of a panic caught in one of the callbacks
+  pub fn take_panic(&self) -> Option<Box<dyn ::std::any::Any + Send>> {
+    match self.user_data.panic.lock() {
+      Ok(mut slot) => slot.take(),
+      Err(poisoned) => poisoned.into_inner().take(),
+    }
+  }
+
+  /// Number of frames rendered so far, over all calls to render
+  pub fn frame_position(&self) -> u64 {
+    self.frame_position
+  }
+
+  /// Call the callback until it returns Complete or Abort, or until at least `max_frames` frames
+  /// are rendered, and return the interleaved output
+  ///
+  /// Only whole buffers are rendered. Like on a real stream, the output of the buffer which
+  /// returned Abort is dropped, and the finished callback is called at the end. Rendering again
+  /// continues where the previous render stopped.
+  pub fn render(&mut self, max_frames: u64) -> Vec<O> {
+    let frames_per_buffer = self.frames_per_buffer as usize;
+    let mut input = vec![I::default(); frames_per_buffer * self.user_data.num_input as usize];
+    let mut output = vec![O::default(); frames_per_buffer * self.user_data.num_output as usize];
+    let mut rendered = Vec::new();
+    let mut frames = 0;
+    while frames < max_frames {
+      let available = input.len().min(self.input.len().saturating_sub(self.input_position));
+      input[..available].copy_from_slice(&self.input[self.input_position..self.input_position + available]);
+      for sample in input[available..].iter_mut() {
+        *sample = I::default();
+      }
+      self.input_position += available;
+      for sample in output.iter_mut() {
+        *sample = O::default();
+      }
+
+      let time = self.frame_position as f64 / self.sample_rate;
+      let time_info = PaStreamCallbackTimeInfo {
+        inputBufferAdcTime: time,
+        currentTime: time,
+        outputBufferDacTime: time,
+      };
+      let result = run_callback(&self.user_data, &input, &mut output, self.frames_per_buffer, &time_info, PaStreamCallbackFlags::empty());
+      self.frame_position += self.frames_per_buffer;
+      frames += self.frames_per_buffer;
+
+      if result != PaStreamCallbackResult::Abort {
+        rendered.extend_from_slice(&output);
+      }
+      if result != PaStreamCallbackResult::Continue {
+        break;
+      }
+    }
+
+    run_finished_callback(&self.user_data);
+    rendered
+  }
+
+  /// Render like `render`, and write the output to a WAV file
+  ///
+  /// Returns the number of frames written.
+  pub fn render_wav<P: AsRef<Path>>(&mut self, path: P, max_frames: u64) -> io::Result<u64>
+    where O: WavSample {
+    let samples = self.render(max_frames);
+    let channels = self.user_data.num_output;
+    let file = BufWriter::new(File::create(path)?);
+    write_wav(file, channels as u16, self.sample_rate as u32, &samples)?;
+    Ok(match channels {
+      0 => 0,
+      n => samples.len() as u64 / n as u64,
+    })
+  }
+}
+
+
+/// Invoke a stream callback through the same trampoline PortAudio calls, so it gets the same
+/// panic handling
+pub(crate) fn run_callback<I, O>(user_data: &StreamUserData<I, O>,
+                                 input: &[I],
+                                 output: &mut [O],
+                                 frames: u64,
+                                 time_info: &PaStreamCallbackTimeInfo,
+                                 flags: PaStreamCallbackFlags) -> PaStreamCallbackResult {
+  let input_ptr = if input.is_empty() { ptr::null() } else { input.as_ptr() as *const c_void };
+  let output_ptr = if output.is_empty() { ptr::null_mut() } else { output.as_mut_ptr() as *mut c_void };
+  let user_data = user_data as *const StreamUserData<I, O> as *mut c_void;
+  match rportaudio::stream_callback::<I, O>(input_ptr, output_ptr, frames as c_ulong, time_info, flags.bits() as c_ulong, user_data) as u32 {
+    raw_portaudio::paContinue => PaStreamCallbackResult::Continue,
+    raw_portaudio::paComplete => PaStreamCallbackResult::Complete,
+    _ => PaStreamCallbackResult::Abort,
+  }
+}
+
+/// Invoke the finished callback through the PortAudio trampoline
+pub(crate) fn run_finished_callback<I, O>(user_data: &StreamUserData<I, O>) {
+  rportaudio::stream_finished_callback::<I, O>(user_data as *const StreamUserData<I, O> as *mut c_void);
+}
+
+
+/// A sample type which can be stored in a WAV file
+pub trait WavSample: SampleType + Copy {
+  /// Format tag of the fmt chunk, 1 for integer PCM and 3 for IEEE floats
+  fn wav_format_tag() -> u16;
+
+  /// Append the little endian WAV representation of the sample
+  fn write_wav(&self, out: &mut Vec<u8>);
+}
+
+impl WavSample for f32 {
+  fn wav_format_tag() -> u16 { 3 }
+  fn write_wav(&self, out: &mut Vec<u8>) { out.extend_from_slice(&self.to_le_bytes()) }
+}
+
+impl WavSample for i32 {
+  fn wav_format_tag() -> u16 { 1 }
+  fn write_wav(&self, out: &mut Vec<u8>) { out.extend_from_slice(&self.to_le_bytes()) }
+}
+
+impl WavSample for I24 {
+  fn wav_format_tag() -> u16 { 1 }
+  fn write_wav(&self, out: &mut Vec<u8>) { out.extend_from_slice(&self.to_le_bytes()) }
+}
+
+impl WavSample for i16 {
+  fn wav_format_tag() -> u16 { 1 }
+  fn write_wav(&self, out: &mut Vec<u8>) { out.extend_from_slice(&self.to_le_bytes()) }
+}
+
+/// 8 bit WAV files are unsigned, so signed samples are offset
+impl WavSample for i8 {
+  fn wav_format_tag() -> u16 { 1 }
+  fn write_wav(&self, out: &mut Vec<u8>) { out.push((*self as u8) ^ 0x80) }
+}
+
+impl WavSample for u8 {
+  fn wav_format_tag() -> u16 { 1 }
+  fn write_wav(&self, out: &mut Vec<u8>) { out.push(*self) }
+}
+
+/// Write interleaved samples as a canonical RIFF WAV file
+pub fn write_wav<W: Write, T: WavSample>(mut writer: W, channels: u16, sample_rate: u32, samples: &[T]) -> io::Result<()> {
+  let bytes_per_sample = kit::sample_size(T::sample_format()).unwrap_or(0) as u16;
+  let mut data = Vec::with_capacity(samples.len() * bytes_per_sample as usize);
+  for sample in samples {
+    sample.write_wav(&mut data);
+  }
+
+  let block_align = channels * bytes_per_sample;
+  let mut header = Vec::with_capacity(44);
+  header.extend_from_slice(b"RIFF");
+  header.extend_from_slice(&(36 + data.len() as u32).to_le_bytes());
+  header.extend_from_slice(b"WAVEfmt ");
+  header.extend_from_slice(&16u32.to_le_bytes());
+  header.extend_from_slice(&T::wav_format_tag().to_le_bytes());
+  header.extend_from_slice(&channels.to_le_bytes());
+  header.extend_from_slice(&sample_rate.to_le_bytes());
+  header.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+  header.extend_from_slice(&block_align.to_le_bytes());
+  header.extend_from_slice(&(bytes_per_sample * 8).to_le_bytes());
+  header.extend_from_slice(b"data");
+  header.extend_from_slice(&(data.len() as u32).to_le_bytes());
+
+  writer.write_all(&header)?;
+  writer.write_all(&data)?;
+  writer.flush()
+}
+
+
+#[cfg(test)]
+mod test {
+  use std::cell::Cell;
+  use std::time::Duration;
+
+  use crate::types::*;
+
+  use super::{write_wav, OfflineRenderer};
+
+  #[test]
+  fn test_render_until_complete() {
+    let finished = Cell::new(0);
+    let mut times = Vec::new();
+    {
+      let mut calls = 0;
+      let mut renderer = OfflineRenderer::new(1, 2, 1000.0, 4, Box::new(|input: &[i16], output: &mut [i16], time: PaStreamTimeInfo, _flags| {
+        times.push(time.current_time);
+        for (frame, sample) in output.chunks_mut(2).zip(input) {
+          frame[0] = *sample;
+          frame[1] = -*sample;
+        }
+        calls += 1;
+        match calls {
+          3 => PaStreamCallbackResult::Complete,
+          _ => PaStreamCallbackResult::Continue,
+        }
+      })).with_input(vec![1, 2, 3, 4, 5, 6]);
+      renderer.set_finished_callback(Box::new(|| finished.set(finished.get() + 1)));
+
+      let output = renderer.render(1000);
+      assert_eq!(output.len(), 24);
+      assert_eq!(&output[..12], &[1, -1, 2, -2, 3, -3, 4, -4, 5, -5, 6, -6]);
+      assert!(output[12..].iter().all(|s| *s == 0));
+      assert_eq!(renderer.frame_position(), 12);
+    }
+    assert_eq!(finished.get(), 1);
+    assert_eq!(times, vec![Duration::from_millis(0), Duration::from_millis(4), Duration::from_millis(8)]);
+  }
+
+  #[test]
+  fn test_render_abort_and_limit() {
+    let mut calls = 0;
+    let mut renderer = OfflineRenderer::new(0, 1, 48000.0, 10, Box::new(move |_input: &[f32], output: &mut [f32], _time, _flags| {
+      calls += 1;
+      for sample in output.iter_mut() {
+        *sample = calls as f32;
+      }
+      match calls {
+        4 => PaStreamCallbackResult::Abort,
+        _ => PaStreamCallbackResult::Continue,
+      }
+    }));
+
+    // Stops after the buffer reaching the limit, then the aborted buffer is dropped
+    assert_eq!(renderer.render(15).len(), 20);
+    assert_eq!(renderer.render(100), vec![3.0; 10]);
+    assert!(renderer.take_panic().is_none());
+  }
+
+  #[test]
+  fn test_write_wav() {
+    let mut file = Vec::new();
+    write_wav(&mut file, 2, 8000, &[0i16, 1, -1, 2]).unwrap();
+    assert_eq!(file.len(), 44 + 8);
+    assert_eq!(&file[..4], b"RIFF");
+    assert_eq!(&file[4..8], &44u32.to_le_bytes());
+    assert_eq!(&file[20..24], &[1, 0, 2, 0]);
+    assert_eq!(&file[28..36], &[0x00, 0x7d, 0, 0, 4, 0, 16, 0]);
+    assert_eq!(&file[40..], &[8, 0, 0, 0, 0, 0, 1, 0, 0xff, 0xff, 2, 0]);
+  }
+}
@@ -0,0 +1,239 @@
+use std::ptr;
+use std::time::Duration;
+
+use libc::{c_ulong, c_void};
+
+use crate::{kit, raw_portaudio};
+use crate::backend::{AudioBackend, RawStreamCallback, RawStreamFinishedCallback, RawStreamParameters, StreamHandle};
+use crate::rpa_error::{ErrorCode, PaError, PaResult};
+use crate::types::*;
+
+/// The backend calling into the PortAudio library
+#[derive(Debug, Default, Copy, Clone)]
+pub struct PortAudioBackend;
+
+fn to_raw_parameters(parameters: &RawStreamParameters) -> raw_portaudio::PaStreamParameters {
+  raw_portaudio::PaStreamParameters {
+    device: parameters.device as i32,
+    channelCount: parameters.channel_count as i32,
+    sampleFormat: parameters.sample_format as raw_portaudio::PaSampleFormat,
+    suggestedLatency: kit::duration_to_pa_time(parameters.suggested_latency),
+    hostApiSpecificStreamInfo: ptr::null_mut(),
+  }
+}
+
+impl AudioBackend for PortAudioBackend {
+  fn initialize(&self) -> PaResult {
+    // Refuse a library the declarations in pa_include do not describe. Pa_GetVersion is in every
+    // v19, unlike Pa_GetVersionInfo which older libraries lack.
+    if !VersionInfo::from_number(crate::version()).is_compatible() {
+      return Err(ErrorCode::IncompatibleVersion.into());
+    }
+    kit::to_pa_result(unsafe { raw_portaudio::Pa_Initialize() })
+  }
+
+  fn terminate(&self) -> PaResult {
+    kit::to_pa_result(unsafe { raw_portaudio::Pa_Terminate() })
+  }
+
+  fn hostapi_count(&self) -> Result<u32, PaError> {
+    match unsafe { raw_portaudio::Pa_GetHostApiCount() } {
+      n if n >= 0 => Ok(n as HostApiIndex),
+      m => kit::to_pa_result(m).map(|_| 0),
+    }
+  }
+
+  fn default_hostapi(&self) -> Result<HostApiIndex, PaError> {
+    match unsafe { raw_portaudio::Pa_GetDefaultHostApi() } {
+      n if n >= 0 => Ok(n as HostApiIndex),
+      m => kit::to_pa_result(m).map(|_| 0),
+    }
+  }
+
+  fn hostapi_info(&self, index: HostApiIndex) -> Option<PaHostApiInfo> {
+    unsafe {
+      match raw_portaudio::Pa_GetHostApiInfo(index as i32) {
+        p if p.is_null() => None,
+        p => Some(PaHostApiInfo::from_raw(&*p)),
+      }
+    }
+  }
+
+  fn hostapi_type_id_to_hostapi_index(&self, type_id: u32) -> Result<HostApiIndex, PaError> {
+    match unsafe { raw_portaudio::Pa_HostApiTypeIdToHostApiIndex(type_id) } {
+      n if n >= 0 => Ok(n as u32),
+      m => kit::to_pa_result(m).map(|_| 0),
+    }
+  }
+
+  fn hostapi_device_index_to_device_index(&self, hostapi: HostApiIndex, hostapi_device_index: u32) -> Result<DeviceIndex, PaError> {
+    match unsafe { raw_portaudio::Pa_HostApiDeviceIndexToDeviceIndex(hostapi as i32, hostapi_device_index as i32) } {
+      n if n >= 0 => Ok(n as u32),
+      m => kit::to_pa_result(m).map(|_| 0),
+    }
+  }
+
+  fn last_host_error(&self) -> Option<PaHostErrorInfo> {
+    unsafe {
+      match raw_portaudio::Pa_GetLastHostErrorInfo() {
+        p if p.is_null() => None,
+        p => Some(PaHostErrorInfo::from_raw(&*p)),
+      }
+    }
+  }
+
+  fn device_count(&self) -> Result<u32, PaError> {
+    match unsafe { raw_portaudio::Pa_GetDeviceCount() } {
+      n if n >= 0 => Ok(n as u32),
+      m => kit::to_pa_result(m).map(|_| 0),
+    }
+  }
+
+  fn default_input_device(&self) -> Option<DeviceIndex> {
+    match unsafe { raw_portaudio::Pa_GetDefaultInputDevice() } {
+      n if n >= 0 => Some(n as u32),
+      _ => None,
+    }
+  }
+
+  fn default_output_device(&self) -> Option<DeviceIndex> {
+    match unsafe { raw_portaudio::Pa_GetDefaultOutputDevice() } {
+      n if n >= 0 => Some(n as u32),
+      _ => None,
+    }
+  }
+
+  fn device_info(&self, index: DeviceIndex) -> Option<PaDeviceInfo> {
+    unsafe {
+      match raw_portaudio::Pa_GetDeviceInfo(index as i32) {
+        p if p.is_null() => None,
+        p => Some(PaDeviceInfo::from_raw(&*p)),
+      }
+    }
+  }
+
+  fn is_format_supported(&self, input: Option<&RawStreamParameters>, output: Option<&RawStreamParameters>, sample_rate: f64) -> PaResult {
+    let input_obj = input.map(to_raw_parameters);
+    let output_obj = output.map(to_raw_parameters);
+    let input_ptr = input_obj.as_ref().map_or(ptr::null(), |p| p as *const _);
+    let output_ptr = output_obj.as_ref().map_or(ptr::null(), |p| p as *const _);
+
+    kit::to_pa_result(unsafe { raw_portaudio::Pa_IsFormatSupported(input_ptr, output_ptr, sample_rate) })
+  }
+
+  unsafe fn open_stream(&self,
+                        input: Option<&RawStreamParameters>,
+                        output: Option<&RawStreamParameters>,
+                        sample_rate: f64,
+                        frames_per_buffer: u64,
+                        flags: PaStreamFlags,
+                        callback: Option<RawStreamCallback>,
+                        user_data: *mut c_void) -> Result<StreamHandle, PaError> {
+    let input_obj = input.map(to_raw_parameters);
+    let output_obj = output.map(to_raw_parameters);
+    let input_ptr = input_obj.as_ref().map_or(ptr::null(), |p| p as *const _);
+    let output_ptr = output_obj.as_ref().map_or(ptr::null(), |p| p as *const _);
+
+    let mut pa_stream = ptr::null_mut();
+    let result = raw_portaudio::Pa_OpenStream(&mut pa_stream,
+                                              input_ptr,
+                                              output_ptr,
+                                              sample_rate,
+                                              frames_per_buffer as c_ulong,
+                                              flags.bits() as c_ulong,
+                                              callback,
+                                              user_data);
+    kit::to_pa_result(result).map(|_| pa_stream)
+  }
+
+  unsafe fn open_default_stream(&self,
+                                num_input_channels: u32,
+                                num_output_channels: u32,
+                                sample_format: u64,
+                                sample_rate: f64,
+                                frames_per_buffer: u64,
+                                callback: Option<RawStreamCallback>,
+                                user_data: *mut c_void) -> Result<StreamHandle, PaError> {
+    let mut pa_stream = ptr::null_mut();
+    let result = raw_portaudio::Pa_OpenDefaultStream(&mut pa_stream,
+                                                     num_input_channels as i32,
+                                                     num_output_channels as i32,
+                                                     sample_format as c_ulong,
+                                                     sample_rate,
+                                                     frames_per_buffer as c_ulong,
+                                                     callback,
+                                                     user_data);
+    kit::to_pa_result(result).map(|_| pa_stream)
+  }
+
+  unsafe fn set_stream_finished_callback(&self, stream: StreamHandle, callback: Option<RawStreamFinishedCallback>) -> PaResult {
+    kit::to_pa_result(raw_portaudio::Pa_SetStreamFinishedCallback(stream, callback))
+  }
+
+  unsafe fn close_stream(&self, stream: StreamHandle) -> PaResult {
+    kit::to_pa_result(raw_portaudio::Pa_CloseStream(stream))
+  }
+
+  unsafe fn start_stream(&self, stream: StreamHandle) -> PaResult {
+    kit::to_pa_result(raw_portaudio::Pa_StartStream(stream))
+  }
+
+  unsafe fn stop_stream(&self, stream: StreamHandle) -> PaResult {
+    kit::to_pa_result(raw_portaudio::Pa_StopStream(stream))
+  }
+
+  unsafe fn abort_stream(&self, stream: StreamHandle) -> PaResult {
+    kit::to_pa_result(raw_portaudio::Pa_AbortStream(stream))
+  }
+
+  unsafe fn is_stream_stopped(&self, stream: StreamHandle) -> Result<bool, PaError> {
+    match raw_portaudio::Pa_IsStreamStopped(stream) {
+      1 => Ok(true),
+      n => kit::to_pa_result(n).map(|_| false),
+    }
+  }
+
+  unsafe fn is_stream_active(&self, stream: StreamHandle) -> Result<bool, PaError> {
+    match raw_portaudio::Pa_IsStreamActive(stream) {
+      1 => Ok(true),
+      n => kit::to_pa_result(n).map(|_| false),
+    }
+  }
+
+  unsafe fn stream_read_available(&self, stream: StreamHandle) -> Result<u32, PaError> {
+    match raw_portaudio::Pa_GetStreamReadAvailable(stream) {
+      n if n >= 0 => { Ok(n as u32) }
+      n => kit::to_pa_result(n as i32).map(|_| 0),
+    }
+  }
+
+  unsafe fn stream_write_available(&self, stream: StreamHandle) -> Result<u32, PaError> {
+    match raw_portaudio::Pa_GetStreamWriteAvailable(stream) {
+      n if n >= 0 => { Ok(n as u32) }
+      n => kit::to_pa_result(n as i32).map(|_| 0),
+    }
+  }
+
+  unsafe fn read_stream(&self, stream: StreamHandle, buffer: *mut c_void, frames: u64) -> PaResult {
+    kit::to_pa_result(raw_portaudio::Pa_ReadStream(stream, buffer, frames as c_ulong))
+  }
+
+  unsafe fn write_stream(&self, stream: StreamHandle, buffer: *const c_void, frames: u64) -> PaResult {
+    kit::to_pa_result(raw_portaudio::Pa_WriteStream(stream, buffer, frames as c_ulong))
+  }
+
+  unsafe fn stream_cpu_load(&self, stream: StreamHandle) -> f64 {
+    raw_portaudio::Pa_GetStreamCpuLoad(stream)
+  }
+
+  unsafe fn stream_time(&self, stream: StreamHandle) -> Duration {
+    kit::pa_time_to_duration(raw_portaudio::Pa_GetStreamTime(stream))
+  }
+
+  unsafe fn stream_info(&self, stream: StreamHandle) -> Option<PaStreamInfo> {
+    match raw_portaudio::Pa_GetStreamInfo(stream) {
+      p if p.is_null() => None,
+      p => Some(PaStreamInfo::from_raw(&*p)),
+    }
+  }
+}
@@ -0,0 +1,282 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::kit;
+use crate::backend::PaStreamCallbackTimeInfo;
+use crate::backend::offline::{run_callback, run_finished_callback};
+use crate::stats::StreamStatistics;
+use crate::types::*;
+
+
+/// One step of the script of a `SimulatedStream`
+#[derive(Copy, Clone, Debug)]
+pub struct SimulatedBuffer {
+  /// Number of frames handed to the callback
+  pub frames: u64,
+
+  /// Status flags the callback receives
+  pub flags: PaStreamCallbackFlags,
+
+  /// Offset in seconds added to the input ADC time, which may be negative
+  pub input_adc_jitter: f64,
+
+  /// Offset in seconds added to the current time, which may be negative
+  pub current_jitter: f64,
+
+  /// Offset in seconds added to the output DAC time, which may be negative
+  pub output_dac_jitter: f64,
+}
+
+impl SimulatedBuffer {
+  /// A buffer of `frames` frames without flags or jitter
+  pub fn new(frames: u64) -> Self {
+    SimulatedBuffer {
+      frames,
+      flags: PaStreamCallbackFlags::empty(),
+      input_adc_jitter: 0.0,
+      current_jitter: 0.0,
+      output_dac_jitter: 0.0,
+    }
+  }
+
+  /// Raise status flags for this buffer
+  pub fn with_flags(mut self, flags: PaStreamCallbackFlags) -> Self {
+    self.flags = flags;
+    self
+  }
+
+  /// Shift the timestamps of this buffer, in seconds
+  pub fn with_jitter(mut self, input_adc: f64, current: f64, output_dac: f64) -> Self {
+    self.input_adc_jitter = input_adc;
+    self.current_jitter = current;
+    self.output_dac_jitter = output_dac;
+    self
+  }
+}
+
+
+/// What happened during one callback of a `SimulatedStream`
+#[derive(Clone, Debug, PartialEq)]
+pub struct SimulatedCallback<O> {
+  /// Number of frames handed to the callback
+  pub frames: u64,
+
+  /// Status flags the callback received
+  pub flags: PaStreamCallbackFlags,
+
+  /// Timestamps the callback received
+  pub time_info: PaStreamTimeInfo,
+
+  /// What the callback returned
+  pub result: PaStreamCallbackResult,
+
+  /// The interleaved output the callback produced
+  pub output: Vec<O>,
+}
+
+
+/// Drives a stream callback from a script, on a deterministic clock
+///
+/// Every buffer of the script results in one callback, until the callback returns Complete or
+/// Abort. The clock is advanced by the frames of each buffer: the current time of a buffer is the
+/// start time plus the frames before it divided by the sample rate, the input ADC time is that
+/// minus the input latency and the output DAC time that plus the output latency, each with the
+/// jitter of the buffer added. Timestamps which would be negative are clamped to zero.
+///
+/// Input is taken from the samples given to `with_input`. Buffers flagged with INPUT_UNDERFLOW or
+/// PRIMING_OUTPUT receive silence instead, without consuming input.
+///
+/// ```
+/// use rportaudio::backend::{SimulatedBuffer, SimulatedStream};
+/// use rportaudio::types::*;
+///
+/// let mut stream = SimulatedStream::new(0, 1, 48000.0, Box::new(|_input: &[f32], _output: &mut [f32], _time, flags: PaStreamCallbackFlags| {
+///   match flags.contains(OUTPUT_UNDERFLOW) {
+///     true => PaStreamCallbackResult::Abort,
+///     false => PaStreamCallbackResult::Continue,
+///   }
+/// }));
+/// let record = stream.run(&[SimulatedBuffer::new(64), SimulatedBuffer::new(64).with_flags(OUTPUT_UNDERFLOW)]);
+/// assert_eq!(record[1].result, PaStreamCallbackResult::Abort);
+/// ```
+pub struct SimulatedStream<'a, I: SampleType, O: SampleType> {
+  user_data: Box<StreamUserData<'a, I, O>>,
+  sample_rate: f64,
+  start_time: Duration,
+  input_latency: Duration,
+  output_latency: Duration,
+  input: Vec<I>,
+  input_position: usize,
+  frame_position: u64,
+}
+
+impl<'a, I, O> SimulatedStream<'a, I, O>
+  where I: SampleType + Copy + Default, O: SampleType + Copy + Default {
+  /// Prepare to drive a callback
+  ///
+  /// ## Arguments
+  /// * num_input_channels: Number of interleaved input channels the callback receives
+  /// * num_output_channels: Number of interleaved output channels the callback produces
+  /// * sample_rate: Sample rate the clock runs at
+  /// * callback: The callback to drive
+  pub fn new(num_input_channels: u32,
+             num_output_channels: u32,
+             sample_rate: f64,
+             callback: Box<StreamCallback<'a, I, O>>) -> Self {
+    let user_data = Box::new(StreamUserData::new(num_input_channels, num_output_channels, Some(callback)));
+    user_data.stats.set_sample_rate(sample_rate);
+    SimulatedStream {
+      user_data,
+      sample_rate,
+      start_time: Duration::from_secs(0),
+      input_latency: Duration::from_secs(0),
+      output_latency: Duration::from_secs(0),
+      input: Vec::new(),
+      input_position: 0,
+      frame_position: 0,
+    }
+  }
+
+  /// Current time of the first buffer
+  pub fn with_start_time(mut self, start_time: Duration) -> Self {
+    self.start_time = start_time;
+    self
+  }
+
+  /// Latencies between the current time and the ADC and DAC times
+  pub fn with_latency(mut self, input_latency: Duration, output_latency: Duration) -> Self {
+    self.input_latency = input_latency;
+    self.output_latency = output_latency;
+    self
+  }
+
+  /// Interleaved samples to feed to the callback as input
+  pub fn with_input(mut self, input: Vec<I>) -> Self {
+    self.input = input;
+    self.input_position = 0;
+    self
+  }
+
+  /// Set a callback which is called every time a run finishes
+  pub fn set_finished_callback(&mut self, finished_callback: Box<StreamFinishedCallback<'a>>) {
+    unsafe { *self.user_data.finished_callback.get() = Some(finished_callback); }
+  }
+
+  /// Statistics of the callback
+  pub fn statistics(&self) -> Arc<StreamStatistics> {
+    self.user_data.stats.clone()
+  }
+
+  /// Take the payload of a panic caught in one of the callbacks
+  pub fn take_panic(&self) -> Option<Box<dyn ::std::any::Any + Send>> {
+    match self.user_data.panic.lock() {
+      Ok(mut slot) => slot.take(),
+      Err(poisoned) => poisoned.into_inner().take(),
+    }
+  }
+
+  /// Run the script and return a record of every callback that was made
+  ///
+  /// The finished callback is called at the end. Running another script continues the clock where
+  /// this one stopped.
+  pub fn run(&mut self, script: &[SimulatedBuffer]) -> Vec<SimulatedCallback<O>> {
+    let num_input = self.user_data.num_input as usize;
+    let num_output = self.user_data.num_output as usize;
+    let mut record = Vec::with_capacity(script.len());
+
+    for buffer in script {
+      let frames = buffer.frames as usize;
+      let mut input = vec![I::default(); frames * num_input];
+      if !buffer.flags.intersects(INPUT_UNDERFLOW | PRIMING_OUTPUT) {
+        let available = input.len().min(self.input.len().saturating_sub(self.input_position));
+        input[..available].copy_from_slice(&self.input[self.input_position..self.input_position + available]);
+        self.input_position += available;
+      }
+      let mut output = vec![O::default(); frames * num_output];
+
+      let now = kit::duration_to_pa_time(self.start_time) + self.frame_position as f64 / self.sample_rate;
+      let time_info = PaStreamCallbackTimeInfo {
+        inputBufferAdcTime: now - kit::duration_to_pa_time(self.input_latency) + buffer.input_adc_jitter,
+        currentTime: now + buffer.current_jitter,
+        outputBufferDacTime: now + kit::duration_to_pa_time(self.output_latency) + buffer.output_dac_jitter,
+      };
+      let result = run_callback(&self.user_data, &input, &mut output, buffer.frames, &time_info, buffer.flags);
+      self.frame_position += buffer.frames;
+
+      record.push(SimulatedCallback {
+        frames: buffer.frames,
+        flags: buffer.flags,
+        time_info: PaStreamTimeInfo::from_raw(&time_info),
+        result,
+        output,
+      });
+      if result != PaStreamCallbackResult::Continue {
+        break;
+      }
+    }
+
+    run_finished_callback(&self.user_data);
+    record
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use std::time::Duration;
+
+  use crate::types::*;
+
+  use super::{SimulatedBuffer, SimulatedStream};
+
+  #[test]
+  fn test_script() {
+    let mut seen = Vec::new();
+    let record = {
+      let mut stream = SimulatedStream::new(1, 1, 1000.0, Box::new(|input: &[i32], output: &mut [i32], _time, flags: PaStreamCallbackFlags| {
+        seen.push(flags);
+        output.copy_from_slice(input);
+        match flags.contains(INPUT_OVERFLOW) {
+          true => PaStreamCallbackResult::Complete,
+          false => PaStreamCallbackResult::Continue,
+        }
+      })).with_input(vec![1, 2, 3, 4, 5, 6]);
+
+      stream.run(&[
+        SimulatedBuffer::new(2).with_flags(PRIMING_OUTPUT),
+        SimulatedBuffer::new(3),
+        SimulatedBuffer::new(1).with_flags(INPUT_UNDERFLOW | OUTPUT_UNDERFLOW),
+        SimulatedBuffer::new(2).with_flags(INPUT_OVERFLOW),
+        SimulatedBuffer::new(2),
+      ])
+    };
+
+    assert_eq!(seen, vec![PRIMING_OUTPUT, PaStreamCallbackFlags::empty(), INPUT_UNDERFLOW | OUTPUT_UNDERFLOW, INPUT_OVERFLOW]);
+    assert_eq!(record.len(), 4);
+    assert_eq!(record[0].output, vec![0, 0]);
+    assert_eq!(record[1].output, vec![1, 2, 3]);
+    assert_eq!(record[2].output, vec![0]);
+    assert_eq!(record[3].output, vec![4, 5]);
+    assert_eq!(record[3].result, PaStreamCallbackResult::Complete);
+  }
+
+  #[test]
+  fn test_clock() {
+    let mut stream = SimulatedStream::new(0, 1, 100.0, Box::new(|_input: &[f32], _output: &mut [f32], _time, _flags| PaStreamCallbackResult::Continue))
+      .with_start_time(Duration::from_secs(1))
+      .with_latency(Duration::from_millis(20), Duration::from_millis(30));
+
+    let record = stream.run(&[SimulatedBuffer::new(10), SimulatedBuffer::new(5).with_jitter(-0.005, 0.001, 0.0)]);
+    assert_eq!(record[0].time_info.current_time, Duration::from_secs(1));
+    assert!((record[0].time_info.output_dac_time.as_secs_f64() - 1.03).abs() < 1e-6);
+
+    let time_info = record[1].time_info;
+    assert!((time_info.input_adc_time.as_secs_f64() - 1.075).abs() < 1e-6);
+    assert!((time_info.current_time.as_secs_f64() - 1.101).abs() < 1e-6);
+    assert!((time_info.output_dac_time.as_secs_f64() - 1.130).abs() < 1e-6);
+
+    // The clock carries over to the next run
+    let record = stream.run(&[SimulatedBuffer::new(1)]);
+    assert!((record[0].time_info.current_time.as_secs_f64() - 1.15).abs() < 1e-6);
+  }
+}
@@ -0,0 +1,826 @@
+use std::collections::VecDeque;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use libc::c_void;
+
+use crate::{kit, raw_portaudio};
+use crate::backend::{AudioBackend, PaStreamCallbackTimeInfo, RawStreamCallback, RawStreamFinishedCallback, RawStreamParameters, StreamHandle, NON_INTERLEAVED};
+use crate::rpa_error::{ErrorCode, PaError, PaResult};
+use crate::types::*;
+
+/// Index of the one host API the virtual backend exposes
+const HOST_API: HostApiIndex = 0;
+
+/// Frames per buffer used when the stream leaves it to the backend, 10ms at 48kHz
+const DEFAULT_FRAMES_PER_BUFFER: u64 = 480;
+
+
+/// A fake device of the `VirtualBackend`
+#[derive(Debug, Clone)]
+pub struct VirtualDevice {
+  /// Human readable name
+  pub name: String,
+
+  /// Maximal number of input channels that can be used
+  pub max_input_channels: u32,
+
+  /// Maximal number of ouput channels that can be used
+  pub max_output_channels: u32,
+
+  /// Default sample rate
+  pub default_sample_rate: f64,
+
+  /// Sample rates streams can use, any positive rate when empty
+  pub sample_rates: Vec<f64>,
+
+  /// Latency reported for both directions
+  pub latency: Duration,
+
+  /// Feed everything played on the device back to its input
+  pub loopback: bool,
+}
+
+impl VirtualDevice {
+  /// An input-only device
+  pub fn input(name: &str, channels: u32) -> Self {
+    Self {
+      name: name.to_string(),
+      max_input_channels: channels,
+      max_output_channels: 0,
+      default_sample_rate: 48000.0,
+      sample_rates: Vec::new(),
+      latency: Duration::from_millis(10),
+      loopback: false,
+    }
+  }
+
+  /// An output-only device, which discards what is played
+  pub fn output(name: &str, channels: u32) -> Self {
+    Self { max_input_channels: 0, max_output_channels: channels, ..Self::input(name, 0) }
+  }
+
+  /// A device whose input records what is played on its output
+  pub fn loopback(name: &str, channels: u32) -> Self {
+    Self { max_input_channels: channels, max_output_channels: channels, loopback: true, ..Self::input(name, 0) }
+  }
+
+  /// Change the default sample rate
+  pub fn with_sample_rate(mut self, sample_rate: f64) -> Self {
+    self.default_sample_rate = sample_rate;
+    self
+  }
+
+  /// Only allow streams at the given sample rates
+  pub fn with_sample_rates(mut self, sample_rates: &[f64]) -> Self {
+    self.sample_rates = sample_rates.to_vec();
+    self
+  }
+
+  /// Change the latency
+  pub fn with_latency(mut self, latency: Duration) -> Self {
+    self.latency = latency;
+    self
+  }
+}
+
+
+/// Bytes played on a loopback device which were not recorded yet
+type LoopbackQueue = Arc<Mutex<VecDeque<u8>>>;
+
+struct VirtualDeviceState {
+  device: VirtualDevice,
+  queue: LoopbackQueue,
+}
+
+/// A pure Rust backend with configurable fake devices
+///
+/// Callback streams are driven by a timer thread at the pace of the sample rate, blocking reads
+/// and writes are paced the same way. Input devices record silence, except for loopback devices
+/// which record what was played on them.
+///
+/// ```
+/// use rportaudio::backend::{VirtualBackend, VirtualDevice};
+///
+/// let backend = VirtualBackend::new()
+///   .with_device(VirtualDevice::input("Microphone", 1))
+///   .with_device(VirtualDevice::output("Speakers", 2));
+/// let pa = rportaudio::PortAudio::with_backend(backend).unwrap();
+/// assert_eq!(rportaudio::device::default_output(&pa), Some(1));
+/// ```
+pub struct VirtualBackend {
+  devices: Vec<VirtualDeviceState>,
+  origin: Instant,
+}
+
+impl VirtualBackend {
+  /// A backend without any device
+  pub fn new() -> Self {
+    Self { devices: Vec::new(), origin: Instant::now() }
+  }
+
+  /// Add a device. The first input and output capable devices are the defaults.
+  pub fn with_device(mut self, device: VirtualDevice) -> Self {
+    self.devices.push(VirtualDeviceState { device, queue: Arc::new(Mutex::new(VecDeque::new())) });
+    self
+  }
+
+  fn device(&self, index: DeviceIndex) -> Result<&VirtualDeviceState, PaError> {
+    self.devices.get(index as usize).ok_or_else(|| ErrorCode::PaInvalidDevice.into())
+  }
+
+  fn check_parameters(&self, parameters: &RawStreamParameters, sample_rate: f64, max_channels: fn(&VirtualDevice) -> u32) -> PaResult {
+    let device = &self.device(parameters.device)?.device;
+    if parameters.channel_count == 0 || parameters.channel_count > max_channels(device) {
+      return Err(ErrorCode::PaInvalidChannelCount.into());
+    }
+    if kit::sample_size(parameters.sample_format).is_none() {
+      return Err(ErrorCode::PaSampleFormatNotSupported.into());
+    }
+    if !device.sample_rates.is_empty() && !device.sample_rates.contains(&sample_rate) {
+      return Err(ErrorCode::PaInvalidSampleRate.into());
+    }
+    Ok(())
+  }
+
+  fn check_format(&self, input: Option<&RawStreamParameters>, output: Option<&RawStreamParameters>, sample_rate: f64) -> PaResult {
+    if input.is_none() && output.is_none() {
+      return Err(ErrorCode::PaBadIODeviceCombination.into());
+    }
+    if sample_rate.is_nan() || sample_rate <= 0.0 {
+      return Err(ErrorCode::PaInvalidSampleRate.into());
+    }
+    if let Some(parameters) = input {
+      self.check_parameters(parameters, sample_rate, |d| d.max_input_channels)?;
+    }
+    if let Some(parameters) = output {
+      self.check_parameters(parameters, sample_rate, |d| d.max_output_channels)?;
+    }
+    Ok(())
+  }
+
+  fn direction(&self, parameters: &RawStreamParameters) -> Direction {
+    let state = &self.devices[parameters.device as usize];
+    Direction {
+      frame_bytes: parameters.channel_count as usize * kit::sample_size(parameters.sample_format).unwrap_or(0),
+      channels: parameters.channel_count as usize,
+      non_interleaved: parameters.sample_format & NON_INTERLEAVED != 0,
+      queue: match state.device.loopback {
+        true => Some(state.queue.clone()),
+        false => None,
+      },
+      latency: state.device.latency,
+    }
+  }
+}
+
+impl Default for VirtualBackend {
+  /// A backend with a single stereo loopback device
+  fn default() -> Self {
+    VirtualBackend::new().with_device(VirtualDevice::loopback("Virtual Loopback", 2))
+  }
+}
+
+
+/// One direction of an open stream
+#[derive(Clone)]
+struct Direction {
+  frame_bytes: usize,
+  channels: usize,
+  non_interleaved: bool,
+  queue: Option<LoopbackQueue>,
+  latency: Duration,
+}
+
+impl Direction {
+  /// Fill the buffer with what was played on a loopback device, or silence
+  fn record(&self, buffer: &mut [u8]) {
+    for b in buffer.iter_mut() {
+      *b = 0;
+    }
+    if let Some(ref queue) = self.queue {
+      let mut queue = queue.lock().unwrap_or_else(|e| e.into_inner());
+      let len = buffer.len().min(queue.len());
+      for (b, q) in buffer.iter_mut().zip(queue.drain(..len)) {
+        *b = q;
+      }
+    }
+  }
+
+  /// Hand the buffer to a loopback device, which keeps at most a second of audio
+  fn play(&self, buffer: &[u8], sample_rate: f64) {
+    if let Some(ref queue) = self.queue {
+      let mut queue = queue.lock().unwrap_or_else(|e| e.into_inner());
+      queue.extend(buffer.iter().cloned());
+      let limit = self.frame_bytes * sample_rate.ceil() as usize;
+      if queue.len() > limit {
+        let excess = queue.len() - limit;
+        queue.drain(..excess);
+      }
+    }
+  }
+
+  /// Copy interleaved frames into a buffer of the stream, which is an array of one pointer per
+  /// channel for a non-interleaved stream
+  unsafe fn scatter(&self, frames: &[u8], buffer: *mut c_void) {
+    if !self.non_interleaved {
+      kit::buffer_slice_mut(buffer as *mut u8, frames.len()).copy_from_slice(frames);
+      return;
+    }
+    let sample_bytes = self.frame_bytes / self.channels;
+    let planes = kit::buffer_slice(buffer as *const *mut u8, self.channels);
+    for (channel, &plane) in planes.iter().enumerate() {
+      let plane = kit::buffer_slice_mut(plane, frames.len() / self.channels);
+      for (sample, frame) in plane.chunks_mut(sample_bytes).zip(frames.chunks(self.frame_bytes)) {
+        sample.copy_from_slice(&frame[channel * sample_bytes..(channel + 1) * sample_bytes]);
+      }
+    }
+  }
+
+  /// Copy a buffer of the stream into interleaved frames, the reverse of `scatter`
+  unsafe fn gather(&self, buffer: *const c_void, frames: &mut [u8]) {
+    if !self.non_interleaved {
+      frames.copy_from_slice(kit::buffer_slice(buffer as *const u8, frames.len()));
+      return;
+    }
+    let sample_bytes = self.frame_bytes / self.channels;
+    let planes = kit::buffer_slice(buffer as *const *const u8, self.channels);
+    for (channel, &plane) in planes.iter().enumerate() {
+      let plane = kit::buffer_slice(plane, frames.len() / self.channels);
+      for (sample, frame) in plane.chunks(sample_bytes).zip(frames.chunks_mut(self.frame_bytes)) {
+        frame[channel * sample_bytes..(channel + 1) * sample_bytes].copy_from_slice(sample);
+      }
+    }
+  }
+}
+
+/// Memory for one direction of the callback, laid out as the stream asked for
+struct CallbackBuffer {
+  data: Vec<u8>,
+  /// Start of every channel in `data` for a non-interleaved stream
+  planes: Vec<*mut u8>,
+}
+
+impl CallbackBuffer {
+  fn new(direction: Option<&Direction>, frames: usize) -> Self {
+    let mut data = vec![0u8; direction.map_or(0, |d| d.frame_bytes) * frames];
+    let planes = match direction {
+      Some(d) if d.non_interleaved => {
+        let plane_bytes = data.len() / d.channels;
+        (0..d.channels).map(|channel| unsafe { data.as_mut_ptr().add(channel * plane_bytes) }).collect()
+      }
+      _ => Vec::new(),
+    };
+    Self { data, planes }
+  }
+
+  /// The pointer handed to the callback
+  fn pointer(&mut self) -> *mut c_void {
+    match self.planes.is_empty() {
+      true => self.data.as_mut_ptr() as *mut c_void,
+      false => self.planes.as_mut_ptr() as *mut c_void,
+    }
+  }
+}
+
+/// The user data pointer, which the contract of `open_stream` allows us to use on another thread
+#[derive(Copy, Clone)]
+struct UserData(*mut c_void);
+
+unsafe impl Send for UserData {}
+
+struct SharedState {
+  stop: AtomicBool,
+  active: AtomicBool,
+  /// Bits of the f64 cpu load
+  cpu_load: AtomicU64,
+}
+
+struct VirtualStream {
+  input: Option<Direction>,
+  output: Option<Direction>,
+  sample_rate: f64,
+  frames_per_buffer: u64,
+  callback: Option<RawStreamCallback>,
+  finished_callback: Option<RawStreamFinishedCallback>,
+  user_data: UserData,
+  origin: Instant,
+  shared: Arc<SharedState>,
+  worker: Option<JoinHandle<()>>,
+  stopped: bool,
+  started_at: Instant,
+  frames_read: u64,
+  frames_written: u64,
+}
+
+impl VirtualStream {
+  fn frames_to_duration(&self, frames: u64) -> Duration {
+    kit::pa_time_to_duration(frames as f64 / self.sample_rate)
+  }
+
+  fn elapsed_frames(&self) -> u64 {
+    (kit::duration_to_pa_time(self.started_at.elapsed()) * self.sample_rate) as u64
+  }
+
+  fn halt(&mut self) -> PaResult {
+    if self.stopped {
+      return Err(ErrorCode::PaStreamIsStopped.into());
+    }
+    self.stopped = true;
+    self.shared.stop.store(true, Ordering::SeqCst);
+    match self.worker.take() {
+      // The worker calls the finished callback itself
+      Some(worker) => { let _ = worker.join(); }
+      None => {
+        self.shared.active.store(false, Ordering::SeqCst);
+        if let Some(finished_callback) = self.finished_callback {
+          finished_callback(self.user_data.0);
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
+/// The timer thread driving a callback stream
+struct Worker {
+  input: Option<Direction>,
+  output: Option<Direction>,
+  sample_rate: f64,
+  frames_per_buffer: u64,
+  callback: RawStreamCallback,
+  finished_callback: Option<RawStreamFinishedCallback>,
+  user_data: UserData,
+  origin: Instant,
+  shared: Arc<SharedState>,
+}
+
+impl Worker {
+  fn run(self) {
+    let period = self.frames_per_buffer as f64 / self.sample_rate;
+    let mut input = vec![0u8; self.input.as_ref().map_or(0, |d| d.frame_bytes) * self.frames_per_buffer as usize];
+    let mut output = vec![0u8; self.output.as_ref().map_or(0, |d| d.frame_bytes) * self.frames_per_buffer as usize];
+    let mut input_buffer = CallbackBuffer::new(self.input.as_ref(), self.frames_per_buffer as usize);
+    let mut output_buffer = CallbackBuffer::new(self.output.as_ref(), self.frames_per_buffer as usize);
+    let started_at = Instant::now();
+    let mut frames_done = 0u64;
+
+    while !self.shared.stop.load(Ordering::SeqCst) {
+      if let Some(ref direction) = self.input {
+        direction.record(&mut input);
+        unsafe { direction.scatter(&input, input_buffer.pointer()) };
+      }
+      for b in output_buffer.data.iter_mut() {
+        *b = 0;
+      }
+
+      let now = kit::duration_to_pa_time(self.origin.elapsed());
+      let time_info = PaStreamCallbackTimeInfo {
+        inputBufferAdcTime: now - period - self.input.as_ref().map_or(0.0, |d| kit::duration_to_pa_time(d.latency)),
+        currentTime: now,
+        outputBufferDacTime: now + self.output.as_ref().map_or(0.0, |d| kit::duration_to_pa_time(d.latency)),
+      };
+
+      let began = Instant::now();
+      let result = (self.callback)(if self.input.is_some() { input_buffer.pointer() as *const c_void } else { ptr::null() },
+                                   if self.output.is_some() { output_buffer.pointer() } else { ptr::null_mut() },
+                                   self.frames_per_buffer as libc::c_ulong,
+                                   &time_info,
+                                   0,
+                                   self.user_data.0);
+      let load = kit::duration_to_pa_time(began.elapsed()) / period;
+      self.shared.cpu_load.store(load.to_bits(), Ordering::Relaxed);
+
+      if result as u32 != raw_portaudio::paAbort {
+        if let Some(ref direction) = self.output {
+          unsafe { direction.gather(output_buffer.pointer(), &mut output) };
+          direction.play(&output, self.sample_rate);
+        }
+      }
+      if result as u32 != raw_portaudio::paContinue {
+        break;
+      }
+
+      frames_done += self.frames_per_buffer;
+      let due = started_at + kit::pa_time_to_duration(frames_done as f64 / self.sample_rate);
+      let now = Instant::now();
+      if due > now {
+        thread::sleep(due - now);
+      }
+    }
+
+    self.shared.active.store(false, Ordering::SeqCst);
+    if let Some(finished_callback) = self.finished_callback {
+      finished_callback(self.user_data.0);
+    }
+  }
+}
+
+unsafe fn stream_mut<'s>(stream: StreamHandle) -> &'s mut VirtualStream {
+  &mut *(stream as *mut VirtualStream)
+}
+
+impl AudioBackend for VirtualBackend {
+  fn initialize(&self) -> PaResult {
+    Ok(())
+  }
+
+  fn terminate(&self) -> PaResult {
+    Ok(())
+  }
+
+  fn hostapi_count(&self) -> Result<u32, PaError> {
+    Ok(1)
+  }
+
+  fn default_hostapi(&self) -> Result<HostApiIndex, PaError> {
+    Ok(HOST_API)
+  }
+
+  fn hostapi_info(&self, index: HostApiIndex) -> Option<PaHostApiInfo> {
+    if index != HOST_API {
+      return None;
+    }
+    Some(PaHostApiInfo {
+      struct_version: 1,
+      type_: HostApiType::InDevelopment,
+      name: "Virtual".to_string(),
+      device_count: self.devices.len() as u32,
+      default_input: self.default_input_device(),
+      default_output: self.default_output_device(),
+    })
+  }
+
+  fn hostapi_type_id_to_hostapi_index(&self, type_id: u32) -> Result<HostApiIndex, PaError> {
+    match type_id {
+      raw_portaudio::paInDevelopment => Ok(HOST_API),
+      _ => Err(ErrorCode::PaHostApiNotFound.into()),
+    }
+  }
+
+  fn hostapi_device_index_to_device_index(&self, hostapi: HostApiIndex, hostapi_device_index: u32) -> Result<DeviceIndex, PaError> {
+    if hostapi != HOST_API {
+      return Err(ErrorCode::PaInvalidHostApi.into());
+    }
+    self.device(hostapi_device_index).map(|_| hostapi_device_index)
+  }
+
+  fn last_host_error(&self) -> Option<PaHostErrorInfo> {
+    None
+  }
+
+  fn device_count(&self) -> Result<u32, PaError> {
+    Ok(self.devices.len() as u32)
+  }
+
+  fn default_input_device(&self) -> Option<DeviceIndex> {
+    self.devices.iter().position(|d| d.device.max_input_channels > 0).map(|i| i as DeviceIndex)
+  }
+
+  fn default_output_device(&self) -> Option<DeviceIndex> {
+    self.devices.iter().position(|d| d.device.max_output_channels > 0).map(|i| i as DeviceIndex)
+  }
+
+  fn device_info(&self, index: DeviceIndex) -> Option<PaDeviceInfo> {
+    let device = &self.device(index).ok()?.device;
+    Some(PaDeviceInfo {
+      struct_version: 2,
+      name: device.name.clone(),
+      host_api: HOST_API,
+      max_input_channels: device.max_input_channels,
+      max_output_channels: device.max_output_channels,
+      default_low_input_latency: device.latency,
+      default_low_output_latency: device.latency,
+      default_high_input_latency: device.latency * 4,
+      default_high_output_latency: device.latency * 4,
+      default_sample_rate: device.default_sample_rate,
+      is_default_input: false,
+      is_default_output: false,
+    })
+  }
+
+  fn is_format_supported(&self, input: Option<&RawStreamParameters>, output: Option<&RawStreamParameters>, sample_rate: f64) -> PaResult {
+    self.check_format(input, output, sample_rate)
+  }
+
+  unsafe fn open_stream(&self,
+                        input: Option<&RawStreamParameters>,
+                        output: Option<&RawStreamParameters>,
+                        sample_rate: f64,
+                        frames_per_buffer: u64,
+                        _flags: PaStreamFlags,
+                        callback: Option<RawStreamCallback>,
+                        user_data: *mut c_void) -> Result<StreamHandle, PaError> {
+    self.check_format(input, output, sample_rate)?;
+
+    let stream = Box::new(VirtualStream {
+      input: input.map(|p| self.direction(p)),
+      output: output.map(|p| self.direction(p)),
+      sample_rate,
+      frames_per_buffer: match frames_per_buffer {
+        0 => DEFAULT_FRAMES_PER_BUFFER,
+        n => n,
+      },
+      callback,
+      finished_callback: None,
+      user_data: UserData(user_data),
+      origin: self.origin,
+      shared: Arc::new(SharedState {
+        stop: AtomicBool::new(false),
+        active: AtomicBool::new(false),
+        cpu_load: AtomicU64::new(0),
+      }),
+      worker: None,
+      stopped: true,
+      started_at: Instant::now(),
+      frames_read: 0,
+      frames_written: 0,
+    });
+    Ok(Box::into_raw(stream) as StreamHandle)
+  }
+
+  unsafe fn set_stream_finished_callback(&self, stream: StreamHandle, callback: Option<RawStreamFinishedCallback>) -> PaResult {
+    let stream = stream_mut(stream);
+    if !stream.stopped {
+      return Err(ErrorCode::PaStreamIsNotStopped.into());
+    }
+    stream.finished_callback = callback;
+    Ok(())
+  }
+
+  unsafe fn close_stream(&self, stream: StreamHandle) -> PaResult {
+    let mut stream = Box::from_raw(stream as *mut VirtualStream);
+    if !stream.stopped {
+      let _ = stream.halt();
+    }
+    Ok(())
+  }
+
+  unsafe fn start_stream(&self, stream: StreamHandle) -> PaResult {
+    let stream = stream_mut(stream);
+    if !stream.stopped {
+      return Err(ErrorCode::PaStreamIsNotStopped.into());
+    }
+    stream.stopped = false;
+    stream.started_at = Instant::now();
+    stream.frames_read = 0;
+    stream.frames_written = 0;
+    stream.shared.stop.store(false, Ordering::SeqCst);
+    stream.shared.active.store(true, Ordering::SeqCst);
+
+    if let Some(callback) = stream.callback {
+      let worker = Worker {
+        input: stream.input.clone(),
+        output: stream.output.clone(),
+        sample_rate: stream.sample_rate,
+        frames_per_buffer: stream.frames_per_buffer,
+        callback,
+        finished_callback: stream.finished_callback,
+        user_data: stream.user_data,
+        origin: stream.origin,
+        shared: stream.shared.clone(),
+      };
+      stream.worker = Some(thread::spawn(move || worker.run()));
+    }
+    Ok(())
+  }
+
+  unsafe fn stop_stream(&self, stream: StreamHandle) -> PaResult {
+    stream_mut(stream).halt()
+  }
+
+  unsafe fn abort_stream(&self, stream: StreamHandle) -> PaResult {
+    stream_mut(stream).halt()
+  }
+
+  unsafe fn is_stream_stopped(&self, stream: StreamHandle) -> Result<bool, PaError> {
+    Ok(stream_mut(stream).stopped)
+  }
+
+  unsafe fn is_stream_active(&self, stream: StreamHandle) -> Result<bool, PaError> {
+    Ok(stream_mut(stream).shared.active.load(Ordering::SeqCst))
+  }
+
+  unsafe fn stream_read_available(&self, stream: StreamHandle) -> Result<u32, PaError> {
+    let stream = stream_mut(stream);
+    if stream.callback.is_some() {
+      return Err(ErrorCode::PaCanNotReadFromACallbackStream.into());
+    }
+    if stream.input.is_none() {
+      return Err(ErrorCode::PaCanNotReadFromAnOutputOnlyStream.into());
+    }
+    Ok(stream.elapsed_frames().saturating_sub(stream.frames_read) as u32)
+  }
+
+  unsafe fn stream_write_available(&self, stream: StreamHandle) -> Result<u32, PaError> {
+    let stream = stream_mut(stream);
+    if stream.callback.is_some() {
+      return Err(ErrorCode::PaCanNotWriteToACallbackStream.into());
+    }
+    let latency = match stream.output {
+      Some(ref direction) => direction.latency,
+      None => return Err(ErrorCode::PaCanNotWriteToAnInputOnlyStream.into()),
+    };
+    let buffered = (kit::duration_to_pa_time(latency) * stream.sample_rate) as u64;
+    Ok((stream.elapsed_frames() + buffered).saturating_sub(stream.frames_written) as u32)
+  }
+
+  unsafe fn read_stream(&self, stream: StreamHandle, buffer: *mut c_void, frames: u64) -> PaResult {
+    let stream = stream_mut(stream);
+    if stream.callback.is_some() {
+      return Err(ErrorCode::PaCanNotReadFromACallbackStream.into());
+    }
+    let direction = match stream.input {
+      Some(ref direction) => direction.clone(),
+      None => return Err(ErrorCode::PaCanNotReadFromAnOutputOnlyStream.into()),
+    };
+    if stream.stopped {
+      return Err(ErrorCode::PaStreamIsStopped.into());
+    }
+
+    // Input which was not read within the latency and a buffer is lost, as with a real device
+    let buffered = (kit::duration_to_pa_time(direction.latency) * stream.sample_rate) as u64 + stream.frames_per_buffer;
+    let overflowed = stream.elapsed_frames() > stream.frames_read + buffered;
+    if overflowed {
+      stream.frames_read = stream.elapsed_frames();
+    }
+
+    // Wait until the requested frames would have been captured
+    stream.frames_read += frames;
+    let due = stream.started_at + stream.frames_to_duration(stream.frames_read);
+    let now = Instant::now();
+    if due > now {
+      thread::sleep(due - now);
+    }
+
+    let mut recorded = vec![0u8; frames as usize * direction.frame_bytes];
+    direction.record(&mut recorded);
+    direction.scatter(&recorded, buffer);
+    match overflowed {
+      true => Err(ErrorCode::PaInputOverflowed.into()),
+      false => Ok(()),
+    }
+  }
+
+  unsafe fn write_stream(&self, stream: StreamHandle, buffer: *const c_void, frames: u64) -> PaResult {
+    let stream = stream_mut(stream);
+    if stream.callback.is_some() {
+      return Err(ErrorCode::PaCanNotWriteToACallbackStream.into());
+    }
+    let direction = match stream.output {
+      Some(ref direction) => direction.clone(),
+      None => return Err(ErrorCode::PaCanNotWriteToAnInputOnlyStream.into()),
+    };
+    if stream.stopped {
+      return Err(ErrorCode::PaStreamIsStopped.into());
+    }
+
+    // Once playback started, everything written has been played when the device asks for more
+    let underflowed = stream.frames_written > 0 && stream.elapsed_frames() > stream.frames_written;
+    if underflowed {
+      stream.frames_written = stream.elapsed_frames();
+    }
+
+    let mut played = vec![0u8; frames as usize * direction.frame_bytes];
+    direction.gather(buffer, &mut played);
+    direction.play(&played, stream.sample_rate);
+
+    // Block while more than the latency worth of audio is waiting to be played
+    stream.frames_written += frames;
+    let due = stream.started_at + stream.frames_to_duration(stream.frames_written);
+    let now = Instant::now() + direction.latency;
+    if due > now {
+      thread::sleep(due - now);
+    }
+    match underflowed {
+      true => Err(ErrorCode::PaOutputUnderflowed.into()),
+      false => Ok(()),
+    }
+  }
+
+  unsafe fn stream_cpu_load(&self, stream: StreamHandle) -> f64 {
+    f64::from_bits(stream_mut(stream).shared.cpu_load.load(Ordering::Relaxed))
+  }
+
+  unsafe fn stream_time(&self, stream: StreamHandle) -> Duration {
+    stream_mut(stream).origin.elapsed()
+  }
+
+  unsafe fn stream_info(&self, stream: StreamHandle) -> Option<PaStreamInfo> {
+    let stream = stream_mut(stream);
+    Some(PaStreamInfo {
+      input_latency: stream.input.as_ref().map_or(Duration::from_secs(0), |d| d.latency),
+      output_latency: stream.output.as_ref().map_or(Duration::from_secs(0), |d| d.latency),
+      sample_rate: stream.sample_rate,
+    })
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use std::sync::{Arc, Mutex};
+  use std::thread;
+  use std::time::Duration;
+
+  use crate::{device, hostapi, PortAudio};
+  use crate::rpa_error::ErrorCode;
+  use crate::stream::Stream;
+  use crate::types::*;
+
+  use super::{VirtualBackend, VirtualDevice};
+
+  fn parameters(device: DeviceIndex, channel_count: u32) -> PaStreamParameters<f32> {
+    PaStreamParameters { device, channel_count, suggested_latency: Duration::from_millis(10), data: 0.0 }
+  }
+
+  #[test]
+  fn test_enumeration() {
+    let backend = VirtualBackend::new()
+      .with_device(VirtualDevice::output("Speakers", 2))
+      .with_device(VirtualDevice::input("Microphone", 1));
+    let pa = PortAudio::with_backend(backend).unwrap();
+
+    assert_eq!(device::count(&pa), Ok(2));
+    assert_eq!(device::default_input(&pa), Some(1));
+    assert_eq!(device::default_output(&pa), Some(0));
+    assert_eq!(device::info(&pa, 1).unwrap().name, "Microphone");
+    assert!(device::info(&pa, 2).is_none());
+    assert_eq!(hostapi::info(&pa, 0).unwrap().device_count, 2);
+
+    let too_many = parameters(1, 2);
+    let error = crate::stream::is_format_supported::<f32, f32>(&pa, Some(too_many), None, 48000.0).unwrap_err();
+    assert_eq!(error, ErrorCode::PaInvalidChannelCount);
+    assert_eq!(error.operation(), Some("is_format_supported"));
+  }
+
+  #[test]
+  fn test_callback_loopback() {
+    let pa = PortAudio::with_backend(VirtualBackend::default()).unwrap();
+    let recorded = Arc::new(Mutex::new(Vec::new()));
+    let finished = Arc::new(Mutex::new(false));
+
+    let callback_recorded = recorded.clone();
+    let mut calls = 0;
+    let callback = Box::new(move |input: &[f32], output: &mut [f32], _time: PaStreamTimeInfo, _flags: PaStreamCallbackFlags| {
+      callback_recorded.lock().unwrap().push(input[0]);
+      calls += 1;
+      for sample in output.iter_mut() {
+        *sample = calls as f32;
+      }
+      match calls {
+        5 => PaStreamCallbackResult::Complete,
+        _ => PaStreamCallbackResult::Continue,
+      }
+    });
+
+    let mut stream = Stream::open(&pa, Some(parameters(0, 2)), Some(parameters(0, 2)), 48000.0, 48, PaStreamFlags::empty(), Some(callback)).unwrap();
+    let callback_finished = finished.clone();
+    stream.set_finished_callback(Box::new(move || *callback_finished.lock().unwrap() = true)).unwrap();
+    stream.start().unwrap();
+    while stream.is_active() == Ok(true) {
+      thread::sleep(Duration::from_millis(1));
+    }
+    stream.stop().unwrap();
+
+    // Every buffer hears the one played before it
+    assert_eq!(*recorded.lock().unwrap(), vec![0.0, 1.0, 2.0, 3.0, 4.0]);
+    assert!(*finished.lock().unwrap());
+    assert!(stream.close().is_ok());
+  }
+
+  #[test]
+  fn test_blocking_loopback() {
+    let pa = PortAudio::with_backend(VirtualBackend::default()).unwrap();
+    let mut stream = Stream::open(&pa, Some(parameters(0, 2)), Some(parameters(0, 2)), 48000.0, 0, PaStreamFlags::empty(), None).unwrap();
+    assert_eq!(stream.read(1).unwrap_err(), ErrorCode::PaStreamIsStopped);
+
+    stream.start().unwrap();
+    assert_eq!(stream.set_finished_callback(Box::new(|| ())).unwrap_err(), ErrorCode::PaStreamIsNotStopped);
+    stream.write(&[0.25, -0.25, 0.5, -0.5]).unwrap();
+    assert_eq!(stream.read(2).unwrap(), (vec![0.25, -0.25, 0.5, -0.5], IoStatus::Ok));
+    assert_eq!(stream.read(1).unwrap(), (vec![0.0, 0.0], IoStatus::Ok));
+    stream.stop().unwrap();
+  }
+
+  #[test]
+  fn test_callback_panic() {
+    let pa = PortAudio::with_backend(VirtualBackend::default()).unwrap();
+    let callback = Box::new(|_input: &[f32], _output: &mut [f32], _time: PaStreamTimeInfo, _flags: PaStreamCallbackFlags| -> PaStreamCallbackResult {
+      panic!("callback failure")
+    });
+
+    let stream = Stream::open(&pa, None, Some(parameters(0, 2)), 48000.0, 48, PaStreamFlags::empty(), Some(callback)).unwrap();
+    stream.start().unwrap();
+    while stream.is_active() == Ok(true) {
+      thread::sleep(Duration::from_millis(1));
+    }
+
+    let payload = stream.take_panic().unwrap();
+    assert_eq!(payload.downcast_ref::<&str>(), Some(&"callback failure"));
+    assert!(stream.take_panic().is_none());
+  }
+}
@@ -0,0 +1,546 @@
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread::{self, Thread};
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "futures")]
+use std::pin::Pin;
+#[cfg(feature = "futures")]
+use std::task::{Context, Poll};
+
+#[cfg(feature = "futures")]
+use futures::future;
+#[cfg(feature = "futures")]
+use futures::task::AtomicWaker;
+
+use crate::ringbuffer::{Consumer, Producer, RingBuffer};
+use crate::rpa_error::{BridgeError, RingBufferError};
+use crate::types::*;
+
+// Upper bound of a single park, in case a wakeup from the callback was skipped
+const MAX_PARK: Duration = Duration::from_millis(10);
+
+
+/// Wakes the thread waiting on one side of a bridge
+struct Signal {
+  waiter: Mutex<Option<Thread>>,
+  #[cfg(feature = "futures")]
+  waker: AtomicWaker,
+  closed: AtomicBool,
+  count: AtomicU64,
+}
+
+impl Signal {
+  fn new() -> Arc<Signal> {
+    Arc::new(Signal {
+      waiter: Mutex::new(None),
+      #[cfg(feature = "futures")]
+      waker: AtomicWaker::new(),
+      closed: AtomicBool::new(false),
+      count: AtomicU64::new(0),
+    })
+  }
+
+  /// Called from the callback, which must not block, so a busy lock means no wakeup
+  fn notify(&self) {
+    if let Ok(waiter) = self.waiter.try_lock() {
+      if let Some(ref thread) = *waiter {
+        thread.unpark();
+      }
+    }
+    #[cfg(feature = "futures")]
+    self.waker.wake();
+  }
+
+  fn register(&self) {
+    if let Ok(mut waiter) = self.waiter.lock() {
+      *waiter = Some(thread::current());
+    }
+  }
+
+  fn is_closed(&self) -> bool {
+    self.closed.load(Ordering::Acquire)
+  }
+
+  /// Park until notified or the deadline passes, returns false once it has passed
+  fn park(&self, deadline: Option<Instant>) -> bool {
+    match deadline {
+      None => thread::park_timeout(MAX_PARK),
+      Some(deadline) => {
+        let now = Instant::now();
+        if now >= deadline {
+          return false;
+        }
+        thread::park_timeout((deadline - now).min(MAX_PARK));
+      }
+    }
+    true
+  }
+}
+
+
+/// Marks both sides closed when the stream drops the callback
+struct Closer(Arc<Signal>, Arc<Signal>);
+
+impl Drop for Closer {
+  fn drop(&mut self) {
+    for signal in &[&self.0, &self.1] {
+      signal.closed.store(true, Ordering::Release);
+      signal.notify();
+    }
+  }
+}
+
+
+/// Receives the input of a bridged stream, see `StreamBuilder::bridge`
+pub struct InputReceiver<I> {
+  consumer: Consumer<I>,
+  signal: Arc<Signal>,
+  channels: usize,
+}
+
+impl<I: Copy> InputReceiver<I> {
+  /// Fill `buffer` with interleaved input, blocking until it is full
+  ///
+  /// Returns less than `buffer.len()` samples only when the stream is closed meanwhile, and
+  /// Disconnected when it is closed and nothing is left.
+  pub fn recv(&mut self, buffer: &mut [I]) -> Result<usize, BridgeError> {
+    self.recv_until(buffer, None)
+  }
+
+  /// Fill `buffer` with the input that is available, without blocking
+  ///
+  /// Returns Empty when nothing is available.
+  pub fn try_recv(&mut self, buffer: &mut [I]) -> Result<usize, BridgeError> {
+    match self.consumer.read(buffer) {
+      0 if self.signal.is_closed() && self.consumer.read_available() == 0 => Err(BridgeError::Disconnected),
+      0 if !buffer.is_empty() => Err(BridgeError::Empty),
+      n => Ok(n),
+    }
+  }
+
+  /// Like `recv`, but gives up after `timeout`, returning Timeout when nothing was received
+  pub fn recv_timeout(&mut self, buffer: &mut [I], timeout: Duration) -> Result<usize, BridgeError> {
+    self.recv_until(buffer, Some(Instant::now() + timeout))
+  }
+
+  /// Number of samples which can be received without blocking
+  pub fn available(&self) -> usize {
+    self.consumer.read_available()
+  }
+
+  /// Number of interleaved channels
+  pub fn channels(&self) -> usize {
+    self.channels
+  }
+
+  /// Number of callbacks whose input was dropped, entirely or partly, because the buffer was full
+  pub fn overruns(&self) -> u64 {
+    self.signal.count.load(Ordering::Relaxed)
+  }
+
+  fn recv_until(&mut self, buffer: &mut [I], deadline: Option<Instant>) -> Result<usize, BridgeError> {
+    self.signal.register();
+    let mut done = 0;
+    loop {
+      // Whatever the callback wrote before closing is visible once closed is
+      let closed = self.signal.is_closed();
+      done += self.consumer.read(&mut buffer[done..]);
+      if done == buffer.len() {
+        return Ok(done);
+      }
+      if closed {
+        return match done {
+          0 => Err(BridgeError::Disconnected),
+          n => Ok(n),
+        };
+      }
+      if !self.signal.park(deadline) {
+        return match done {
+          0 => Err(BridgeError::Timeout),
+          n => Ok(n),
+        };
+      }
+    }
+  }
+}
+
+
+/// Sends the output of a bridged stream, see `StreamBuilder::bridge`
+pub struct OutputSender<O> {
+  producer: Producer<O>,
+  signal: Arc<Signal>,
+  channels: usize,
+}
+
+impl<O: Copy> OutputSender<O> {
+  /// Queue all of the interleaved output in `buffer`, blocking until there is room
+  ///
+  /// Returns less than `buffer.len()` samples only when the stream is closed meanwhile, and
+  /// Disconnected when it is closed before anything was queued.
+  pub fn send(&mut self, buffer: &[O]) -> Result<usize, BridgeError> {
+    self.send_until(buffer, None)
+  }
+
+  /// Queue as much of `buffer` as fits, without blocking
+  ///
+  /// Returns Full when nothing fits.
+  pub fn try_send(&mut self, buffer: &[O]) -> Result<usize, BridgeError> {
+    if self.signal.is_closed() {
+      return Err(BridgeError::Disconnected);
+    }
+    match self.producer.write(buffer) {
+      0 if !buffer.is_empty() => Err(BridgeError::Full),
+      n => Ok(n),
+    }
+  }
+
+  /// Like `send`, but gives up after `timeout`, returning Timeout when nothing was queued
+  pub fn send_timeout(&mut self, buffer: &[O], timeout: Duration) -> Result<usize, BridgeError> {
+    self.send_until(buffer, Some(Instant::now() + timeout))
+  }
+
+  /// Number of samples which can be queued without blocking
+  pub fn available(&self) -> usize {
+    self.producer.write_available()
+  }
+
+  /// Number of interleaved channels
+  pub fn channels(&self) -> usize {
+    self.channels
+  }
+
+  /// Number of callbacks which ran short of output and played silence for the rest
+  pub fn underruns(&self) -> u64 {
+    self.signal.count.load(Ordering::Relaxed)
+  }
+
+  fn send_until(&mut self, buffer: &[O], deadline: Option<Instant>) -> Result<usize, BridgeError> {
+    self.signal.register();
+    let mut done = 0;
+    loop {
+      if self.signal.is_closed() {
+        return match done {
+          0 => Err(BridgeError::Disconnected),
+          n => Ok(n),
+        };
+      }
+      done += self.producer.write(&buffer[done..]);
+      if done == buffer.len() {
+        return Ok(done);
+      }
+      if !self.signal.park(deadline) {
+        return match done {
+          0 => Err(BridgeError::Timeout),
+          n => Ok(n),
+        };
+      }
+    }
+  }
+}
+
+
+#[cfg(feature = "futures")]
+impl<I: Copy + Default> InputReceiver<I> {
+  /// Receive `frames` frames of interleaved input, without blocking the executor
+  ///
+  /// Only with the `futures` feature.
+  ///
+  /// The task is woken by the stream callback. Returns fewer frames only when the stream is closed
+  /// meanwhile, and Disconnected when it is closed and nothing is left.
+  pub async fn read_async(&mut self, frames: u32) -> Result<Vec<I>, BridgeError> {
+    let mut buffer = vec![I::default(); frames as usize * self.channels];
+    let mut done = 0;
+    let count = future::poll_fn(|cx| self.poll_fill(cx, &mut buffer, &mut done)).await?;
+    buffer.truncate(count);
+    Ok(buffer)
+  }
+
+  /// A `futures::Stream` of blocks of `frames` frames, which ends when the stream is closed
+  ///
+  /// Only with the `futures` feature.
+  pub fn blocks(self, frames: u32) -> InputBlocks<I> {
+    let block = vec![I::default(); frames as usize * self.channels];
+    InputBlocks { receiver: self, block, done: 0 }
+  }
+
+  /// Fill `buffer` from `done` on, returning the number of samples once it is full or closed
+  fn poll_fill(&mut self, cx: &mut Context<'_>, buffer: &mut [I], done: &mut usize) -> Poll<Result<usize, BridgeError>> {
+    let mut registered = false;
+    loop {
+      let closed = self.signal.is_closed();
+      *done += self.consumer.read(&mut buffer[*done..]);
+      if *done == buffer.len() {
+        return Poll::Ready(Ok(*done));
+      }
+      if closed {
+        return Poll::Ready(match *done {
+          0 => Err(BridgeError::Disconnected),
+          n => Ok(n),
+        });
+      }
+      if registered {
+        return Poll::Pending;
+      }
+      // Check once more after registering, the callback may have run in between
+      self.signal.waker.register(cx.waker());
+      registered = true;
+    }
+  }
+}
+
+#[cfg(feature = "futures")]
+impl<O: Copy> OutputSender<O> {
+  /// Queue all of the interleaved output in `buffer`, without blocking the executor
+  ///
+  /// Only with the `futures` feature.
+  ///
+  /// The task is woken by the stream callback. Returns Disconnected when the stream is closed
+  /// before everything was queued.
+  pub async fn write_async(&mut self, buffer: &[O]) -> Result<(), BridgeError> {
+    let mut done = 0;
+    future::poll_fn(|cx| self.poll_drain(cx, buffer, &mut done)).await
+  }
+
+  /// A `futures::Sink` of blocks of interleaved output
+  ///
+  /// Only with the `futures` feature.
+  pub fn into_sink(self) -> OutputSink<O> {
+    OutputSink { sender: self, pending: Vec::new(), done: 0 }
+  }
+
+  /// Queue `buffer` from `done` on, ready once all of it is queued
+  fn poll_drain(&mut self, cx: &mut Context<'_>, buffer: &[O], done: &mut usize) -> Poll<Result<(), BridgeError>> {
+    let mut registered = false;
+    loop {
+      if self.signal.is_closed() {
+        return Poll::Ready(Err(BridgeError::Disconnected));
+      }
+      *done += self.producer.write(&buffer[*done..]);
+      if *done == buffer.len() {
+        return Poll::Ready(Ok(()));
+      }
+      if registered {
+        return Poll::Pending;
+      }
+      self.signal.waker.register(cx.waker());
+      registered = true;
+    }
+  }
+}
+
+
+/// Blocks of input as a `futures::Stream`, see `InputReceiver::blocks`
+///
+/// The last block may be shorter when the stream is closed.
+///
+/// Only with the `futures` feature.
+#[cfg(feature = "futures")]
+pub struct InputBlocks<I> {
+  receiver: InputReceiver<I>,
+  block: Vec<I>,
+  done: usize,
+}
+
+#[cfg(feature = "futures")]
+impl<I: Copy + Default + Unpin> futures::Stream for InputBlocks<I> {
+  type Item = Vec<I>;
+
+  fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Vec<I>>> {
+    let this = self.get_mut();
+    match this.receiver.poll_fill(cx, &mut this.block, &mut this.done) {
+      Poll::Pending => Poll::Pending,
+      Poll::Ready(Err(_)) => Poll::Ready(None),
+      Poll::Ready(Ok(count)) => {
+        this.done = 0;
+        Poll::Ready(Some(this.block[..count].to_vec()))
+      }
+    }
+  }
+}
+
+
+/// Output as a `futures::Sink` of interleaved blocks, see `OutputSender::into_sink`
+///
+/// Only with the `futures` feature.
+#[cfg(feature = "futures")]
+pub struct OutputSink<O> {
+  sender: OutputSender<O>,
+  pending: Vec<O>,
+  done: usize,
+}
+
+#[cfg(feature = "futures")]
+impl<O: Copy + Unpin> futures::Sink<Vec<O>> for OutputSink<O> {
+  type Error = BridgeError;
+
+  fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), BridgeError>> {
+    self.poll_flush(cx)
+  }
+
+  fn start_send(self: Pin<&mut Self>, block: Vec<O>) -> Result<(), BridgeError> {
+    let this = self.get_mut();
+    this.pending = block;
+    this.done = 0;
+    Ok(())
+  }
+
+  fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), BridgeError>> {
+    let this = self.get_mut();
+    if this.done == this.pending.len() {
+      return Poll::Ready(Ok(()));
+    }
+    this.sender.poll_drain(cx, &this.pending, &mut this.done)
+  }
+
+  fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), BridgeError>> {
+    self.poll_flush(cx)
+  }
+}
+
+
+/// A callback stream together with the handles its callback talks to
+///
+/// The handles block, or with the `futures` feature also await, where a Stream can only block.
+pub struct Bridge<'a, I: SampleType, O: SampleType> {
+  /// The stream, which still has to be started
+  pub stream: Stream<'a, I, O>,
+
+  /// Receives the input, None for an output-only stream
+  pub input: Option<InputReceiver<I>>,
+
+  /// Takes the output, None for an input-only stream
+  pub output: Option<OutputSender<O>>,
+}
+
+
+/// The callback of a bridged stream and the handles it talks to
+type BridgeParts<'a, I, O> = (Box<StreamCallback<'a, I, O>>, Option<InputReceiver<I>>, Option<OutputSender<O>>);
+
+/// Build the callback of a bridged stream and the handles it talks to
+pub(crate) fn callback<'a, I, O>(input_channels: usize, output_channels: usize, buffer_frames: usize)
+                                 -> Result<BridgeParts<'a, I, O>, RingBufferError>
+  where I: SampleType + Copy + Default + Send + 'static, O: SampleType + Copy + Default + Send + 'static {
+  let input_signal = Signal::new();
+  let output_signal = Signal::new();
+  let (mut input_producer, input_consumer) = RingBuffer::new((buffer_frames * input_channels.max(1)).next_power_of_two())?.split();
+  let (output_producer, mut output_consumer) = RingBuffer::new((buffer_frames * output_channels.max(1)).next_power_of_two())?.split();
+
+  let closer = Closer(input_signal.clone(), output_signal.clone());
+  let callback = move |input: &[I], output: &mut [O], _time: PaStreamTimeInfo, _flags: PaStreamCallbackFlags| {
+    let (input_signal, output_signal) = (&closer.0, &closer.1);
+
+    // Only whole frames are moved, so the channels never shift
+    if let Some(frames) = input_producer.write_available().checked_div(input_channels) {
+      let room = frames * input_channels;
+      if room < input.len() {
+        input_signal.count.fetch_add(1, Ordering::Relaxed);
+      }
+      input_producer.write(&input[..room.min(input.len())]);
+      input_signal.notify();
+    }
+
+    if let Some(frames) = output_consumer.read_available().checked_div(output_channels) {
+      let queued = frames * output_channels;
+      let wanted = queued.min(output.len());
+      let count = output_consumer.read(&mut output[..wanted]);
+      if count < output.len() {
+        for sample in &mut output[count..] {
+          *sample = O::default();
+        }
+        output_signal.count.fetch_add(1, Ordering::Relaxed);
+      }
+      output_signal.notify();
+    }
+
+    PaStreamCallbackResult::Continue
+  };
+
+  let input = match input_channels {
+    0 => None,
+    _ => Some(InputReceiver { consumer: input_consumer, signal: input_signal, channels: input_channels }),
+  };
+  let output = match output_channels {
+    0 => None,
+    _ => Some(OutputSender { producer: output_producer, signal: output_signal, channels: output_channels }),
+  };
+  Ok((Box::new(callback), input, output))
+}
+
+
+#[cfg(test)]
+mod test {
+  use std::time::Duration;
+
+  use crate::backend::VirtualBackend;
+  use crate::rpa_error::BridgeError;
+  use crate::stream::StreamBuilder;
+  use crate::types::*;
+
+  #[test]
+  fn test_bridge() {
+    let pa = PortAudio::with_backend(VirtualBackend::default()).unwrap();
+    let bridge = StreamBuilder::<f32, f32>::new(&pa)
+      .input(0, 2)
+      .output(0, 2)
+      .frames_per_buffer(64)
+      .bridge(4096)
+      .unwrap();
+    let mut input = bridge.input.unwrap();
+    let mut output = bridge.output.unwrap();
+
+    assert_eq!(input.try_recv(&mut [0.0; 4]), Err(BridgeError::Empty));
+    // Prime the output with a ramp, the loopback device returns it as input
+    let ramp: Vec<f32> = (0..512).map(|i| i as f32).collect();
+    assert_eq!(output.send(&ramp), Ok(512));
+    bridge.stream.start().unwrap();
+
+    let mut received = vec![0.0; 1024];
+    assert_eq!(input.recv_timeout(&mut received, Duration::from_secs(5)), Ok(1024));
+    let start = received.iter().position(|&s| s == 1.0).unwrap() - 1;
+    assert_eq!(&received[start..start + 512], &ramp[..]);
+    // The output ran dry once the ramp was played, and was filled with silence
+    assert!(output.underruns() > 0);
+    assert!(received[start + 512..].iter().all(|&s| s == 0.0));
+
+    bridge.stream.close().unwrap();
+    assert_eq!(input.recv(&mut received), Err(BridgeError::Disconnected));
+    assert_eq!(output.try_send(&ramp), Err(BridgeError::Disconnected));
+  }
+
+  #[cfg(feature = "futures")]
+  #[test]
+  fn test_async() {
+    use futures::{executor, SinkExt, StreamExt};
+
+    let pa = PortAudio::with_backend(VirtualBackend::default()).unwrap();
+    let bridge = StreamBuilder::<i16, i16>::new(&pa)
+      .input(0, 2)
+      .output(0, 2)
+      .frames_per_buffer(32)
+      .bridge(1024)
+      .unwrap();
+    let mut input = bridge.input.unwrap();
+    let mut output = bridge.output.unwrap();
+    bridge.stream.start().unwrap();
+
+    let ramp: Vec<i16> = (1..=256).collect();
+    let received = executor::block_on(async {
+      output.write_async(&ramp).await.unwrap();
+      let mut sink = output.into_sink();
+      sink.send(vec![0; 64]).await.unwrap();
+
+      let first = input.read_async(16).await.unwrap();
+      assert_eq!(first.len(), 32);
+      let mut received = first;
+      let mut blocks = input.blocks(64).take(4);
+      while let Some(block) = blocks.next().await {
+        received.extend(block);
+      }
+      received
+    });
+    assert_eq!(received.len(), 32 + 4 * 128);
+    let start = received.iter().position(|&s| s == 1).unwrap();
+    assert_eq!(&received[start..start + 256], &ramp[..]);
+  }
+}
@@ -0,0 +1,250 @@
+use std::marker::PhantomData;
+use std::time::Duration;
+
+use crate::backend::RawStreamParameters;
+use crate::bridge::{self, Bridge};
+use crate::frames::{self, FramesCallback};
+use crate::rpa_error::StreamBuilderError;
+use crate::rportaudio;
+use crate::types::*;
+
+/// Latency requested for each direction of a stream built by a StreamBuilder
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum Latency {
+  /// The default low latency of the device, for interactive use
+  Low,
+
+  /// The default high latency of the device, for robust playback and recording
+  #[default]
+  High,
+
+  /// An explicit latency
+  Exact(Duration),
+}
+
+/// Configures and opens a Stream
+///
+/// Everything is checked against the device info before the stream is opened: the devices have
+/// to exist, the channel counts have to lie within what the devices offer and the whole
+/// configuration has to pass `is_format_supported`. The sample rate defaults to the default
+/// sample rate of the output device, or of the input device for an input-only stream.
+///
+/// ```no_run
+/// use rportaudio::PortAudio;
+/// use rportaudio::device;
+/// use rportaudio::stream::{Latency, StreamBuilder};
+/// use rportaudio::types::PaStreamCallbackResult;
+///
+/// let pa = PortAudio::new().unwrap();
+/// let output = device::default_output(&pa).unwrap();
+/// let stream = StreamBuilder::<f32, f32>::new(&pa)
+///   .output(output, 2)
+///   .sample_rate(48000.0)
+///   .latency(Latency::Low)
+///   .callback(Box::new(|_input: &[f32], output: &mut [f32], _time, _flags| {
+///     for sample in output.iter_mut() { *sample = 0.0; }
+///     PaStreamCallbackResult::Continue
+///   }))
+///   .unwrap();
+/// stream.start().unwrap();
+/// ```
+pub struct StreamBuilder<'a, I: SampleType, O: SampleType> {
+  pa: &'a PortAudio,
+  input: Option<(DeviceIndex, u32)>,
+  output: Option<(DeviceIndex, u32)>,
+  sample_rate: Option<f64>,
+  frames_per_buffer: u64,
+  latency: Latency,
+  flags: PaStreamFlags,
+  samples: PhantomData<(I, O)>,
+}
+
+impl<'a, I: SampleType, O: SampleType> StreamBuilder<'a, I, O> {
+  /// Start configuring a stream without input or output
+  pub fn new(pa: &'a PortAudio) -> Self {
+    StreamBuilder {
+      pa,
+      input: None,
+      output: None,
+      sample_rate: None,
+      frames_per_buffer: 0,
+      latency: Latency::default(),
+      flags: PaStreamFlags::empty(),
+      samples: PhantomData,
+    }
+  }
+
+  /// Record `channels` channels from `device`
+  pub fn input(mut self, device: DeviceIndex, channels: u32) -> Self {
+    self.input = Some((device, channels));
+    self
+  }
+
+  /// Play `channels` channels to `device`
+  pub fn output(mut self, device: DeviceIndex, channels: u32) -> Self {
+    self.output = Some((device, channels));
+    self
+  }
+
+  /// Sample rate of the stream
+  pub fn sample_rate(mut self, sample_rate: f64) -> Self {
+    self.sample_rate = Some(sample_rate);
+    self
+  }
+
+  /// Number of frames per buffer, FRAMES_PER_BUFFER_UNSPECIFIED lets PortAudio choose
+  pub fn frames_per_buffer(mut self, frames_per_buffer: u64) -> Self {
+    self.frames_per_buffer = frames_per_buffer;
+    self
+  }
+
+  /// Latency of both directions, High by default
+  pub fn latency(mut self, latency: Latency) -> Self {
+    self.latency = latency;
+    self
+  }
+
+  /// Additional flags for the behaviour of the stream
+  pub fn flags(mut self, flags: PaStreamFlags) -> Self {
+    self.flags = flags;
+    self
+  }
+
+  /// Open a stream which calls `callback` to process the buffers
+  pub fn callback(self, callback: Box<StreamCallback<'a, I, O>>) -> Result<Stream<'a, I, O>, StreamBuilderError> {
+    self.open(Some(callback))
+  }
+
+  /// Open a stream whose callback gets its buffers as frame views of the channels of the builder
+  pub fn frames_callback(self, callback: Box<FramesCallback<'a, I, O>>) -> Result<Stream<'a, I, O>, StreamBuilderError> {
+    let input_channels = self.input.map_or(0, |(_, channels)| channels);
+    let output_channels = self.output.map_or(0, |(_, channels)| channels);
+    self.open(Some(frames::callback(input_channels, output_channels, callback)))
+  }
+
+  /// Open a stream which is used with the blocking read and write methods
+  pub fn blocking(self) -> Result<Stream<'a, I, O>, StreamBuilderError> {
+    self.open(None)
+  }
+
+  /// Open a stream whose callback is provided by the crate, moving audio to and from handles
+  ///
+  /// The input and output pass through ring buffers of at least `buffer_frames` frames each. When
+  /// the output buffer runs dry the callback plays silence and counts an underrun, when the input
+  /// buffer is full the input is dropped and counted as an overrun. `buffer_frames` must not be 0.
+  ///
+  /// This is the async path of the crate: with the `futures` feature the handles have
+  /// `read_async` and `write_async`, and convert into a `futures::Stream` and `futures::Sink`.
+  pub fn bridge(self, buffer_frames: usize) -> Result<Bridge<'a, I, O>, StreamBuilderError>
+    where I: Copy + Default + Send + 'static, O: Copy + Default + Send + 'static {
+    if buffer_frames == 0 {
+      return Err(StreamBuilderError::NoBufferFrames);
+    }
+    let input_channels = self.input.map_or(0, |(_, channels)| channels as usize);
+    let output_channels = self.output.map_or(0, |(_, channels)| channels as usize);
+    let (callback, input, output) = bridge::callback(input_channels, output_channels, buffer_frames)
+      .map_err(StreamBuilderError::RingBuffer)?;
+    let stream = self.callback(callback)?;
+    Ok(Bridge { stream, input, output })
+  }
+
+  fn open(self, callback: Option<Box<StreamCallback<'a, I, O>>>) -> Result<Stream<'a, I, O>, StreamBuilderError> {
+    if self.input.is_none() && self.output.is_none() {
+      return Err(StreamBuilderError::NoDirection);
+    }
+
+    let mut default_sample_rate = 0.0;
+    let input = match self.input {
+      Some((device, channels)) => {
+        let (parameters, rate) = parameters(self.pa, true, device, channels, I::sample_format(), self.latency)?;
+        default_sample_rate = rate;
+        Some(parameters)
+      }
+      None => None,
+    };
+    let output = match self.output {
+      Some((device, channels)) => {
+        let (parameters, rate) = parameters(self.pa, false, device, channels, O::sample_format(), self.latency)?;
+        default_sample_rate = rate;
+        Some(parameters)
+      }
+      None => None,
+    };
+    let sample_rate = self.sample_rate.unwrap_or(default_sample_rate);
+
+    rportaudio::is_raw_format_supported(self.pa, input.as_ref(), output.as_ref(), sample_rate)
+      .map_err(StreamBuilderError::Unsupported)?;
+    rportaudio::open_raw_stream(self.pa, input, output, sample_rate, self.frames_per_buffer, self.flags, callback)
+      .map_err(StreamBuilderError::Open)
+  }
+}
+
+
+/// Check one direction against the device info, returning its parameters and the default sample
+/// rate of the device
+pub(crate) fn parameters(pa: &PortAudio, is_input: bool, device: DeviceIndex, channels: u32, sample_format: u64, latency: Latency)
+              -> Result<(RawStreamParameters, f64), StreamBuilderError> {
+  let info = rportaudio::device_info(pa, device).ok_or(StreamBuilderError::InvalidDevice(device))?;
+  let (max, low, high) = match is_input {
+    true => (info.max_input_channels, info.default_low_input_latency, info.default_high_input_latency),
+    false => (info.max_output_channels, info.default_low_output_latency, info.default_high_output_latency),
+  };
+  if channels == 0 || channels > max {
+    return Err(match is_input {
+      true => StreamBuilderError::InvalidInputChannels { device, requested: channels, max },
+      false => StreamBuilderError::InvalidOutputChannels { device, requested: channels, max },
+    });
+  }
+  let parameters = RawStreamParameters {
+    device,
+    channel_count: channels,
+    sample_format,
+    suggested_latency: match latency {
+      Latency::Low => low,
+      Latency::High => high,
+      Latency::Exact(latency) => latency,
+    },
+  };
+  Ok((parameters, info.default_sample_rate))
+}
+
+
+#[cfg(test)]
+mod test {
+  use std::time::Duration;
+
+  use crate::backend::{VirtualBackend, VirtualDevice};
+  use crate::rpa_error::{ErrorCode, StreamBuilderError};
+  use crate::types::*;
+
+  use super::{Latency, StreamBuilder};
+
+  #[test]
+  fn test_builder() {
+    let backend = VirtualBackend::new()
+      .with_device(VirtualDevice::output("Speakers", 2).with_sample_rates(&[44100.0, 48000.0]))
+      .with_device(VirtualDevice::input("Microphone", 1));
+    let pa = PortAudio::with_backend(backend).unwrap();
+
+    let stream = StreamBuilder::<f32, f32>::new(&pa)
+      .output(0, 2)
+      .sample_rate(48000.0)
+      .latency(Latency::Exact(Duration::from_millis(5)))
+      .blocking()
+      .unwrap();
+    assert_eq!(stream.info().unwrap().sample_rate, 48000.0);
+
+    let open = |builder: StreamBuilder<'_, f32, f32>| builder.blocking().err().unwrap();
+    assert_eq!(open(StreamBuilder::new(&pa)), StreamBuilderError::NoDirection);
+    assert_eq!(open(StreamBuilder::new(&pa).output(5, 2)), StreamBuilderError::InvalidDevice(5));
+    assert_eq!(open(StreamBuilder::new(&pa).output(0, 3)),
+               StreamBuilderError::InvalidOutputChannels { device: 0, requested: 3, max: 2 });
+    assert_eq!(open(StreamBuilder::new(&pa).input(0, 1)),
+               StreamBuilderError::InvalidInputChannels { device: 0, requested: 1, max: 0 });
+    assert_eq!(StreamBuilder::<f32, f32>::new(&pa).output(0, 2).bridge(0).err(), Some(StreamBuilderError::NoBufferFrames));
+    match open(StreamBuilder::new(&pa).output(0, 2).sample_rate(8000.0)) {
+      StreamBuilderError::Unsupported(e) => assert_eq!(e, ErrorCode::PaInvalidSampleRate),
+      e => panic!("unexpected error {}", e),
+    }
+  }
+}
@@ -1,34 +1,34 @@
-use crate::rpa_error::PaError;
-use crate::rportaudio;
-use crate::types::{DeviceIndex, PaDeviceInfo};
+use crate::{hostapi, rportaudio};
+use crate::rpa_error::{DeviceLookupError, PaError};
+use crate::types::{DeviceCapabilities, DeviceId, DeviceIndex, HostApiIndex, HostApiType, PaDeviceInfo, PortAudio};
 
 /// Retrieve the number of available devices.
-pub fn count() -> Result<u32, PaError> {
-  rportaudio::device_count()
+pub fn count(pa: &PortAudio) -> Result<u32, PaError> {
+  rportaudio::device_count(pa)
 }
 
 
 /// Retrieve the index of the default input device
 ///
 /// Will return None when none are available.
-pub fn default_input() -> Option<DeviceIndex> {
-  rportaudio::default_input_device()
+pub fn default_input(pa: &PortAudio) -> Option<DeviceIndex> {
+  rportaudio::default_input_device(pa)
 }
 
 
 /// Retrieve the index of the default output device
 ///
 /// Will return None when none are available.
-pub fn default_output() -> Option<DeviceIndex> {
-  rportaudio::default_output_device()
+pub fn default_output(pa: &PortAudio) -> Option<DeviceIndex> {
+  rportaudio::default_output_device(pa)
 }
 
 
 /// Get info about a particular device
 ///
 /// Returns None when the index is out of range.
-pub fn info(index: DeviceIndex) -> Option<PaDeviceInfo> {
-  rportaudio::device_info(index)
+pub fn info(pa: &PortAudio, index: DeviceIndex) -> Option<PaDeviceInfo> {
+  rportaudio::device_info(pa, index)
 }
 
 /// Converts a device index from a specific host API to a global device index
@@ -36,14 +36,294 @@ pub fn info(index: DeviceIndex) -> Option<PaDeviceInfo> {
 /// Returns Err(InvalidHostApi) when the host_api is out of range, and Err(InvalidDevice) when
 /// host_api_device_index is out of range.
 ///
-/// ```
+/// ```no_run
 /// // We retrieve the index of device 3 of api 1
-/// let device_index = match rportaudio::device::hostapi_device_index_to_device_index(1, 3) {
+/// let pa = rportaudio::PortAudio::new().unwrap();
+/// let device_index = match rportaudio::device::hostapi_device_index_to_device_index(&pa, 1, 3) {
 ///   Ok(n) => n,
 ///   Err(e) => { println!("Error: {:?}", e); return; }
 /// };
 /// ```
-pub fn hostapi_device_index_to_device_index(hostapi: u32, hostapi_device_index: u32) -> Result<u32, PaError> {
-  rportaudio::hostapi_device_index_to_device_index(hostapi, hostapi_device_index)
+pub fn hostapi_device_index_to_device_index(pa: &PortAudio, hostapi: u32, hostapi_device_index: u32) -> Result<u32, PaError> {
+  rportaudio::hostapi_device_index_to_device_index(pa, hostapi, hostapi_device_index)
+}
+
+
+
+/// Iterate over all devices
+///
+/// The iterator is empty when the device count can not be retrieved.
+///
+/// ```no_run
+/// let pa = rportaudio::PortAudio::new().unwrap();
+/// for (index, info) in rportaudio::device::all(&pa).outputs() {
+///   println!("{}: {}{}", index, info.name, if info.is_default_output { " (default)" } else { "" });
+/// }
+/// ```
+pub fn all(pa: &PortAudio) -> Devices<'_> {
+  Devices {
+    pa,
+    index: 0,
+    count: count(pa).unwrap_or(0),
+    inputs: false,
+    outputs: false,
+    host_api: None,
+  }
+}
+
+
+/// Iterator over devices and their info, see `all`
+pub struct Devices<'p> {
+  pa: &'p PortAudio,
+  index: DeviceIndex,
+  count: u32,
+  inputs: bool,
+  outputs: bool,
+  host_api: Option<HostApiIndex>,
 }
 
+impl<'p> Devices<'p> {
+  /// Only yield devices with input channels
+  pub fn inputs(mut self) -> Self {
+    self.inputs = true;
+    self
+  }
+
+  /// Only yield devices with output channels
+  pub fn outputs(mut self) -> Self {
+    self.outputs = true;
+    self
+  }
+
+  /// Only yield devices of the given host API
+  pub fn host_api(mut self, host_api: HostApiIndex) -> Self {
+    self.host_api = Some(host_api);
+    self
+  }
+
+  fn matches(&self, info: &PaDeviceInfo) -> bool {
+    let host_api = match self.host_api {
+      Some(host_api) => info.host_api == host_api,
+      None => true,
+    };
+    host_api && (!self.inputs || info.max_input_channels > 0) && (!self.outputs || info.max_output_channels > 0)
+  }
+}
+
+impl<'p> Iterator for Devices<'p> {
+  type Item = (DeviceIndex, PaDeviceInfo);
+
+  fn next(&mut self) -> Option<Self::Item> {
+    while self.index < self.count {
+      let index = self.index;
+      self.index += 1;
+      match info(self.pa, index) {
+        Some(info) if self.matches(&info) => return Some((index, info)),
+        _ => {}
+      }
+    }
+    None
+  }
+
+  fn size_hint(&self) -> (usize, Option<usize>) {
+    (0, Some((self.count - self.index) as usize))
+  }
+}
+
+
+
+/// Stable identifiers of all devices
+fn ids(pa: &PortAudio) -> Vec<(DeviceIndex, DeviceId)> {
+  let mut ids: Vec<(DeviceIndex, DeviceId)> = Vec::new();
+  for (index, info) in all(pa) {
+    let host_api = hostapi::info(pa, info.host_api).map_or(HostApiType::Unknown, |api| api.type_);
+    let ordinal = ids.iter().filter(|(_, id)| id.host_api == host_api && id.name == info.name).count() as u32;
+    ids.push((index, DeviceId { host_api, name: info.name, ordinal }));
+  }
+  ids
+}
+
+/// Get the stable identifier of a device, to store instead of its index
+///
+/// Returns None when the index is out of range.
+pub fn id(pa: &PortAudio, index: DeviceIndex) -> Option<DeviceId> {
+  ids(pa).into_iter().find(|&(i, _)| i == index).map(|(_, id)| id)
+}
+
+/// Get the current index of the device with the given identifier
+///
+/// ```no_run
+/// let pa = rportaudio::PortAudio::new().unwrap();
+/// let id: rportaudio::types::DeviceId = "ALSA:0:default".parse().unwrap();
+/// println!("{:?}", rportaudio::device::find(&pa, &id));
+/// ```
+pub fn find(pa: &PortAudio, id: &DeviceId) -> Result<DeviceIndex, DeviceLookupError> {
+  ids(pa).into_iter()
+    .find(|(_, candidate)| candidate == id)
+    .map(|(index, _)| index)
+    .ok_or_else(|| DeviceLookupError::NotFound(id.to_string()))
+}
+
+
+/// Something device names can be matched against, see `find_by_name`
+pub trait NamePattern {
+  /// Whether the name matches
+  fn matches(&self, name: &str) -> bool;
+
+  /// Whether the name matches exactly, which settles ambiguous matches
+  fn matches_exactly(&self, name: &str) -> bool;
+
+  /// Description of the pattern for errors
+  fn describe(&self) -> String;
+}
+
+/// Matches names containing the string
+impl NamePattern for &str {
+  fn matches(&self, name: &str) -> bool { name.contains(*self) }
+  fn matches_exactly(&self, name: &str) -> bool { name == *self }
+  fn describe(&self) -> String { self.to_string() }
+}
+
+#[cfg(feature = "regex")]
+impl NamePattern for &regex::Regex {
+  fn matches(&self, name: &str) -> bool { self.is_match(name) }
+  fn matches_exactly(&self, _name: &str) -> bool { false }
+  fn describe(&self) -> String { self.as_str().to_string() }
+}
+
+/// Get the index of the one device whose name matches the pattern
+///
+/// A string matches names containing it. When several names contain it, a name equal to it wins.
+/// With the `regex` feature a `&Regex` can be used as well, see also `find_by_regex`.
+///
+/// ```no_run
+/// let pa = rportaudio::PortAudio::new().unwrap();
+/// match rportaudio::device::find_by_name(&pa, "USB") {
+///   Ok(index) => println!("using device {}", index),
+///   Err(e) => println!("{}", e),
+/// }
+/// ```
+pub fn find_by_name<P: NamePattern>(pa: &PortAudio, pattern: P) -> Result<DeviceIndex, DeviceLookupError> {
+  let matching: Vec<(DeviceIndex, PaDeviceInfo)> = all(pa).filter(|(_, info)| pattern.matches(&info.name)).collect();
+  if matching.len() == 1 {
+    return Ok(matching[0].0);
+  }
+
+  let exact: Vec<DeviceIndex> = matching.iter().filter(|(_, info)| pattern.matches_exactly(&info.name)).map(|&(index, _)| index).collect();
+  match (matching.len(), exact.len()) {
+    (0, _) => Err(DeviceLookupError::NotFound(pattern.describe())),
+    (_, 1) => Ok(exact[0]),
+    _ => Err(DeviceLookupError::Ambiguous(pattern.describe(), matching.into_iter().map(|(index, _)| index).collect())),
+  }
+}
+
+/// Get the index of the one device whose name matches the regular expression
+#[cfg(feature = "regex")]
+pub fn find_by_regex(pa: &PortAudio, pattern: &str) -> Result<DeviceIndex, DeviceLookupError> {
+  let regex = regex::Regex::new(pattern).map_err(|e| DeviceLookupError::InvalidPattern(e.to_string()))?;
+  find_by_name(pa, &regex)
+}
+
+
+
+/// Find out which sample rates, formats and channel counts a device supports
+///
+/// The device is probed once per session, later calls return the cached report. Returns
+/// Err(InvalidDevice) when the index is out of range.
+///
+/// ```no_run
+/// let pa = rportaudio::PortAudio::new().unwrap();
+/// let device = rportaudio::device::default_output(&pa).unwrap();
+/// if let Ok(capabilities) = rportaudio::device::capabilities(&pa, device) {
+///   println!("{:?}", capabilities.output.map(|output| output.sample_rates));
+/// }
+/// ```
+pub fn capabilities(pa: &PortAudio, index: DeviceIndex) -> Result<DeviceCapabilities, PaError> {
+  rportaudio::device_capabilities(pa, index)
+}
+
+#[cfg(test)]
+mod test {
+  use crate::{hostapi, PortAudio};
+  use crate::backend::{VirtualBackend, VirtualDevice};
+  use crate::rpa_error::DeviceLookupError;
+  use crate::types::{DeviceId, SampleFormat};
+
+  #[test]
+  fn test_all() {
+    let backend = VirtualBackend::new()
+      .with_device(VirtualDevice::input("Microphone", 1))
+      .with_device(VirtualDevice::output("Speakers", 2))
+      .with_device(VirtualDevice::loopback("Loopback", 2));
+    let pa = PortAudio::with_backend(backend).unwrap();
+
+    let names = |devices: super::Devices| devices.map(|(i, d)| (i, d.name)).collect::<Vec<_>>();
+    assert_eq!(super::all(&pa).count(), 3);
+    assert_eq!(names(super::all(&pa).inputs()), vec![(0, "Microphone".to_string()), (2, "Loopback".to_string())]);
+    assert_eq!(names(super::all(&pa).outputs().inputs()), vec![(2, "Loopback".to_string())]);
+    assert_eq!(super::all(&pa).host_api(1).count(), 0);
+
+    let defaults = super::all(&pa).map(|(_, d)| (d.is_default_input, d.is_default_output)).collect::<Vec<_>>();
+    assert_eq!(defaults, vec![(true, false), (false, true), (false, false)]);
+
+    let api_devices = hostapi::devices(&pa, 0).unwrap();
+    assert_eq!(api_devices.iter().map(|&(i, _)| i).collect::<Vec<_>>(), vec![0, 1, 2]);
+    assert!(hostapi::devices(&pa, 1).is_err());
+  }
+
+  #[test]
+  fn test_find() {
+    let backend = VirtualBackend::new()
+      .with_device(VirtualDevice::input("USB Microphone", 1))
+      .with_device(VirtualDevice::output("USB", 2))
+      .with_device(VirtualDevice::output("USB", 2));
+    let pa = PortAudio::with_backend(backend).unwrap();
+
+    let id = super::id(&pa, 2).unwrap();
+    assert_eq!(id.to_string(), "InDevelopment:1:USB");
+    assert_eq!(id.to_string().parse::<DeviceId>(), Ok(id.clone()));
+    assert_eq!(super::find(&pa, &id), Ok(2));
+    assert_eq!(super::find(&pa, &"InDevelopment:2:USB".parse().unwrap()), Err(DeviceLookupError::NotFound("InDevelopment:2:USB".to_string())));
+    assert_eq!("USB".parse::<DeviceId>(), Err(DeviceLookupError::InvalidId("USB".to_string())));
+
+    assert_eq!(super::find_by_name(&pa, "Micro"), Ok(0));
+    assert_eq!(super::find_by_name(&pa, "Speakers"), Err(DeviceLookupError::NotFound("Speakers".to_string())));
+    assert_eq!(super::find_by_name(&pa, "USB"), Err(DeviceLookupError::Ambiguous("USB".to_string(), vec![0, 1, 2])));
+  }
+
+  #[test]
+  fn test_capabilities() {
+    let backend = VirtualBackend::new()
+      .with_device(VirtualDevice::output("Speakers", 4).with_sample_rates(&[44100.0, 48000.0]))
+      .with_device(VirtualDevice::input("Microphone", 1).with_sample_rate(16000.0).with_sample_rates(&[16000.0]));
+    let pa = PortAudio::with_backend(backend).unwrap();
+
+    let speakers = super::capabilities(&pa, 0).unwrap();
+    assert!(speakers.input.is_none());
+    let output = speakers.output.unwrap();
+    assert_eq!(output.sample_rates, vec![44100.0, 48000.0]);
+    assert_eq!(output.sample_formats, SampleFormat::ALL.to_vec());
+    assert_eq!(output.channel_counts, vec![1, 2, 3, 4]);
+
+    let microphone = super::capabilities(&pa, 1).unwrap();
+    assert_eq!(microphone.input.unwrap().sample_rates, vec![16000.0]);
+    assert_eq!(super::capabilities(&pa, 1).unwrap(), super::capabilities(&pa, 1).unwrap());
+    assert!(super::capabilities(&pa, 2).is_err());
+  }
+
+  #[cfg(feature = "regex")]
+  #[test]
+  fn test_find_by_regex() {
+    let backend = VirtualBackend::new()
+      .with_device(VirtualDevice::input("USB Microphone", 1))
+      .with_device(VirtualDevice::output("USB Speakers", 2));
+    let pa = PortAudio::with_backend(backend).unwrap();
+
+    assert_eq!(super::find_by_regex(&pa, "^USB .*ph"), Ok(0));
+    assert!(super::find_by_regex(&pa, "^USB").is_err());
+    match super::find_by_regex(&pa, "(") {
+      Err(DeviceLookupError::InvalidPattern(_)) => {}
+      other => panic!("unexpected {:?}", other),
+    }
+  }
+}
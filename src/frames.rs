@@ -0,0 +1,246 @@
+//! Views of interleaved samples which know the number of channels
+//!
+//! A `Frames` or `FramesMut` is checked once when it is made, after which a frame is always a
+//! whole frame and a channel always the same channel.
+//!
+//! ```
+//! use rportaudio::frames::{Frames, FramesMut};
+//!
+//! let samples = [0.1f32, -0.1, 0.2, -0.2, 0.3, -0.3];
+//! let frames = Frames::new(&samples, 2).unwrap();
+//! assert_eq!(frames.frame_count(), 3);
+//! assert_eq!(frames.channel(1).cloned().collect::<Vec<_>>(), vec![-0.1, -0.2, -0.3]);
+//! assert!(Frames::new(&samples[..5], 2).is_none());
+//!
+//! let mut output = [0.0f32; 4];
+//! let mut frames = FramesMut::new(&mut output, 2).unwrap();
+//! frames.frame_mut(1).unwrap()[0] = 0.5;
+//! assert_eq!(output, [0.0, 0.0, 0.5, 0.0]);
+//! ```
+
+use std::ops::Range;
+use std::slice::{ChunksExact, ChunksExactMut};
+
+use crate::types::*;
+
+/// Callback which gets its buffers as frame views
+pub type FramesCallback<'a, I, O> = dyn FnMut(Frames<I>, FramesMut<O>, PaStreamTimeInfo, PaStreamCallbackFlags) -> PaStreamCallbackResult + 'a;
+
+
+/// Interleaved samples of a number of channels
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Frames<'a, T> {
+  samples: &'a [T],
+  channels: usize,
+}
+
+impl<'a, T> Frames<'a, T> {
+  /// A view of `samples` as frames of `channels` samples, None when they do not hold whole frames
+  ///
+  /// Zero channels are only allowed without samples, as for an unused direction.
+  pub fn new(samples: &'a [T], channels: usize) -> Option<Self> {
+    match whole_frames(samples.len(), channels) {
+      true => Some(Frames { samples, channels }),
+      false => None,
+    }
+  }
+
+  /// Number of channels in a frame
+  pub fn channels(&self) -> usize {
+    self.channels
+  }
+
+  /// Number of frames
+  pub fn frame_count(&self) -> usize {
+    self.samples.len().checked_div(self.channels).unwrap_or(0)
+  }
+
+  /// Returns true when there are no frames
+  pub fn is_empty(&self) -> bool {
+    self.samples.is_empty()
+  }
+
+  /// The samples of frame `index`
+  pub fn frame(&self, index: usize) -> Option<&'a [T]> {
+    self.samples.get(frame_range(index, self.channels)?)
+  }
+
+  /// Iterate over the frames
+  pub fn frames(&self) -> ChunksExact<'a, T> {
+    self.samples.chunks_exact(self.channels.max(1))
+  }
+
+  /// Iterate over the samples of channel `channel`, which is empty for a channel that is not there
+  pub fn channel(&self, channel: usize) -> impl Iterator<Item = &'a T> {
+    let samples = match channel < self.channels {
+      true => self.samples,
+      false => &[],
+    };
+    samples.iter().skip(channel).step_by(self.channels.max(1))
+  }
+
+  /// The interleaved samples
+  pub fn as_slice(&self) -> &'a [T] {
+    self.samples
+  }
+}
+
+
+/// Mutable interleaved samples of a number of channels
+#[derive(Debug, PartialEq)]
+pub struct FramesMut<'a, T> {
+  samples: &'a mut [T],
+  channels: usize,
+}
+
+impl<'a, T> FramesMut<'a, T> {
+  /// A view of `samples` as frames of `channels` samples, None when they do not hold whole frames
+  ///
+  /// Zero channels are only allowed without samples, as for an unused direction.
+  pub fn new(samples: &'a mut [T], channels: usize) -> Option<Self> {
+    match whole_frames(samples.len(), channels) {
+      true => Some(FramesMut { samples, channels }),
+      false => None,
+    }
+  }
+
+  /// Number of channels in a frame
+  pub fn channels(&self) -> usize {
+    self.channels
+  }
+
+  /// Number of frames
+  pub fn frame_count(&self) -> usize {
+    self.samples.len().checked_div(self.channels).unwrap_or(0)
+  }
+
+  /// Returns true when there are no frames
+  pub fn is_empty(&self) -> bool {
+    self.samples.is_empty()
+  }
+
+  /// The samples of frame `index`
+  pub fn frame(&self, index: usize) -> Option<&[T]> {
+    self.as_frames().frame(index)
+  }
+
+  /// The samples of frame `index`, to be changed
+  pub fn frame_mut(&mut self, index: usize) -> Option<&mut [T]> {
+    self.samples.get_mut(frame_range(index, self.channels)?)
+  }
+
+  /// Iterate over the frames
+  pub fn frames(&self) -> ChunksExact<'_, T> {
+    self.samples.chunks_exact(self.channels.max(1))
+  }
+
+  /// Iterate over the frames, to change them
+  pub fn frames_mut(&mut self) -> ChunksExactMut<'_, T> {
+    self.samples.chunks_exact_mut(self.channels.max(1))
+  }
+
+  /// Iterate over the samples of channel `channel`, which is empty for a channel that is not there
+  pub fn channel(&self, channel: usize) -> impl Iterator<Item = &T> {
+    self.as_frames().channel(channel)
+  }
+
+  /// Iterate over the samples of channel `channel`, to change them
+  pub fn channel_mut(&mut self, channel: usize) -> impl Iterator<Item = &mut T> {
+    let channels = self.channels.max(1);
+    let samples = match channel < self.channels {
+      true => &mut self.samples[..],
+      false => &mut [],
+    };
+    samples.iter_mut().skip(channel).step_by(channels)
+  }
+
+  /// Set every sample to `value`
+  pub fn fill(&mut self, value: T) where T: Clone {
+    for sample in self.samples.iter_mut() {
+      *sample = value.clone();
+    }
+  }
+
+  /// A read-only view of the same frames
+  pub fn as_frames(&self) -> Frames<'_, T> {
+    Frames { samples: self.samples, channels: self.channels }
+  }
+
+  /// The interleaved samples
+  pub fn as_slice(&self) -> &[T] {
+    self.samples
+  }
+
+  /// The interleaved samples, to be changed
+  pub fn as_mut_slice(&mut self) -> &mut [T] {
+    self.samples
+  }
+}
+
+/// The samples of frame `index`, None without channels or when the range does not fit in a usize
+fn frame_range(index: usize, channels: usize) -> Option<Range<usize>> {
+  let start = index.checked_mul(channels).filter(|_| channels > 0)?;
+  Some(start..start.checked_add(channels)?)
+}
+
+// usize::is_multiple_of is newer than the rust-version of the crate
+#[allow(clippy::manual_is_multiple_of)]
+fn whole_frames(len: usize, channels: usize) -> bool {
+  match channels {
+    0 => len == 0,
+    channels => len % channels == 0,
+  }
+}
+
+
+/// Wrap a callback taking frame views for a stream with the given channel counts
+///
+/// `StreamBuilder::frames_callback` does this with the channel counts of the builder.
+pub fn callback<'a, I: 'a, O: 'a>(input_channels: u32, output_channels: u32, mut callback: Box<FramesCallback<'a, I, O>>)
+                                  -> Box<StreamCallback<'a, I, O>> {
+  let (input_channels, output_channels) = (input_channels as usize, output_channels as usize);
+  Box::new(move |input: &[I], output: &mut [O], time, flags| {
+    // PortAudio always hands over whole frames, a buffer of an unused direction is empty
+    let input = Frames::new(input, input_channels).unwrap_or(Frames { samples: &[], channels: input_channels });
+    let output = match whole_frames(output.len(), output_channels) {
+      true => FramesMut { samples: output, channels: output_channels },
+      false => FramesMut { samples: &mut [], channels: output_channels },
+    };
+    callback(input, output, time, flags)
+  })
+}
+
+
+#[cfg(test)]
+mod test {
+  use super::{Frames, FramesMut};
+
+  #[test]
+  fn test_frames() {
+    let mut samples = [1, 2, 3, 4, 5, 6];
+    let frames = Frames::new(&samples, 3).unwrap();
+    assert_eq!(frames.frame_count(), 2);
+    assert_eq!(frames.frame(1), Some(&[4, 5, 6][..]));
+    assert_eq!(frames.frame(2), None);
+    assert_eq!(frames.frame(usize::MAX), None);
+    assert_eq!(frames.frame(usize::MAX / 3), None);
+    assert_eq!(frames.frames().map(|frame| frame[0]).collect::<Vec<_>>(), vec![1, 4]);
+    assert_eq!(frames.channel(2).cloned().collect::<Vec<_>>(), vec![3, 6]);
+    assert_eq!(frames.channel(3).count(), 0);
+
+    assert!(Frames::<i16>::new(&[], 0).is_some());
+    assert!(Frames::new(&samples, 0).is_none());
+    assert!(Frames::new(&samples, 4).is_none());
+
+    let mut frames = FramesMut::new(&mut samples, 2).unwrap();
+    for sample in frames.channel_mut(1) {
+      *sample = -*sample;
+    }
+    frames.frame_mut(0).unwrap()[0] = 0;
+    assert_eq!(frames.frame_mut(3), None);
+    assert_eq!(frames.frame_mut(usize::MAX), None);
+    assert_eq!(frames.channel_mut(2).count(), 0);
+    assert_eq!(frames.frame_count(), 3);
+    assert_eq!(samples, [0, -2, 3, -4, 5, -6]);
+  }
+}
@@ -1,34 +1,49 @@
-use crate::types::{PaHostErrorInfo, HostApiIndex, PaHostApiInfo};
-use crate::rportaudio;
-use crate::rpa_error::PaError;
+use crate::types::{PaHostErrorInfo, HostApiIndex, PaHostApiInfo, PortAudio, DeviceIndex, PaDeviceInfo};
+use crate::{device, rportaudio};
+use crate::rpa_error::{ErrorCode, PaError};
 
 /// Return information about the last host error encountered.
 ///
 /// The values in this structure will only be valid if a PortAudio function has previously returned
 /// the UnanticipatedHostError error code.
-pub fn last_error() -> Option<PaHostErrorInfo> {
-  rportaudio::last_host_error()
+pub fn last_error(pa: &PortAudio) -> Option<PaHostErrorInfo> {
+  rportaudio::last_host_error(pa)
 }
 
 
 /// Get the number of host API's available
-pub fn count() -> Result<u32, PaError> {
-  rportaudio::hostapi_count()
+pub fn count(pa: &PortAudio) -> Result<u32, PaError> {
+  rportaudio::hostapi_count(pa)
 }
 
 
 /// Get the default Host API
-pub fn default() -> Result<HostApiIndex, PaError> {
-  rportaudio::default_hostapi()
+pub fn default(pa: &PortAudio) -> Result<HostApiIndex, PaError> {
+  rportaudio::default_hostapi(pa)
 }
 
 
 /// Get information about a specific Host API
 ///
 /// Returns None when an invalid index is given
-pub fn info(index: HostApiIndex) -> Option<PaHostApiInfo> {
-  rportaudio::hostapi_info(index)
+pub fn info(pa: &PortAudio, index: HostApiIndex) -> Option<PaHostApiInfo> {
+  rportaudio::hostapi_info(pa, index)
 }
 
 
 
+
+
+/// Get the devices of a specific Host API, with their global device index
+///
+/// Returns Err(InvalidHostApi) when an invalid index is given
+pub fn devices(pa: &PortAudio, index: HostApiIndex) -> Result<Vec<(DeviceIndex, PaDeviceInfo)>, PaError> {
+  let api = info(pa, index).ok_or(ErrorCode::PaInvalidHostApi)?;
+  let mut devices = Vec::with_capacity(api.device_count as usize);
+  for hostapi_device_index in 0..api.device_count {
+    let device_index = device::hostapi_device_index_to_device_index(pa, index, hostapi_device_index)?;
+    let device_info = device::info(pa, device_index).ok_or(ErrorCode::PaInvalidDevice)?;
+    devices.push((device_index, device_info));
+  }
+  Ok(devices)
+}
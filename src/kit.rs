@@ -1,6 +1,7 @@
 use std::time::Duration;
 
 use crate::raw_portaudio;
+use crate::backend::NON_INTERLEAVED;
 use crate::rpa_error::{PaError, PaResult};
 
 pub fn to_pa_result(code: i32) -> PaResult {
@@ -22,6 +23,33 @@ pub fn duration_to_pa_time(duration: Duration) -> f64 {
   duration.as_secs() as f64 + (duration.subsec_nanos() as f64 * 1e-9)
 }
 
+/// Size in bytes of a single sample of the given PortAudio sample format
+pub fn sample_size(sample_format: u64) -> Option<usize> {
+  match sample_format & !NON_INTERLEAVED {
+    0x00000001 | 0x00000002 => Some(4),
+    0x00000004 => Some(3),
+    0x00000008 => Some(2),
+    0x00000010 | 0x00000020 => Some(1),
+    _ => None,
+  }
+}
+
+/// Borrow a buffer handed over by PortAudio, which is null when the direction is unused
+pub unsafe fn buffer_slice<'b, T>(buffer: *const T, len: usize) -> &'b [T] {
+  if buffer.is_null() || len == 0 {
+    return &[];
+  }
+  ::std::slice::from_raw_parts(buffer, len)
+}
+
+/// Mutably borrow a buffer handed over by PortAudio, which is null when the direction is unused
+pub unsafe fn buffer_slice_mut<'b, T>(buffer: *mut T, len: usize) -> &'b mut [T] {
+  if buffer.is_null() || len == 0 {
+    return &mut [];
+  }
+  ::std::slice::from_raw_parts_mut(buffer, len)
+}
+
 #[cfg(test)]
 mod test {
   #[test]
@@ -2,7 +2,8 @@
 extern crate bitflags;
 extern crate libc;
 
-pub use self::rportaudio::{error_text, initialize, terminate, version, version_text, hostapi_device_index_to_device_index};
+pub use self::rportaudio::{error_text, version, version_info, version_text};
+pub use self::types::PortAudio;
 
 use crate::pa_include::portaudio as raw_portaudio;
 
@@ -12,7 +13,19 @@ pub mod rpa_error;
 pub mod device;
 pub mod hostapi;
 pub mod stream;
+pub mod backend;
+pub mod ringbuffer;
+pub mod sample;
+pub mod frames;
 
 mod pa_include;
+mod bridge;
+mod builder;
+mod negotiate;
 mod rportaudio;
+mod session;
+mod stats;
 mod kit;
+mod lifecycle;
+mod planar;
+mod typed;
@@ -0,0 +1,325 @@
+use std::any::Any;
+use std::error;
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::rpa_error::{PaError, PaResult};
+use crate::stats::{StatsSnapshot, StreamStatistics};
+use crate::types::*;
+
+mod private {
+  pub trait Sealed {}
+}
+
+/// The states of a LifecycleStream
+pub trait StreamState: private::Sealed {}
+
+/// Opened or stopped, the stream can be started
+pub struct Stopped;
+
+/// Started and processing audio, the stream can be read, written, stopped or aborted
+pub struct Running;
+
+/// The callback returned Complete or Abort, the stream has to be stopped before it can restart
+pub struct Finished;
+
+impl private::Sealed for Stopped {}
+impl private::Sealed for Running {}
+impl private::Sealed for Finished {}
+impl StreamState for Stopped {}
+impl StreamState for Running {}
+impl StreamState for Finished {}
+
+
+/// A failed transition, which hands back the stream in the state it was in
+pub struct TransitionError<T> {
+  /// The stream, unchanged
+  pub stream: T,
+
+  /// Why the transition failed
+  pub error: PaError,
+}
+
+impl<T> fmt::Display for TransitionError<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+    fmt::Display::fmt(&self.error, f)
+  }
+}
+
+impl<T> fmt::Debug for TransitionError<T> {
+  fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+    ::std::fmt::Display::fmt(self, fmt)
+  }
+}
+
+impl<T> error::Error for TransitionError<T> {
+  fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+    Some(&self.error)
+  }
+}
+
+
+/// A Stream whose state is tracked in its type
+///
+/// Methods are only available in the states where they make sense, so reading from a stopped
+/// stream or starting a running one does not compile. `start`, `stop` and `abort` consume the
+/// stream and return it in its new state, or hand it back unchanged with the error.
+///
+/// ```no_run
+/// use rportaudio::PortAudio;
+/// use rportaudio::stream::{LifecycleStream, StreamProgress, FRAMES_PER_BUFFER_UNSPECIFIED};
+///
+/// let pa = PortAudio::new().unwrap();
+/// let stream = LifecycleStream::open_default(&pa, 0, 2, 44100.0, FRAMES_PER_BUFFER_UNSPECIFIED, None).unwrap();
+/// let running = stream.start().map_err(|e| e.error).unwrap();
+/// running.write(&[0.0f32; 512]).unwrap();
+/// let progress = running.poll().map_err(|e| e.error).unwrap();
+/// match progress {
+///   StreamProgress::Running(running) => { running.stop().map_err(|e| e.error).unwrap(); }
+///   StreamProgress::Finished(finished) => { finished.stop().map_err(|e| e.error).unwrap(); }
+/// }
+/// ```
+///
+/// ```compile_fail
+/// use rportaudio::PortAudio;
+/// use rportaudio::stream::{LifecycleStream, FRAMES_PER_BUFFER_UNSPECIFIED};
+///
+/// let pa = PortAudio::new().unwrap();
+/// let stream = LifecycleStream::open_default(&pa, 0, 2, 44100.0, FRAMES_PER_BUFFER_UNSPECIFIED, None).unwrap();
+/// // A stopped stream can not be written to
+/// stream.write(&[0.0f32; 512]);
+/// ```
+pub struct LifecycleStream<'a, I: SampleType, O: SampleType, S: StreamState> {
+  stream: Stream<'a, I, O>,
+  state: PhantomData<S>,
+}
+
+/// What `LifecycleStream::poll` found a running stream to be doing
+pub enum StreamProgress<'a, I: SampleType, O: SampleType> {
+  /// Still processing audio
+  Running(LifecycleStream<'a, I, O, Running>),
+
+  /// The callback completed or aborted the stream
+  Finished(LifecycleStream<'a, I, O, Finished>),
+}
+
+impl<'a, I: SampleType, O: SampleType, S: StreamState> LifecycleStream<'a, I, O, S> {
+  fn with_state<T: StreamState>(self) -> LifecycleStream<'a, I, O, T> {
+    LifecycleStream { stream: self.stream, state: PhantomData }
+  }
+
+  /// Give up the tracking and return the dynamically checked stream
+  pub fn into_inner(self) -> Stream<'a, I, O> {
+    self.stream
+  }
+
+  /// Aborts the stream if needed and closes it
+  pub fn close(self) -> PaResult {
+    self.stream.close()
+  }
+
+  /// Returns the cpu load the stream callback consumes
+  pub fn cpu_load(&self) -> f64 {
+    self.stream.cpu_load()
+  }
+
+  /// Get the current timestamp of the stream
+  pub fn time(&self) -> Duration {
+    self.stream.time()
+  }
+
+  /// Get the actual latencies and sample rate
+  pub fn info(&self) -> Option<PaStreamInfo> {
+    self.stream.info()
+  }
+
+  /// Statistics of the callback, see `Stream::statistics`
+  pub fn statistics(&self) -> Arc<StreamStatistics> {
+    self.stream.statistics()
+  }
+
+  /// A snapshot of the statistics of the callback
+  pub fn stats(&self) -> StatsSnapshot {
+    self.stream.stats()
+  }
+
+  /// Take the payload of a panic raised by the callback or the finished callback
+  pub fn take_panic(&self) -> Option<Box<dyn Any + Send>> {
+    self.stream.take_panic()
+  }
+}
+
+impl<'a, T: SampleType> LifecycleStream<'a, T, T, Stopped> {
+  /// Constructs a stopped stream using the default input and output devices, see
+  /// `Stream::open_default`
+  pub fn open_default(pa: &'a PortAudio,
+                      num_input_channels: u32,
+                      num_output_channels: u32,
+                      sample_rate: f64,
+                      frames_per_buffer: u64,
+                      callback: Option<Box<StreamCallback<'a, T, T>>>)
+                      -> Result<Self, PaError> {
+    let stream = Stream::open_default(pa, num_input_channels, num_output_channels, sample_rate, frames_per_buffer, callback)?;
+    Ok(LifecycleStream { stream, state: PhantomData })
+  }
+}
+
+impl<'a, I: SampleType, O: SampleType> LifecycleStream<'a, I, O, Stopped> {
+  /// Constructs a stopped stream, see `Stream::open`
+  pub fn open(pa: &'a PortAudio,
+              input: Option<PaStreamParameters<I>>,
+              output: Option<PaStreamParameters<O>>,
+              sample_rate: f64,
+              frames_per_buffer: u64,
+              flags: PaStreamFlags,
+              callback: Option<Box<StreamCallback<'a, I, O>>>)
+              -> Result<Self, PaError> {
+    let stream = Stream::open(pa, input, output, sample_rate, frames_per_buffer, flags, callback)?;
+    Ok(LifecycleStream { stream, state: PhantomData })
+  }
+
+  /// Track the state of a stream, stopping it first when it is not stopped
+  pub fn from_stream(stream: Stream<'a, I, O>) -> Result<Self, TransitionError<Stream<'a, I, O>>> {
+    match stream.is_stopped() {
+      Ok(true) => {}
+      Ok(false) => if let Err(error) = stream.stop() {
+        return Err(TransitionError { stream, error });
+      },
+      Err(error) => return Err(TransitionError { stream, error }),
+    }
+    Ok(LifecycleStream { stream, state: PhantomData })
+  }
+
+  /// Starts the stream
+  pub fn start(self) -> Result<LifecycleStream<'a, I, O, Running>, TransitionError<Self>> {
+    match self.stream.start() {
+      Ok(()) => Ok(self.with_state()),
+      Err(error) => Err(TransitionError { stream: self, error }),
+    }
+  }
+
+  /// Set a callback which is to be called when the StreamCallback finishes
+  pub fn set_finished_callback(&mut self, finished_callback: Box<StreamFinishedCallback<'a>>) -> PaResult {
+    self.stream.set_finished_callback(finished_callback)
+  }
+
+  /// Remove any previously attached finish callback
+  pub fn unset_finished_callback(&mut self) -> PaResult {
+    self.stream.unset_finished_callback()
+  }
+}
+
+impl<'a, I: SampleType, O: SampleType> LifecycleStream<'a, I, O, Running> {
+  /// Stops the stream once all buffers have played
+  pub fn stop(self) -> Result<LifecycleStream<'a, I, O, Stopped>, TransitionError<Self>> {
+    match self.stream.stop() {
+      Ok(()) => Ok(self.with_state()),
+      Err(error) => Err(TransitionError { stream: self, error }),
+    }
+  }
+
+  /// Stops the stream immediately
+  pub fn abort(self) -> Result<LifecycleStream<'a, I, O, Stopped>, TransitionError<Self>> {
+    match self.stream.abort() {
+      Ok(()) => Ok(self.with_state()),
+      Err(error) => Err(TransitionError { stream: self, error }),
+    }
+  }
+
+  /// Find out whether the callback finished the stream
+  pub fn poll(self) -> Result<StreamProgress<'a, I, O>, TransitionError<Self>> {
+    match self.stream.is_active() {
+      Ok(true) => Ok(StreamProgress::Running(self)),
+      Ok(false) => Ok(StreamProgress::Finished(self.with_state())),
+      Err(error) => Err(TransitionError { stream: self, error }),
+    }
+  }
+
+  /// Write the given buffer to the stream, see `Stream::write`
+  pub fn write(&self, buffer: &[O]) -> Result<IoStatus, PaError> {
+    self.stream.write(buffer)
+  }
+
+  /// Read the requested number of frames, see `Stream::read`
+  pub fn read(&self, frames: u32) -> Result<(Vec<I>, IoStatus), PaError> {
+    self.stream.read(frames)
+  }
+
+  /// Fill the whole buffer with input frames, see `Stream::read_into`
+  pub fn read_into(&self, buffer: &mut [I]) -> Result<(usize, IoStatus), PaError> {
+    self.stream.read_into(buffer)
+  }
+
+  /// Read the frames which are available without blocking, see `Stream::try_read`
+  pub fn try_read(&self, buffer: &mut [I]) -> Result<(usize, IoStatus), PaError> {
+    self.stream.try_read(buffer)
+  }
+
+  /// Write as many frames as possible without blocking, see `Stream::try_write`
+  pub fn try_write(&self, buffer: &[O]) -> Result<(usize, IoStatus), PaError> {
+    self.stream.try_write(buffer)
+  }
+
+  /// Get the number of frames that can be read from the stream without waiting
+  pub fn num_read_available(&self) -> Result<u32, PaError> {
+    self.stream.num_read_available()
+  }
+
+  /// Get the number of frames that can be written to the stream without waiting
+  pub fn num_write_available(&self) -> Result<u32, PaError> {
+    self.stream.num_write_available()
+  }
+}
+
+impl<'a, I: SampleType, O: SampleType> LifecycleStream<'a, I, O, Finished> {
+  /// Stops the finished stream, after which it can be started again
+  pub fn stop(self) -> Result<LifecycleStream<'a, I, O, Stopped>, TransitionError<Self>> {
+    match self.stream.stop() {
+      Ok(()) => Ok(self.with_state()),
+      Err(error) => Err(TransitionError { stream: self, error }),
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use std::thread;
+  use std::time::Duration;
+
+  use crate::backend::VirtualBackend;
+  use crate::types::*;
+
+  use super::{LifecycleStream, StreamProgress};
+
+  #[test]
+  fn test_lifecycle() {
+    let pa = PortAudio::with_backend(VirtualBackend::default()).unwrap();
+    let mut buffers = 0;
+    let callback = Box::new(move |_input: &[f32], _output: &mut [f32], _time, _flags| {
+      buffers += 1;
+      match buffers {
+        3 => PaStreamCallbackResult::Complete,
+        _ => PaStreamCallbackResult::Continue,
+      }
+    });
+    let stream = LifecycleStream::open_default(&pa, 0, 2, 48000.0, 64, Some(callback)).unwrap();
+
+    let mut running = stream.start().map_err(|e| e.error).unwrap();
+    let finished = loop {
+      match running.poll().map_err(|e| e.error).unwrap() {
+        StreamProgress::Running(stream) => running = stream,
+        StreamProgress::Finished(finished) => break finished,
+      }
+      thread::sleep(Duration::from_millis(1));
+    };
+    assert_eq!(finished.stats().callbacks, 3);
+
+    // A finished stream is stopped before it starts over
+    let running = finished.stop().map_err(|e| e.error).unwrap().start().map_err(|e| e.error).unwrap();
+    let stopped = running.abort().map_err(|e| e.error).unwrap();
+    assert_eq!(stopped.into_inner().is_stopped(), Ok(true));
+  }
+}
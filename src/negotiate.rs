@@ -0,0 +1,406 @@
+use crate::backend::RawStreamParameters;
+use crate::builder::{self, Latency};
+use crate::rpa_error::{ErrorCode, PaError, StreamBuilderError};
+use crate::rportaudio;
+use crate::sample::{self, Dither};
+use crate::types::*;
+
+/// The configuration a FormatNegotiator settled on
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct StreamConfig {
+  /// Sample rate of the stream
+  pub sample_rate: f64,
+
+  /// Number of input channels, 0 for an output-only stream
+  pub input_channels: u32,
+
+  /// Number of output channels, 0 for an input-only stream
+  pub output_channels: u32,
+
+  /// Sample format of both directions
+  pub sample_format: SampleFormat,
+}
+
+
+/// A blocking stream of the sample format that was negotiated
+pub enum AnyStream<'a> {
+  /// A stream of f32 samples
+  F32(Stream<'a, f32, f32>),
+  /// A stream of i32 samples
+  I32(Stream<'a, i32, i32>),
+  /// A stream of packed 24 bit samples
+  I24(Stream<'a, I24, I24>),
+  /// A stream of i16 samples
+  I16(Stream<'a, i16, i16>),
+  /// A stream of i8 samples
+  I8(Stream<'a, i8, i8>),
+  /// A stream of u8 samples
+  U8(Stream<'a, u8, u8>),
+}
+
+impl<'a> AnyStream<'a> {
+  /// The sample format of the stream
+  pub fn sample_format(&self) -> SampleFormat {
+    match *self {
+      AnyStream::F32(_) => SampleFormat::F32,
+      AnyStream::I32(_) => SampleFormat::I32,
+      AnyStream::I24(_) => SampleFormat::I24,
+      AnyStream::I16(_) => SampleFormat::I16,
+      AnyStream::I8(_) => SampleFormat::I8,
+      AnyStream::U8(_) => SampleFormat::U8,
+    }
+  }
+
+  /// Get the actual latencies and sample rate
+  pub fn info(&self) -> Option<PaStreamInfo> {
+    match *self {
+      AnyStream::F32(ref stream) => stream.info(),
+      AnyStream::I32(ref stream) => stream.info(),
+      AnyStream::I24(ref stream) => stream.info(),
+      AnyStream::I16(ref stream) => stream.info(),
+      AnyStream::I8(ref stream) => stream.info(),
+      AnyStream::U8(ref stream) => stream.info(),
+    }
+  }
+
+  fn open(pa: &'a PortAudio, config: &StreamConfig, input: Option<RawStreamParameters>, output: Option<RawStreamParameters>,
+          frames_per_buffer: u64, flags: PaStreamFlags) -> Result<AnyStream<'a>, PaError> {
+    let rate = config.sample_rate;
+    Ok(match config.sample_format {
+      SampleFormat::F32 => AnyStream::F32(rportaudio::open_raw_stream(pa, input, output, rate, frames_per_buffer, flags, None)?),
+      SampleFormat::I32 => AnyStream::I32(rportaudio::open_raw_stream(pa, input, output, rate, frames_per_buffer, flags, None)?),
+      SampleFormat::I24 => AnyStream::I24(rportaudio::open_raw_stream(pa, input, output, rate, frames_per_buffer, flags, None)?),
+      SampleFormat::I16 => AnyStream::I16(rportaudio::open_raw_stream(pa, input, output, rate, frames_per_buffer, flags, None)?),
+      SampleFormat::I8 => AnyStream::I8(rportaudio::open_raw_stream(pa, input, output, rate, frames_per_buffer, flags, None)?),
+      SampleFormat::U8 => AnyStream::U8(rportaudio::open_raw_stream(pa, input, output, rate, frames_per_buffer, flags, None)?),
+    })
+  }
+  /// Open a callback stream in the format of the configuration, converting from and to f32
+  #[allow(clippy::too_many_arguments)]
+  fn open_f32(pa: &'a PortAudio, config: &StreamConfig, input: Option<RawStreamParameters>, output: Option<RawStreamParameters>,
+              frames_per_buffer: u64, flags: PaStreamFlags, dither: Dither, callback: Box<StreamCallback<'a, f32, f32>>)
+              -> Result<AnyStream<'a>, PaError> {
+    let rate = config.sample_rate;
+    let (inputs, outputs) = (config.input_channels, config.output_channels);
+    Ok(match config.sample_format {
+      SampleFormat::F32 => AnyStream::F32(rportaudio::open_raw_stream(pa, input, output, rate, frames_per_buffer, flags, Some(callback))?),
+      SampleFormat::I32 => {
+        let callback = sample::f32_callback(inputs, outputs, frames_per_buffer, dither, callback);
+        AnyStream::I32(rportaudio::open_raw_stream(pa, input, output, rate, frames_per_buffer, flags, Some(callback))?)
+      }
+      SampleFormat::I24 => {
+        let callback = sample::f32_callback(inputs, outputs, frames_per_buffer, dither, callback);
+        AnyStream::I24(rportaudio::open_raw_stream(pa, input, output, rate, frames_per_buffer, flags, Some(callback))?)
+      }
+      SampleFormat::I16 => {
+        let callback = sample::f32_callback(inputs, outputs, frames_per_buffer, dither, callback);
+        AnyStream::I16(rportaudio::open_raw_stream(pa, input, output, rate, frames_per_buffer, flags, Some(callback))?)
+      }
+      SampleFormat::I8 => {
+        let callback = sample::f32_callback(inputs, outputs, frames_per_buffer, dither, callback);
+        AnyStream::I8(rportaudio::open_raw_stream(pa, input, output, rate, frames_per_buffer, flags, Some(callback))?)
+      }
+      SampleFormat::U8 => {
+        let callback = sample::f32_callback(inputs, outputs, frames_per_buffer, dither, callback);
+        AnyStream::U8(rportaudio::open_raw_stream(pa, input, output, rate, frames_per_buffer, flags, Some(callback))?)
+      }
+    })
+  }
+}
+
+
+/// A stream opened by a FormatNegotiator, with what it settled on
+pub struct NegotiatedStream<S> {
+  /// The configuration that was chosen
+  pub config: StreamConfig,
+
+  /// The latencies and sample rate the stream actually got
+  pub info: PaStreamInfo,
+
+  /// The stream itself
+  pub stream: S,
+}
+
+
+/// Opens a stream with the first configuration a device accepts
+///
+/// Sample rates, channel counts and sample formats are each given as a list in order of
+/// preference. The sample rate weighs heaviest, followed by the input channels, the output
+/// channels and finally the sample format: every format is tried at the preferred channel counts
+/// before a channel count is given up, and every channel count before a sample rate is. Candidates
+/// are checked with `is_format_supported`.
+///
+/// Without sample rates the default sample rate of the output device is tried, or of the input
+/// device for an input-only stream. The sample formats default to `SampleFormat::ALL`.
+///
+/// When nothing works, the error of the most preferred configuration is returned.
+///
+/// ```no_run
+/// use rportaudio::PortAudio;
+/// use rportaudio::device;
+/// use rportaudio::stream::{AnyStream, FormatNegotiator};
+/// use rportaudio::types::SampleFormat;
+///
+/// let pa = PortAudio::new().unwrap();
+/// let output = device::default_output(&pa).unwrap();
+/// let negotiated = FormatNegotiator::new(&pa)
+///   .output(output, &[2, 1])
+///   .sample_rates(&[48000.0, 44100.0])
+///   .sample_formats(&[SampleFormat::F32, SampleFormat::I16])
+///   .blocking()
+///   .unwrap();
+/// println!("{:?} at {} Hz", negotiated.config, negotiated.info.sample_rate);
+/// if let AnyStream::F32(stream) = negotiated.stream {
+///   stream.start().unwrap();
+/// }
+/// ```
+pub struct FormatNegotiator<'a> {
+  pa: &'a PortAudio,
+  input: Option<(DeviceIndex, Vec<u32>)>,
+  output: Option<(DeviceIndex, Vec<u32>)>,
+  sample_rates: Vec<f64>,
+  sample_formats: Vec<SampleFormat>,
+  frames_per_buffer: u64,
+  latency: Latency,
+  flags: PaStreamFlags,
+}
+
+impl<'a> FormatNegotiator<'a> {
+  /// Start negotiating a stream without input or output
+  pub fn new(pa: &'a PortAudio) -> Self {
+    FormatNegotiator {
+      pa,
+      input: None,
+      output: None,
+      sample_rates: Vec::new(),
+      sample_formats: SampleFormat::ALL.to_vec(),
+      frames_per_buffer: 0,
+      latency: Latency::default(),
+      flags: PaStreamFlags::empty(),
+    }
+  }
+
+  /// Record from `device`, with one of the channel counts
+  pub fn input(mut self, device: DeviceIndex, channel_counts: &[u32]) -> Self {
+    self.input = Some((device, channel_counts.to_vec()));
+    self
+  }
+
+  /// Play to `device`, with one of the channel counts
+  pub fn output(mut self, device: DeviceIndex, channel_counts: &[u32]) -> Self {
+    self.output = Some((device, channel_counts.to_vec()));
+    self
+  }
+
+  /// Sample rates to try
+  pub fn sample_rates(mut self, sample_rates: &[f64]) -> Self {
+    self.sample_rates = sample_rates.to_vec();
+    self
+  }
+
+  /// Sample formats to try
+  pub fn sample_formats(mut self, sample_formats: &[SampleFormat]) -> Self {
+    self.sample_formats = sample_formats.to_vec();
+    self
+  }
+
+  /// Number of frames per buffer, FRAMES_PER_BUFFER_UNSPECIFIED lets PortAudio choose
+  pub fn frames_per_buffer(mut self, frames_per_buffer: u64) -> Self {
+    self.frames_per_buffer = frames_per_buffer;
+    self
+  }
+
+  /// Latency of both directions, High by default
+  pub fn latency(mut self, latency: Latency) -> Self {
+    self.latency = latency;
+    self
+  }
+
+  /// Additional flags for the behaviour of the stream
+  pub fn flags(mut self, flags: PaStreamFlags) -> Self {
+    self.flags = flags;
+    self
+  }
+
+  /// Find the first supported configuration without opening a stream
+  pub fn negotiate(&self) -> Result<StreamConfig, StreamBuilderError> {
+    self.try_each(&self.sample_formats, |config, _, _| Ok(*config))
+  }
+
+  /// Open a blocking stream with the first configuration that works
+  ///
+  /// When opening fails although the configuration is supported, the next one is tried.
+  pub fn blocking(self) -> Result<NegotiatedStream<AnyStream<'a>>, StreamBuilderError> {
+    let pa = self.pa;
+    self.try_each(&self.sample_formats, |config, input, output| {
+      let stream = AnyStream::open(pa, config, input, output, self.frames_per_buffer, self.flags)
+        .map_err(StreamBuilderError::Open)?;
+      let info = stream.info().ok_or(StreamBuilderError::Open(ErrorCode::PaBadStreamPtr.into()))?;
+      Ok(NegotiatedStream { config: *config, info, stream })
+    })
+  }
+
+  /// Open a stream of `T` samples which calls `callback` to process the buffers
+  ///
+  /// Only the configurations in the sample format of `T` are tried, and only the first supported
+  /// one is opened.
+  pub fn callback<T: SampleType>(self, callback: Box<StreamCallback<'a, T, T>>)
+                                 -> Result<NegotiatedStream<Stream<'a, T, T>>, StreamBuilderError> {
+    let formats: Vec<SampleFormat> = self.sample_formats.iter()
+      .cloned()
+      .filter(|format| format.sample_format() == T::sample_format())
+      .collect();
+    let (config, input, output) = self.try_each(&formats, |config, input, output| Ok((*config, input, output)))?;
+    let stream = rportaudio::open_raw_stream(self.pa, input, output, config.sample_rate, self.frames_per_buffer, self.flags, Some(callback))
+      .map_err(StreamBuilderError::Open)?;
+    let info = stream.info().ok_or(StreamBuilderError::Open(ErrorCode::PaBadStreamPtr.into()))?;
+    Ok(NegotiatedStream { config, info, stream })
+  }
+
+  /// Open a stream in the first supported format which calls a `callback` working in f32
+  ///
+  /// The samples are converted on the audio thread, see `sample::f32_callback`, so the device can
+  /// run in its native format. Only the first supported configuration is opened.
+  pub fn f32_callback(self, dither: Dither, callback: Box<StreamCallback<'a, f32, f32>>)
+                      -> Result<NegotiatedStream<AnyStream<'a>>, StreamBuilderError> {
+    let (config, input, output) = self.try_each(&self.sample_formats, |config, input, output| Ok((*config, input, output)))?;
+    let stream = AnyStream::open_f32(self.pa, &config, input, output, self.frames_per_buffer, self.flags, dither, callback)
+      .map_err(StreamBuilderError::Open)?;
+    let info = stream.info().ok_or(StreamBuilderError::Open(ErrorCode::PaBadStreamPtr.into()))?;
+    Ok(NegotiatedStream { config, info, stream })
+  }
+
+  /// Call `attempt` with the supported configurations in order of preference, until it succeeds
+  fn try_each<R, F>(&self, sample_formats: &[SampleFormat], mut attempt: F) -> Result<R, StreamBuilderError>
+    where F: FnMut(&StreamConfig, Option<RawStreamParameters>, Option<RawStreamParameters>) -> Result<R, StreamBuilderError> {
+    if self.input.is_none() && self.output.is_none() {
+      return Err(StreamBuilderError::NoDirection);
+    }
+
+    let sample_rates = match self.sample_rates.is_empty() {
+      true => {
+        let (device, _) = self.output.as_ref().or(self.input.as_ref()).unwrap();
+        let info = rportaudio::device_info(self.pa, *device).ok_or(StreamBuilderError::InvalidDevice(*device))?;
+        vec![info.default_sample_rate]
+      }
+      false => self.sample_rates.clone(),
+    };
+    // A missing direction takes part as a single channel count of 0
+    let input_channels = self.input.as_ref().map_or(vec![0], |(_, counts)| counts.clone());
+    let output_channels = self.output.as_ref().map_or(vec![0], |(_, counts)| counts.clone());
+
+    let mut first_error = None;
+    for &sample_rate in &sample_rates {
+      for &input_count in &input_channels {
+        for &output_count in &output_channels {
+          for &sample_format in sample_formats {
+            let config = StreamConfig { sample_rate, input_channels: input_count, output_channels: output_count, sample_format };
+            match self.check(&config).and_then(|(input, output)| attempt(&config, input, output)) {
+              Ok(result) => return Ok(result),
+              Err(e) => { first_error.get_or_insert(e); }
+            }
+          }
+        }
+      }
+    }
+    Err(first_error.unwrap_or(StreamBuilderError::NoConfiguration))
+  }
+
+  fn check(&self, config: &StreamConfig) -> Result<(Option<RawStreamParameters>, Option<RawStreamParameters>), StreamBuilderError> {
+    let format = config.sample_format.sample_format();
+    let input = match self.input {
+      Some((device, _)) => Some(builder::parameters(self.pa, true, device, config.input_channels, format, self.latency)?.0),
+      None => None,
+    };
+    let output = match self.output {
+      Some((device, _)) => Some(builder::parameters(self.pa, false, device, config.output_channels, format, self.latency)?.0),
+      None => None,
+    };
+    rportaudio::is_raw_format_supported(self.pa, input.as_ref(), output.as_ref(), config.sample_rate)
+      .map_err(StreamBuilderError::Unsupported)?;
+    Ok((input, output))
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use std::sync::{Arc, Mutex};
+  use std::thread;
+  use std::time::Duration;
+
+  use crate::backend::{VirtualBackend, VirtualDevice};
+  use crate::rpa_error::StreamBuilderError;
+  use crate::sample::Dither;
+  use crate::types::*;
+
+  use super::{AnyStream, FormatNegotiator, StreamConfig};
+
+  #[test]
+  fn test_negotiate() {
+    let backend = VirtualBackend::new()
+      .with_device(VirtualDevice::output("Speakers", 2).with_sample_rate(44100.0).with_sample_rates(&[44100.0]));
+    let pa = PortAudio::with_backend(backend).unwrap();
+
+    let negotiated = FormatNegotiator::new(&pa)
+      .output(0, &[6, 2])
+      .sample_rates(&[48000.0, 44100.0])
+      .sample_formats(&[SampleFormat::I16, SampleFormat::F32])
+      .blocking()
+      .unwrap();
+    assert_eq!(negotiated.config, StreamConfig { sample_rate: 44100.0, input_channels: 0, output_channels: 2, sample_format: SampleFormat::I16 });
+    assert_eq!(negotiated.info.sample_rate, 44100.0);
+    assert!(matches!(negotiated.stream, AnyStream::I16(_)));
+
+    let stream = FormatNegotiator::new(&pa)
+      .output(0, &[1])
+      .callback(Box::new(|_input: &[f32], _output: &mut [f32], _time, _flags| PaStreamCallbackResult::Continue))
+      .unwrap();
+    assert_eq!(stream.config.sample_format, SampleFormat::F32);
+    assert_eq!(stream.config.sample_rate, 44100.0);
+
+    // The most preferred configuration tells why nothing worked
+    let error = FormatNegotiator::new(&pa).output(0, &[6, 2]).sample_rates(&[48000.0]).negotiate().unwrap_err();
+    assert_eq!(error, StreamBuilderError::InvalidOutputChannels { device: 0, requested: 6, max: 2 });
+    assert_eq!(FormatNegotiator::new(&pa).output(0, &[]).negotiate().unwrap_err(), StreamBuilderError::NoConfiguration);
+  }
+
+  #[test]
+  fn test_f32_callback() {
+    let pa = PortAudio::with_backend(VirtualBackend::default()).unwrap();
+    let heard = Arc::new(Mutex::new(Vec::new()));
+    let callback_heard = heard.clone();
+    let callback = Box::new(move |input: &[f32], output: &mut [f32], _time, _flags| {
+      callback_heard.lock().unwrap().push(input[0]);
+      for sample in output.iter_mut() {
+        *sample = 0.5;
+      }
+      match callback_heard.lock().unwrap().len() {
+        3 => PaStreamCallbackResult::Complete,
+        _ => PaStreamCallbackResult::Continue,
+      }
+    });
+
+    let negotiated = FormatNegotiator::new(&pa)
+      .input(0, &[2])
+      .output(0, &[2])
+      .sample_formats(&[SampleFormat::I16])
+      .frames_per_buffer(64)
+      .f32_callback(Dither::Tpdf, callback)
+      .unwrap();
+    let stream = match negotiated.stream {
+      AnyStream::I16(stream) => stream,
+      _ => panic!("expected an i16 stream"),
+    };
+    stream.start().unwrap();
+    while stream.is_active() == Ok(true) {
+      thread::sleep(Duration::from_millis(1));
+    }
+
+    // What is played comes back, give or take the dither
+    let heard = heard.lock().unwrap();
+    assert_eq!(heard.len(), 3);
+    assert_eq!(heard[0], 0.0);
+    assert!(heard[1..].iter().all(|&sample| (sample - 0.5).abs() <= 1.0 / 16384.0));
+  }
+}
@@ -15,6 +15,17 @@ extern {}
 
 /* automatically generated by rust-bindgen */
 
+#[repr(C)]
+pub struct Struct_PaVersionInfo {
+  pub versionMajor: ::libc::c_int,
+  pub versionMinor: ::libc::c_int,
+  pub versionSubMinor: ::libc::c_int,
+  pub versionControlRevision: *const ::libc::c_char,
+  pub versionText: *const ::libc::c_char,
+}
+
+pub type PaVersionInfo = Struct_PaVersionInfo;
+
 pub type PaError = ::libc::c_int;
 pub type Enum_PaErrorCode = ::libc::c_int;
 
@@ -155,6 +166,7 @@ pub type PaStreamInfo = Struct_PaStreamInfo;
 extern "C" {
   pub fn Pa_GetVersion() -> ::libc::c_int;
   pub fn Pa_GetVersionText() -> *const ::libc::c_char;
+  pub fn Pa_GetVersionInfo() -> *const PaVersionInfo;
   pub fn Pa_GetErrorText(errorCode: PaError) -> *const ::libc::c_char;
   pub fn Pa_Initialize() -> PaError;
   pub fn Pa_Terminate() -> PaError;
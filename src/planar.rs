@@ -0,0 +1,242 @@
+use std::any::Any;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::rpa_error::{PaError, PaResult};
+use crate::rportaudio;
+use crate::stats::{StatsSnapshot, StreamStatistics};
+use crate::types::*;
+
+/// A non-interleaved stream, which keeps the samples of every channel apart
+///
+/// The callback gets one slice per channel, and the blocking reads and writes take one buffer per
+/// channel, instead of interleaved frames.
+///
+/// ```no_run
+/// use rportaudio::PortAudio;
+/// use rportaudio::stream::{PlanarStream, FRAMES_PER_BUFFER_UNSPECIFIED};
+/// use rportaudio::types::PaStreamCallbackResult;
+///
+/// let pa = PortAudio::new().unwrap();
+/// let callback = Box::new(|_input: &[&[f32]], output: &mut [&mut [f32]], _time, _flags| {
+///   let (left, right) = output.split_at_mut(1);
+///   left[0].copy_from_slice(right[0]);
+///   PaStreamCallbackResult::Continue
+/// });
+/// let stream = PlanarStream::open_default(&pa, 0, 2, 44100.0, FRAMES_PER_BUFFER_UNSPECIFIED, Some(callback)).unwrap();
+/// stream.start().unwrap();
+/// ```
+pub struct PlanarStream<'a, I: SampleType, O: SampleType> {
+  pub(crate) stream: Stream<'a, I, O>,
+}
+
+impl<'a, T: SampleType> PlanarStream<'a, T, T> {
+  /// Constructs a non-interleaved stream using the default input and output devices, see
+  /// `Stream::open_default`
+  pub fn open_default(pa: &'a PortAudio,
+                      num_input_channels: u32,
+                      num_output_channels: u32,
+                      sample_rate: f64,
+                      frames_per_buffer: u64,
+                      callback: Option<Box<PlanarStreamCallback<'a, T, T>>>)
+                      -> Result<PlanarStream<'a, T, T>, PaError> {
+    rportaudio::open_planar_default_stream(
+      pa,
+      num_input_channels,
+      num_output_channels,
+      sample_rate,
+      frames_per_buffer,
+      callback,
+    )
+  }
+}
+
+impl<'a, I: SampleType, O: SampleType> PlanarStream<'a, I, O> {
+  /// Constructs a non-interleaved stream, see `Stream::open`
+  pub fn open(pa: &'a PortAudio,
+              input: Option<PaStreamParameters<I>>,
+              output: Option<PaStreamParameters<O>>,
+              sample_rate: f64,
+              frames_per_buffer: u64,
+              flags: PaStreamFlags,
+              callback: Option<Box<PlanarStreamCallback<'a, I, O>>>)
+              -> Result<PlanarStream<'a, I, O>, PaError> {
+    rportaudio::open_planar_stream(
+      pa,
+      input,
+      output,
+      sample_rate,
+      frames_per_buffer,
+      flags,
+      callback,
+    )
+  }
+
+  /// Starts the stream
+  pub fn start(&self) -> PaResult {
+    self.stream.start()
+  }
+
+  /// Stops the stream. It will block untill all audio has finished playing
+  pub fn stop(&self) -> PaResult {
+    self.stream.stop()
+  }
+
+  /// Stop stream immediately without waiting for the buffers to complete
+  pub fn abort(&self) -> PaResult {
+    self.stream.abort()
+  }
+
+  /// Aborts the stream if needed and closes it
+  pub fn close(self) -> PaResult {
+    self.stream.close()
+  }
+
+  /// Returns wether the stream is stopped
+  pub fn is_stopped(&self) -> Result<bool, PaError> {
+    self.stream.is_stopped()
+  }
+
+  /// Returns wether the stream is active
+  pub fn is_active(&self) -> Result<bool, PaError> {
+    self.stream.is_active()
+  }
+
+  /// Get the number of frames that can be read from the stream without waiting
+  pub fn num_read_available(&self) -> Result<u32, PaError> {
+    self.stream.num_read_available()
+  }
+
+  /// Get the number of frames that can be written to the stream without waiting
+  pub fn num_write_available(&self) -> Result<u32, PaError> {
+    self.stream.num_write_available()
+  }
+
+  /// Write one buffer per output channel to the stream, the buffers must be equally long
+  pub fn write(&self, buffers: &[&[O]]) -> Result<IoStatus, PaError> {
+    rportaudio::write_planar_stream(&self.stream, buffers)
+  }
+
+  /// Read the requested number of frames, returning one Vec per input channel
+  pub fn read(&self, frames: u32) -> Result<(Vec<Vec<I>>, IoStatus), PaError> {
+    rportaudio::read_planar_stream(&self.stream, frames)
+  }
+
+  /// Fill one buffer per input channel, the buffers must be equally long
+  ///
+  /// Returns the number of frames read.
+  pub fn read_into(&self, buffers: &mut [&mut [I]]) -> Result<(usize, IoStatus), PaError> {
+    rportaudio::read_planar_stream_into(&self.stream, buffers)
+  }
+
+  /// Number of reads which reported an input overflow
+  pub fn input_overflows(&self) -> u64 {
+    self.stream.input_overflows()
+  }
+
+  /// Number of writes which reported an output underflow
+  pub fn output_underflows(&self) -> u64 {
+    self.stream.output_underflows()
+  }
+
+  /// Returns the cpu load the stream callback consumes
+  pub fn cpu_load(&self) -> f64 {
+    self.stream.cpu_load()
+  }
+
+  /// Get the current timestamp of the stream
+  pub fn time(&self) -> Duration {
+    self.stream.time()
+  }
+
+  /// Get the actual latencies and sample rate
+  pub fn info(&self) -> Option<PaStreamInfo> {
+    self.stream.info()
+  }
+
+  /// Statistics of the callback, see `Stream::statistics`
+  pub fn statistics(&self) -> Arc<StreamStatistics> {
+    self.stream.statistics()
+  }
+
+  /// A snapshot of the statistics of the callback
+  pub fn stats(&self) -> StatsSnapshot {
+    self.stream.stats()
+  }
+
+  /// Set a callback which is to be called when the PlanarStreamCallback finishes
+  pub fn set_finished_callback(&mut self, finished_callback: Box<StreamFinishedCallback<'a>>) -> PaResult {
+    self.stream.set_finished_callback(finished_callback)
+  }
+
+  /// Remove any previously attached finish callback
+  pub fn unset_finished_callback(&mut self) -> PaResult {
+    self.stream.unset_finished_callback()
+  }
+
+  /// Take the payload of a panic raised by the callback or the finished callback
+  pub fn take_panic(&self) -> Option<Box<dyn Any + Send>> {
+    self.stream.take_panic()
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use std::sync::{Arc, Mutex};
+  use std::thread;
+  use std::time::Duration;
+
+  use crate::backend::VirtualBackend;
+  use crate::rpa_error::ErrorCode;
+  use crate::types::*;
+
+  use super::PlanarStream;
+
+  #[test]
+  fn test_planar_callback() {
+    let pa = PortAudio::with_backend(VirtualBackend::default()).unwrap();
+    let heard = Arc::new(Mutex::new(Vec::new()));
+    let callback_heard = heard.clone();
+    let mut calls = 0;
+    let callback = Box::new(move |input: &[&[i16]], output: &mut [&mut [i16]], _time, _flags| {
+      callback_heard.lock().unwrap().push((input[0][0], input[1][31]));
+      calls += 1;
+      for (channel, samples) in output.iter_mut().enumerate() {
+        for sample in samples.iter_mut() {
+          *sample = calls * (channel as i16 + 1);
+        }
+      }
+      match calls {
+        3 => PaStreamCallbackResult::Complete,
+        _ => PaStreamCallbackResult::Continue,
+      }
+    });
+
+    let stream = PlanarStream::open_default(&pa, 2, 2, 48000.0, 32, Some(callback)).unwrap();
+    stream.start().unwrap();
+    while stream.is_active() == Ok(true) {
+      thread::sleep(Duration::from_millis(1));
+    }
+    stream.stop().unwrap();
+
+    // The loopback keeps the channels apart
+    assert_eq!(*heard.lock().unwrap(), vec![(0, 0), (1, 2), (2, 4)]);
+  }
+
+  #[test]
+  fn test_planar_blocking() {
+    let pa = PortAudio::with_backend(VirtualBackend::default()).unwrap();
+    let stream = PlanarStream::<f32, f32>::open_default(&pa, 2, 2, 48000.0, 0, None).unwrap();
+    stream.start().unwrap();
+
+    assert_eq!(stream.write(&[&[0.25, 0.5, 0.75], &[-0.25, -0.5, -0.75]]), Ok(IoStatus::Ok));
+    assert_eq!(stream.write(&[&[0.25, 0.5], &[-0.25]]).unwrap_err(), ErrorCode::PaBadBufferPtr);
+    assert_eq!(stream.write(&[&[0.25]]).unwrap_err(), ErrorCode::PaBadBufferPtr);
+
+    assert_eq!(stream.read(2).unwrap(), (vec![vec![0.25, 0.5], vec![-0.25, -0.5]], IoStatus::Ok));
+    let (mut left, mut right) = ([0.0; 4], [0.0; 4]);
+    assert_eq!(stream.read_into(&mut [&mut left[..], &mut right[..]]), Ok((4, IoStatus::Ok)));
+    assert_eq!((left, right), ([0.75, 0.0, 0.0, 0.0], [-0.75, 0.0, 0.0, 0.0]));
+  }
+}
@@ -0,0 +1,267 @@
+//! Wait-free single-producer, single-consumer ring buffer
+//!
+//! This is the Rust counterpart of PortAudio's `pa_ringbuffer`, the usual way to move samples
+//! between a stream callback and a thread that may block. Neither half ever locks or allocates,
+//! so one of them can be used from the callback.
+//!
+//! ```
+//! use rportaudio::ringbuffer::RingBuffer;
+//!
+//! let (mut producer, mut consumer) = RingBuffer::<f32>::new(8).unwrap().split();
+//! assert_eq!(producer.write(&[0.1, 0.2, 0.3]), 3);
+//!
+//! let mut samples = [0.0; 4];
+//! assert_eq!(consumer.read(&mut samples), 3);
+//! assert_eq!(&samples[..3], &[0.1, 0.2, 0.3]);
+//! ```
+
+use std::cell::UnsafeCell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::rpa_error::RingBufferError;
+
+struct Shared<T> {
+  buffer: Box<[UnsafeCell<T>]>,
+  mask: usize,
+  // Both indices count up forever and wrap around usize, the mask maps them into the buffer
+  write_index: AtomicUsize,
+  read_index: AtomicUsize,
+}
+
+// The producer only touches the elements between write_index and read_index + capacity, the
+// consumer only those between read_index and write_index, so they never alias.
+unsafe impl<T: Send> Sync for Shared<T> {}
+
+impl<T> Shared<T> {
+  fn capacity(&self) -> usize {
+    self.buffer.len()
+  }
+
+  /// The two parts of `count` elements from `index` on, which may wrap around the end
+  ///
+  /// Unsafe since the caller has to make sure the other half does not use these elements.
+  #[allow(clippy::mut_from_ref)]
+  unsafe fn regions(&self, index: usize, count: usize) -> (&mut [T], &mut [T]) {
+    let start = index & self.mask;
+    let first = count.min(self.capacity() - start);
+    let base = self.buffer.as_ptr() as *mut T;
+    (::std::slice::from_raw_parts_mut(base.add(start), first),
+     ::std::slice::from_raw_parts_mut(base, count - first))
+  }
+}
+
+
+/// A ring buffer whose capacity is a power of two, to be split into a Producer and a Consumer
+pub struct RingBuffer<T> {
+  shared: Arc<Shared<T>>,
+}
+
+impl<T: Copy + Default> RingBuffer<T> {
+  /// Allocate a ring buffer of `capacity` elements
+  ///
+  /// Returns NotPower2 when the capacity is not a power of two, and MemoryAllocateFail when the
+  /// memory cannot be allocated.
+  pub fn new(capacity: usize) -> Result<RingBuffer<T>, RingBufferError> {
+    if !capacity.is_power_of_two() {
+      return Err(RingBufferError::NotPower2("The capacity of a ring buffer must be a power of two"));
+    }
+    let mut buffer = Vec::new();
+    if buffer.try_reserve_exact(capacity).is_err() {
+      return Err(RingBufferError::MemoryAllocateFail("Not enough memory for the ring buffer"));
+    }
+    buffer.extend((0..capacity).map(|_| UnsafeCell::new(T::default())));
+
+    Ok(RingBuffer {
+      shared: Arc::new(Shared {
+        buffer: buffer.into_boxed_slice(),
+        mask: capacity - 1,
+        write_index: AtomicUsize::new(0),
+        read_index: AtomicUsize::new(0),
+      }),
+    })
+  }
+}
+
+impl<T> RingBuffer<T> {
+  /// Number of elements the buffer holds
+  pub fn capacity(&self) -> usize {
+    self.shared.capacity()
+  }
+
+  /// Split into the writing and the reading half, which can be sent to different threads
+  pub fn split(self) -> (Producer<T>, Consumer<T>) {
+    (Producer { shared: self.shared.clone() }, Consumer { shared: self.shared })
+  }
+}
+
+
+/// The writing half of a RingBuffer
+pub struct Producer<T> {
+  shared: Arc<Shared<T>>,
+}
+
+unsafe impl<T: Send> Send for Producer<T> {}
+
+impl<T: Copy> Producer<T> {
+  /// Number of elements the buffer holds
+  pub fn capacity(&self) -> usize {
+    self.shared.capacity()
+  }
+
+  /// Number of elements that can be written
+  pub fn write_available(&self) -> usize {
+    let read = self.shared.read_index.load(Ordering::Acquire);
+    let write = self.shared.write_index.load(Ordering::Relaxed);
+    self.capacity() - write.wrapping_sub(read)
+  }
+
+  /// The free space, as two slices to be filled in order
+  ///
+  /// The second slice is only non-empty when the space wraps around the end of the buffer. The
+  /// elements still hold old data. Call `advance_write_index` to hand what was filled in to the
+  /// consumer.
+  pub fn write_regions(&mut self) -> (&mut [T], &mut [T]) {
+    let available = self.write_available();
+    let write = self.shared.write_index.load(Ordering::Relaxed);
+    unsafe { self.shared.regions(write, available) }
+  }
+
+  /// Make `count` elements from the start of the write regions available to the consumer
+  ///
+  /// `count` is limited to what is available for writing. Returns the number of elements.
+  pub fn advance_write_index(&mut self, count: usize) -> usize {
+    let count = count.min(self.write_available());
+    let write = self.shared.write_index.load(Ordering::Relaxed);
+    self.shared.write_index.store(write.wrapping_add(count), Ordering::Release);
+    count
+  }
+
+  /// Copy as much of `data` into the buffer as fits, returning the number of elements written
+  pub fn write(&mut self, data: &[T]) -> usize {
+    let count = {
+      let (first, second) = self.write_regions();
+      let in_first = data.len().min(first.len());
+      let in_second = (data.len() - in_first).min(second.len());
+      first[..in_first].copy_from_slice(&data[..in_first]);
+      second[..in_second].copy_from_slice(&data[in_first..in_first + in_second]);
+      in_first + in_second
+    };
+    self.advance_write_index(count)
+  }
+}
+
+
+/// The reading half of a RingBuffer
+pub struct Consumer<T> {
+  shared: Arc<Shared<T>>,
+}
+
+unsafe impl<T: Send> Send for Consumer<T> {}
+
+impl<T: Copy> Consumer<T> {
+  /// Number of elements the buffer holds
+  pub fn capacity(&self) -> usize {
+    self.shared.capacity()
+  }
+
+  /// Number of elements that can be read
+  pub fn read_available(&self) -> usize {
+    let write = self.shared.write_index.load(Ordering::Acquire);
+    let read = self.shared.read_index.load(Ordering::Relaxed);
+    write.wrapping_sub(read)
+  }
+
+  /// The elements which can be read, as two slices in order
+  ///
+  /// The second slice is only non-empty when the data wraps around the end of the buffer. Call
+  /// `advance_read_index` to release what was used to the producer.
+  pub fn read_regions(&mut self) -> (&[T], &[T]) {
+    let available = self.read_available();
+    let read = self.shared.read_index.load(Ordering::Relaxed);
+    let (first, second) = unsafe { self.shared.regions(read, available) };
+    (first, second)
+  }
+
+  /// Release `count` elements from the start of the read regions to the producer
+  ///
+  /// `count` is limited to what is available for reading. Returns the number of elements.
+  pub fn advance_read_index(&mut self, count: usize) -> usize {
+    let count = count.min(self.read_available());
+    let read = self.shared.read_index.load(Ordering::Relaxed);
+    self.shared.read_index.store(read.wrapping_add(count), Ordering::Release);
+    count
+  }
+
+  /// Copy as many elements into `data` as are available, returning the number of elements read
+  pub fn read(&mut self, data: &mut [T]) -> usize {
+    let count = {
+      let (first, second) = self.read_regions();
+      let in_first = data.len().min(first.len());
+      let in_second = (data.len() - in_first).min(second.len());
+      data[..in_first].copy_from_slice(&first[..in_first]);
+      data[in_first..in_first + in_second].copy_from_slice(&second[..in_second]);
+      in_first + in_second
+    };
+    self.advance_read_index(count)
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use std::thread;
+
+  use crate::rpa_error::RingBufferError;
+
+  use super::RingBuffer;
+
+  #[test]
+  fn test_regions() {
+    assert!(matches!(RingBuffer::<i16>::new(6), Err(RingBufferError::NotPower2(_))));
+    assert!(matches!(RingBuffer::<i16>::new(0), Err(RingBufferError::NotPower2(_))));
+
+    let (mut producer, mut consumer) = RingBuffer::<i16>::new(4).unwrap().split();
+    assert_eq!(producer.write(&[1, 2, 3]), 3);
+    assert_eq!(consumer.advance_read_index(2), 2);
+
+    // The free space now wraps around the end
+    {
+      let (first, second) = producer.write_regions();
+      assert_eq!((first.len(), second.len()), (1, 2));
+      first[0] = 4;
+      second[0] = 5;
+    }
+    assert_eq!(producer.advance_write_index(5), 3);
+    assert_eq!(producer.write_available(), 0);
+    assert_eq!(producer.write(&[6]), 0);
+
+    // The element which was not filled in still holds the old sample
+    assert_eq!(consumer.read_regions(), (&[3, 4][..], &[5, 2][..]));
+    let mut data = [0; 8];
+    assert_eq!(consumer.read(&mut data), 4);
+    assert_eq!(&data[..4], &[3, 4, 5, 2]);
+    assert_eq!(consumer.read_available(), 0);
+  }
+
+  #[test]
+  fn test_threads() {
+    let (mut producer, mut consumer) = RingBuffer::<u32>::new(64).unwrap().split();
+    let writer = thread::spawn(move || {
+      let mut next = 0;
+      while next < 10000 {
+        let chunk: Vec<u32> = (next..(next + 37).min(10000)).collect();
+        next += producer.write(&chunk) as u32;
+      }
+    });
+
+    let mut received = Vec::new();
+    let mut data = [0; 50];
+    while received.len() < 10000 {
+      let count = consumer.read(&mut data);
+      received.extend_from_slice(&data[..count]);
+    }
+    writer.join().unwrap();
+    assert!(received.iter().enumerate().all(|(i, &value)| value == i as u32));
+  }
+}
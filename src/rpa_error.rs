@@ -1,11 +1,13 @@
 use std::{error, fmt};
 
 use crate::raw_portaudio;
+use crate::types::PaHostErrorInfo;
 
+/// The error codes of PortAudio, plus the errors raised by the bindings themselves
 #[repr(i32)]
-#[derive(PartialEq, Copy, Clone)]
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
 #[allow(missing_docs)]
-pub enum PaError {
+pub enum ErrorCode {
   PaNoError = raw_portaudio::paNoError,
   PaNotInitialized = raw_portaudio::paNotInitialized,
   PaUnanticipatedHostError = raw_portaudio::paUnanticipatedHostError,
@@ -67,113 +69,328 @@ pub enum PaError {
 //  PaCanNotWriteToAnInputOnlyStream = raw_portaudio::PaErrorCode_paCanNotWriteToAnInputOnlyStream,
 //  PaIncompatibleStreamHostApi = raw_portaudio::PaErrorCode_paIncompatibleStreamHostApi,
 //  PaBadBufferPtr = raw_portaudio::PaErrorCode_paBadBufferPtr,
-  UnknownError,
+
+  // The errors of the bindings are numbered apart from the -10000.. range of PortAudio
+  UnknownError = -20000,
+  /// The linked PortAudio library is not the version the bindings were written for
+  IncompatibleVersion = -20001,
 }
 
 
-impl PaError {
+impl ErrorCode {
   /// Get the enum value corresponding to the given i32
-  pub fn from_i32(num: i32) -> PaError {
+  pub fn from_i32(num: i32) -> ErrorCode {
     match num {
-      raw_portaudio::paNoError => PaError::PaNoError,
-      raw_portaudio::paNotInitialized => PaError::PaNotInitialized,
-      raw_portaudio::paUnanticipatedHostError => PaError::PaUnanticipatedHostError,
-      raw_portaudio::paInvalidChannelCount => PaError::PaInvalidChannelCount,
-      raw_portaudio::paInvalidSampleRate => PaError::PaInvalidSampleRate,
-      raw_portaudio::paInvalidDevice => PaError::PaInvalidDevice,
-      raw_portaudio::paInvalidFlag => PaError::PaInvalidFlag,
-      raw_portaudio::paSampleFormatNotSupported => PaError::PaSampleFormatNotSupported,
-      raw_portaudio::paBadIODeviceCombination => PaError::PaBadIODeviceCombination,
-      raw_portaudio::paInsufficientMemory => PaError::PaInsufficientMemory,
-      raw_portaudio::paBufferTooBig => PaError::PaBufferTooBig,
-      raw_portaudio::paBufferTooSmall => PaError::PaBufferTooSmall,
-      raw_portaudio::paNullCallback => PaError::PaNullCallback,
-      raw_portaudio::paBadStreamPtr => PaError::PaBadStreamPtr,
-      raw_portaudio::paTimedOut => PaError::PaTimedOut,
-      raw_portaudio::paInternalError => PaError::PaInternalError,
-      raw_portaudio::paDeviceUnavailable => PaError::PaDeviceUnavailable,
-      raw_portaudio::paIncompatibleHostApiSpecificStreamInfo => PaError::PaIncompatibleHostApiSpecificStreamInfo,
-      raw_portaudio::paStreamIsStopped => PaError::PaStreamIsStopped,
-      raw_portaudio::paStreamIsNotStopped => PaError::PaStreamIsNotStopped,
-      raw_portaudio::paInputOverflowed => PaError::PaInputOverflowed,
-      raw_portaudio::paOutputUnderflowed => PaError::PaOutputUnderflowed,
-      raw_portaudio::paHostApiNotFound => PaError::PaHostApiNotFound,
-      raw_portaudio::paInvalidHostApi => PaError::PaInvalidHostApi,
-      raw_portaudio::paCanNotReadFromACallbackStream => PaError::PaCanNotReadFromACallbackStream,
-      raw_portaudio::paCanNotWriteToACallbackStream => PaError::PaCanNotWriteToACallbackStream,
-      raw_portaudio::paCanNotReadFromAnOutputOnlyStream => PaError::PaCanNotReadFromAnOutputOnlyStream,
-      raw_portaudio::paCanNotWriteToAnInputOnlyStream => PaError::PaCanNotWriteToAnInputOnlyStream,
-      raw_portaudio::paIncompatibleStreamHostApi => PaError::PaIncompatibleStreamHostApi,
-      raw_portaudio::paBadBufferPtr => PaError::PaBadBufferPtr,
-
-
-//      raw_portaudio::PaErrorCode_paNoError => PaError::PaNoError,
-//      raw_portaudio::PaErrorCode_paNotInitialized => PaError::PaNotInitialized,
-//      raw_portaudio::PaErrorCode_paUnanticipatedHostError => PaError::PaUnanticipatedHostError,
-//      raw_portaudio::PaErrorCode_paInvalidChannelCount => PaError::PaInvalidChannelCount,
-//      raw_portaudio::PaErrorCode_paInvalidSampleRate => PaError::PaInvalidSampleRate,
-//      raw_portaudio::PaErrorCode_paInvalidDevice => PaError::PaInvalidDevice,
-//      raw_portaudio::PaErrorCode_paInvalidFlag => PaError::PaInvalidFlag,
-//      raw_portaudio::PaErrorCode_paSampleFormatNotSupported => PaError::PaSampleFormatNotSupported,
-//      raw_portaudio::PaErrorCode_paBadIODeviceCombination => PaError::PaBadIODeviceCombination,
-//      raw_portaudio::PaErrorCode_paInsufficientMemory => PaError::PaInsufficientMemory,
-//      raw_portaudio::PaErrorCode_paBufferTooBig => PaError::PaBufferTooBig,
-//      raw_portaudio::PaErrorCode_paBufferTooSmall => PaError::PaBufferTooSmall,
-//      raw_portaudio::PaErrorCode_paNullCallback => PaError::PaNullCallback,
-//      raw_portaudio::PaErrorCode_paBadStreamPtr => PaError::PaBadStreamPtr,
-//      raw_portaudio::PaErrorCode_paTimedOut => PaError::PaTimedOut,
-//      raw_portaudio::PaErrorCode_paInternalError => PaError::PaInternalError,
-//      raw_portaudio::PaErrorCode_paDeviceUnavailable => PaError::PaDeviceUnavailable,
-//      raw_portaudio::PaErrorCode_paIncompatibleHostApiSpecificStreamInfo => PaError::PaIncompatibleHostApiSpecificStreamInfo,
-//      raw_portaudio::PaErrorCode_paStreamIsStopped => PaError::PaStreamIsStopped,
-//      raw_portaudio::PaErrorCode_paStreamIsNotStopped => PaError::PaStreamIsNotStopped,
-//      raw_portaudio::PaErrorCode_paInputOverflowed => PaError::PaInputOverflowed,
-//      raw_portaudio::PaErrorCode_paOutputUnderflowed => PaError::PaOutputUnderflowed,
-//      raw_portaudio::PaErrorCode_paHostApiNotFound => PaError::PaHostApiNotFound,
-//      raw_portaudio::PaErrorCode_paInvalidHostApi => PaError::PaInvalidHostApi,
-//      raw_portaudio::PaErrorCode_paCanNotReadFromACallbackStream => PaError::PaCanNotReadFromACallbackStream,
-//      raw_portaudio::PaErrorCode_paCanNotWriteToACallbackStream => PaError::PaCanNotWriteToACallbackStream,
-//      raw_portaudio::PaErrorCode_paCanNotReadFromAnOutputOnlyStream => PaError::PaCanNotReadFromAnOutputOnlyStream,
-//      raw_portaudio::PaErrorCode_paCanNotWriteToAnInputOnlyStream => PaError::PaCanNotWriteToAnInputOnlyStream,
-//      raw_portaudio::PaErrorCode_paIncompatibleStreamHostApi => PaError::PaIncompatibleStreamHostApi,
-//      raw_portaudio::PaErrorCode_paBadBufferPtr => PaError::PaBadBufferPtr,
-
-      _ => PaError::UnknownError,
+      raw_portaudio::paNoError => ErrorCode::PaNoError,
+      raw_portaudio::paNotInitialized => ErrorCode::PaNotInitialized,
+      raw_portaudio::paUnanticipatedHostError => ErrorCode::PaUnanticipatedHostError,
+      raw_portaudio::paInvalidChannelCount => ErrorCode::PaInvalidChannelCount,
+      raw_portaudio::paInvalidSampleRate => ErrorCode::PaInvalidSampleRate,
+      raw_portaudio::paInvalidDevice => ErrorCode::PaInvalidDevice,
+      raw_portaudio::paInvalidFlag => ErrorCode::PaInvalidFlag,
+      raw_portaudio::paSampleFormatNotSupported => ErrorCode::PaSampleFormatNotSupported,
+      raw_portaudio::paBadIODeviceCombination => ErrorCode::PaBadIODeviceCombination,
+      raw_portaudio::paInsufficientMemory => ErrorCode::PaInsufficientMemory,
+      raw_portaudio::paBufferTooBig => ErrorCode::PaBufferTooBig,
+      raw_portaudio::paBufferTooSmall => ErrorCode::PaBufferTooSmall,
+      raw_portaudio::paNullCallback => ErrorCode::PaNullCallback,
+      raw_portaudio::paBadStreamPtr => ErrorCode::PaBadStreamPtr,
+      raw_portaudio::paTimedOut => ErrorCode::PaTimedOut,
+      raw_portaudio::paInternalError => ErrorCode::PaInternalError,
+      raw_portaudio::paDeviceUnavailable => ErrorCode::PaDeviceUnavailable,
+      raw_portaudio::paIncompatibleHostApiSpecificStreamInfo => ErrorCode::PaIncompatibleHostApiSpecificStreamInfo,
+      raw_portaudio::paStreamIsStopped => ErrorCode::PaStreamIsStopped,
+      raw_portaudio::paStreamIsNotStopped => ErrorCode::PaStreamIsNotStopped,
+      raw_portaudio::paInputOverflowed => ErrorCode::PaInputOverflowed,
+      raw_portaudio::paOutputUnderflowed => ErrorCode::PaOutputUnderflowed,
+      raw_portaudio::paHostApiNotFound => ErrorCode::PaHostApiNotFound,
+      raw_portaudio::paInvalidHostApi => ErrorCode::PaInvalidHostApi,
+      raw_portaudio::paCanNotReadFromACallbackStream => ErrorCode::PaCanNotReadFromACallbackStream,
+      raw_portaudio::paCanNotWriteToACallbackStream => ErrorCode::PaCanNotWriteToACallbackStream,
+      raw_portaudio::paCanNotReadFromAnOutputOnlyStream => ErrorCode::PaCanNotReadFromAnOutputOnlyStream,
+      raw_portaudio::paCanNotWriteToAnInputOnlyStream => ErrorCode::PaCanNotWriteToAnInputOnlyStream,
+      raw_portaudio::paIncompatibleStreamHostApi => ErrorCode::PaIncompatibleStreamHostApi,
+      raw_portaudio::paBadBufferPtr => ErrorCode::PaBadBufferPtr,
+
+
+//      raw_portaudio::PaErrorCode_paNoError => ErrorCode::PaNoError,
+//      raw_portaudio::PaErrorCode_paNotInitialized => ErrorCode::PaNotInitialized,
+//      raw_portaudio::PaErrorCode_paUnanticipatedHostError => ErrorCode::PaUnanticipatedHostError,
+//      raw_portaudio::PaErrorCode_paInvalidChannelCount => ErrorCode::PaInvalidChannelCount,
+//      raw_portaudio::PaErrorCode_paInvalidSampleRate => ErrorCode::PaInvalidSampleRate,
+//      raw_portaudio::PaErrorCode_paInvalidDevice => ErrorCode::PaInvalidDevice,
+//      raw_portaudio::PaErrorCode_paInvalidFlag => ErrorCode::PaInvalidFlag,
+//      raw_portaudio::PaErrorCode_paSampleFormatNotSupported => ErrorCode::PaSampleFormatNotSupported,
+//      raw_portaudio::PaErrorCode_paBadIODeviceCombination => ErrorCode::PaBadIODeviceCombination,
+//      raw_portaudio::PaErrorCode_paInsufficientMemory => ErrorCode::PaInsufficientMemory,
+//      raw_portaudio::PaErrorCode_paBufferTooBig => ErrorCode::PaBufferTooBig,
+//      raw_portaudio::PaErrorCode_paBufferTooSmall => ErrorCode::PaBufferTooSmall,
+//      raw_portaudio::PaErrorCode_paNullCallback => ErrorCode::PaNullCallback,
+//      raw_portaudio::PaErrorCode_paBadStreamPtr => ErrorCode::PaBadStreamPtr,
+//      raw_portaudio::PaErrorCode_paTimedOut => ErrorCode::PaTimedOut,
+//      raw_portaudio::PaErrorCode_paInternalError => ErrorCode::PaInternalError,
+//      raw_portaudio::PaErrorCode_paDeviceUnavailable => ErrorCode::PaDeviceUnavailable,
+//      raw_portaudio::PaErrorCode_paIncompatibleHostApiSpecificStreamInfo => ErrorCode::PaIncompatibleHostApiSpecificStreamInfo,
+//      raw_portaudio::PaErrorCode_paStreamIsStopped => ErrorCode::PaStreamIsStopped,
+//      raw_portaudio::PaErrorCode_paStreamIsNotStopped => ErrorCode::PaStreamIsNotStopped,
+//      raw_portaudio::PaErrorCode_paInputOverflowed => ErrorCode::PaInputOverflowed,
+//      raw_portaudio::PaErrorCode_paOutputUnderflowed => ErrorCode::PaOutputUnderflowed,
+//      raw_portaudio::PaErrorCode_paHostApiNotFound => ErrorCode::PaHostApiNotFound,
+//      raw_portaudio::PaErrorCode_paInvalidHostApi => ErrorCode::PaInvalidHostApi,
+//      raw_portaudio::PaErrorCode_paCanNotReadFromACallbackStream => ErrorCode::PaCanNotReadFromACallbackStream,
+//      raw_portaudio::PaErrorCode_paCanNotWriteToACallbackStream => ErrorCode::PaCanNotWriteToACallbackStream,
+//      raw_portaudio::PaErrorCode_paCanNotReadFromAnOutputOnlyStream => ErrorCode::PaCanNotReadFromAnOutputOnlyStream,
+//      raw_portaudio::PaErrorCode_paCanNotWriteToAnInputOnlyStream => ErrorCode::PaCanNotWriteToAnInputOnlyStream,
+//      raw_portaudio::PaErrorCode_paIncompatibleStreamHostApi => ErrorCode::PaIncompatibleStreamHostApi,
+//      raw_portaudio::PaErrorCode_paBadBufferPtr => ErrorCode::PaBadBufferPtr,
+
+      _ => ErrorCode::UnknownError,
     }
   }
 }
 
-impl fmt::Display for PaError {
+impl fmt::Display for ErrorCode {
   fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-//    match *self {
-//      PaError::UnknownError => write!(f, "Unknown Error"),
-//      other => {
-//        let message_c = unsafe { raw_portaudio::PaErrorCode_pa_GetErrorText(other as i32) };
-//        let message_s = String::from_utf8_lossy(unsafe { CStr::from_ptr(message_c).to_bytes() });
-//        f.write_str(&*message_s)
-//      }
-//    }
     let msg = crate::rportaudio::error_text(*self);
     f.write_str(&msg[..])
   }
 }
 
+
+/// An error of PortAudio or of the bindings
+///
+/// Besides the error code it keeps the raw number PortAudio returned, the operation that failed
+/// and, for UnanticipatedHostError, what the host API reported. Compare with an ErrorCode to test
+/// for a specific error.
+#[derive(Clone, PartialEq)]
+pub struct PaError {
+  code: ErrorCode,
+  raw: i32,
+  host_error: Option<PaHostErrorInfo>,
+  operation: Option<&'static str>,
+}
+
+impl PaError {
+  /// Get the error corresponding to the given PortAudio error code
+  pub fn from_i32(num: i32) -> PaError {
+    PaError { code: ErrorCode::from_i32(num), raw: num, host_error: None, operation: None }
+  }
+
+  /// The error code, UnknownError when PortAudio returned a code these bindings do not know
+  pub fn code(&self) -> ErrorCode {
+    self.code
+  }
+
+  /// The number PortAudio returned, or the discriminant of errors raised by the bindings
+  ///
+  /// UnknownError and IncompatibleVersion are not PortAudio errors, they are numbered from
+  /// -20000 down, where PortAudio has no errors.
+  pub fn raw_code(&self) -> i32 {
+    self.raw
+  }
+
+  /// What the host API reported, for UnanticipatedHostError
+  pub fn host_error(&self) -> Option<&PaHostErrorInfo> {
+    self.host_error.as_ref()
+  }
+
+  /// Name of the operation that failed, such as "open_stream" or "write"
+  pub fn operation(&self) -> Option<&'static str> {
+    self.operation
+  }
+
+  /// Record the failed operation, and fetch the host error details when they apply
+  pub(crate) fn context(mut self, pa: &crate::PortAudio, operation: &'static str) -> PaError {
+    if self.operation.is_none() {
+      self.operation = Some(operation);
+    }
+    if self.code == ErrorCode::PaUnanticipatedHostError && self.host_error.is_none() {
+      self.host_error = pa.backend().last_host_error();
+    }
+    self
+  }
+}
+
+impl From<ErrorCode> for PaError {
+  fn from(code: ErrorCode) -> PaError {
+    PaError { code, raw: code as i32, host_error: None, operation: None }
+  }
+}
+
+impl PartialEq<ErrorCode> for PaError {
+  fn eq(&self, other: &ErrorCode) -> bool {
+    self.code == *other
+  }
+}
+
+impl fmt::Display for PaError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+    if let Some(operation) = self.operation {
+      write!(f, "{}: ", operation)?;
+    }
+    match self.code {
+      ErrorCode::UnknownError => write!(f, "Unknown Error ({})", self.raw)?,
+      code => write!(f, "{}", code)?,
+    }
+    if let Some(ref host_error) = self.host_error {
+      write!(f, " ({:?} error {}: {})", host_error.api_type, host_error.code, host_error.text)?;
+    }
+    Ok(())
+  }
+}
+
 impl fmt::Debug for PaError {
   fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
     ::std::fmt::Display::fmt(self, fmt)
   }
 }
 
+impl error::Error for PaError {}
+
+/// Errors of looking up a device by DeviceId or name
+#[derive(PartialEq, Clone)]
+pub enum DeviceLookupError {
+  /// No device matches the id or name
+  NotFound(String),
+
+  /// Several devices match the name, their indices are given
+  Ambiguous(String, Vec<u32>),
+
+  /// The regular expression does not compile
+  InvalidPattern(String),
+
+  /// The string is not a serialized DeviceId
+  InvalidId(String),
+}
+
+impl fmt::Display for DeviceLookupError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+    match *self {
+      DeviceLookupError::NotFound(ref what) => write!(f, "No device found for {:?}", what),
+      DeviceLookupError::Ambiguous(ref what, ref indices) => write!(f, "Devices {:?} all match {:?}", indices, what),
+      DeviceLookupError::InvalidPattern(ref msg) => write!(f, "Invalid device name pattern: {}", msg),
+      DeviceLookupError::InvalidId(ref id) => write!(f, "Invalid device id {:?}", id),
+    }
+  }
+}
+
+impl fmt::Debug for DeviceLookupError {
+  fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+    ::std::fmt::Display::fmt(self, fmt)
+  }
+}
+
+impl error::Error for DeviceLookupError {}
+
+/// Errors of opening a stream through a StreamBuilder
+#[derive(PartialEq, Clone)]
+pub enum StreamBuilderError {
+  /// Neither an input nor an output was configured
+  NoDirection,
+
+  /// The device index does not refer to a device
+  InvalidDevice(u32),
+
+  /// The input channel count is zero or exceeds the maximum of the device
+  InvalidInputChannels { device: u32, requested: u32, max: u32 },
+
+  /// The output channel count is zero or exceeds the maximum of the device
+  InvalidOutputChannels { device: u32, requested: u32, max: u32 },
+
+  /// The device refuses the configuration
+  Unsupported(PaError),
+
+  /// Opening the stream failed
+  Open(PaError),
+
+  /// The preferences of a FormatNegotiator leave no configuration to try
+  NoConfiguration,
+
+  /// A bridged stream was asked for ring buffers without room for a frame
+  NoBufferFrames,
+
+  /// The ring buffers of a bridged stream could not be created
+  RingBuffer(RingBufferError),
+}
+
+impl fmt::Display for StreamBuilderError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+    match *self {
+      StreamBuilderError::NoDirection => write!(f, "The stream has neither an input nor an output"),
+      StreamBuilderError::InvalidDevice(device) => write!(f, "No device with index {}", device),
+      StreamBuilderError::InvalidInputChannels { device, requested, max } =>
+        write!(f, "Device {} has 1 to {} input channels, {} requested", device, max, requested),
+      StreamBuilderError::InvalidOutputChannels { device, requested, max } =>
+        write!(f, "Device {} has 1 to {} output channels, {} requested", device, max, requested),
+      StreamBuilderError::Unsupported(ref e) => write!(f, "Unsupported stream configuration: {}", e),
+      StreamBuilderError::Open(ref e) => write!(f, "Failed to open the stream: {}", e),
+      StreamBuilderError::NoConfiguration => write!(f, "No stream configuration to try"),
+      StreamBuilderError::NoBufferFrames => write!(f, "The ring buffers need room for at least one frame"),
+      StreamBuilderError::RingBuffer(ref e) => write!(f, "Failed to create the ring buffers: {}", e),
+    }
+  }
+}
+
+impl fmt::Debug for StreamBuilderError {
+  fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+    ::std::fmt::Display::fmt(self, fmt)
+  }
+}
+
+impl error::Error for StreamBuilderError {
+  fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+    match *self {
+      StreamBuilderError::Unsupported(ref e) | StreamBuilderError::Open(ref e) => Some(e),
+      StreamBuilderError::RingBuffer(ref e) => Some(e),
+      _ => None,
+    }
+  }
+}
+
+
+/// Errors of the input and output handles of a bridged stream
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum BridgeError {
+  /// No input is available yet
+  Empty,
+
+  /// There is no room for output yet
+  Full,
+
+  /// Nothing could be transferred before the timeout
+  Timeout,
+
+  /// The stream is closed
+  Disconnected,
+}
+
+impl fmt::Display for BridgeError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+    match *self {
+      BridgeError::Empty => write!(f, "No input available"),
+      BridgeError::Full => write!(f, "No room for output"),
+      BridgeError::Timeout => write!(f, "Timed out"),
+      BridgeError::Disconnected => write!(f, "The stream is closed"),
+    }
+  }
+}
+
+impl error::Error for BridgeError {}
+
+
 /// A result type wrapping PaError.
 ///
 /// The original NoError is mapped to Ok(()) and other values mapped to Err(x)
 pub type PaResult = Result<(), PaError>;
 
 
+/// Errors of creating a ring buffer
 #[derive(PartialEq, Copy, Clone)]
-#[allow(missing_docs)]
 pub enum RingBufferError {
+  /// The memory for the buffer could not be allocated
   MemoryAllocateFail(&'static str),
+
+  /// The capacity is not a power of two
   NotPower2(&'static str),
 }
 
@@ -192,19 +409,30 @@ impl fmt::Debug for RingBufferError {
   }
 }
 
-impl error::Error for RingBufferError {
-  fn description(&self) -> &str {
-    match *self {
-      RingBufferError::NotPower2(msg) => msg,
-      RingBufferError::MemoryAllocateFail(msg) => msg,
-//      _ => "UNKNOW ERROR"
-    }
-  }
+impl error::Error for RingBufferError {}
 
-  fn cause(&self) -> Option<&error::Error> {
-    match *self {
-      _ => Some(self),
-    }
+
+
+#[cfg(test)]
+mod test {
+  use std::error::Error;
+
+  use super::{ErrorCode, PaError};
+
+  #[test]
+  fn test_error() {
+    let error = PaError::from_i32(-9998);
+    assert_eq!(error, ErrorCode::PaInvalidChannelCount);
+    assert_eq!(error.raw_code(), -9998);
+    assert!(error.operation().is_none());
+
+    let unknown = PaError::from_i32(-12345);
+    assert_eq!(unknown.code(), ErrorCode::UnknownError);
+    assert_eq!(unknown.raw_code(), -12345);
+    assert_eq!(unknown.to_string(), "Unknown Error (-12345)");
+    assert_eq!(PaError::from(ErrorCode::IncompatibleVersion).raw_code(), -20001);
+
+    let boxed: Box<dyn Error> = Box::new(PaError::from(ErrorCode::UnknownError));
+    assert!(boxed.source().is_none());
   }
 }
-
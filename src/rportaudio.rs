@@ -1,11 +1,19 @@
-use std::{mem, ptr};
+use std::any::Any;
+use std::ptr;
 use std::ffi::CStr;
-use std::time::Duration;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use libc::{c_ulong, c_void};
 
 use crate::{kit, raw_portaudio};
-use crate::rpa_error::{PaError, PaResult};
+use crate::backend::{RawStreamCallback, RawStreamFinishedCallback, RawStreamParameters, NON_INTERLEAVED};
+use crate::frames::{Frames, FramesMut};
+use crate::planar::PlanarStream;
+use crate::rpa_error::{ErrorCode, PaError, PaResult};
+use crate::stats::StreamStatistics;
 use crate::types::*;
 
 /// PortAudio version
@@ -21,30 +29,29 @@ pub fn version_text() -> String {
   version_s.into_owned()
 }
 
-/// Initialize the PortAudio API
+/// Version information of the linked PortAudio library
 ///
-/// Each successful call must be matched by a call to terminate
-pub fn initialize() -> PaResult {
-  kit::to_pa_result(unsafe { raw_portaudio::Pa_Initialize() })
-}
-
-/// Terminate the PortAudio API
-///
-/// Call this function exactly once for each successful call to initialize
-pub fn terminate() -> PaResult {
-  kit::to_pa_result(unsafe { raw_portaudio::Pa_Terminate() })
+/// Libraries before 19.5 have no `Pa_GetVersionInfo`, their info is made of the version number
+/// and text, without a control revision.
+pub fn version_info() -> VersionInfo {
+  let number = VersionInfo::from_number(version());
+  if (number.major, number.minor) < (19, 5) {
+    return VersionInfo { text: version_text(), ..number };
+  }
+  unsafe {
+    let pa_version_info = raw_portaudio::Pa_GetVersionInfo();
+    VersionInfo::from_raw(&*pa_version_info)
+  }
 }
 
-//pub fn version_info() -> PaVersionInfo {
-//  unsafe {
-//    let pa_version_info = raw_portaudio::Pa_GetVersionInfo();
-//    PaVersionInfo::from_raw(&*pa_version_info)
-//  }
-//}
-
-pub fn error_text(pa_error: PaError) -> String {
-  match pa_error {
-    PaError::UnknownError => "Unknown Error".to_string(),
+/// Human-readable text of an error code
+pub fn error_text(code: ErrorCode) -> String {
+  match code {
+    ErrorCode::UnknownError => "Unknown Error".to_string(),
+    ErrorCode::IncompatibleVersion => {
+      let (major, minor, sub_minor) = HEADER_VERSION;
+      format!("Linked {} is incompatible with the bindings for PortAudio {}.{}.{}", version_text(), major, minor, sub_minor)
+    }
     other => {
       let message_c = unsafe { raw_portaudio::Pa_GetErrorText(other as i32) };
       let message_s = String::from_utf8_lossy(unsafe { CStr::from_ptr(message_c).to_bytes() });
@@ -55,39 +62,25 @@ pub fn error_text(pa_error: PaError) -> String {
 
 
 /// Get the number of host API's available
-pub fn hostapi_count() -> Result<u32, PaError> {
-  match unsafe { raw_portaudio::Pa_GetHostApiCount() } {
-    n if n >= 0 => Ok(n as HostApiIndex),
-    m => kit::to_pa_result(m).map(|_| 0),
-  }
+pub fn hostapi_count(pa: &PortAudio) -> Result<u32, PaError> {
+  pa.backend().hostapi_count().map_err(|e| e.context(pa, "hostapi_count"))
 }
 
 /// Get the default Host API
-pub fn default_hostapi() -> Result<HostApiIndex, PaError> {
-  match unsafe { raw_portaudio::Pa_GetDefaultHostApi() } {
-    n if n >= 0 => Ok(n as HostApiIndex),
-    m => kit::to_pa_result(m).map(|_| 0),
-  }
+pub fn default_hostapi(pa: &PortAudio) -> Result<HostApiIndex, PaError> {
+  pa.backend().default_hostapi().map_err(|e| e.context(pa, "default_hostapi"))
 }
 
 
 /// Get information about a specific Host API
 ///
 /// Returns None when an invalid index is given
-pub fn hostapi_info(index: HostApiIndex) -> Option<PaHostApiInfo> {
-  unsafe {
-    match raw_portaudio::Pa_GetHostApiInfo(index as i32) {
-      p if p.is_null() => None,
-      p => Some(PaHostApiInfo::from_raw(&*p)),
-    }
-  }
+pub fn hostapi_info(pa: &PortAudio, index: HostApiIndex) -> Option<PaHostApiInfo> {
+  pa.backend().hostapi_info(index)
 }
 
-pub fn hostapi_type_id_to_hostapi_index(type_id: u32) -> Result<u32, PaError> {
-  match unsafe { raw_portaudio::Pa_HostApiTypeIdToHostApiIndex(type_id) } {
-    n if n >= 0 => Ok(n as u32),
-    m => kit::to_pa_result(m).map(|_| 0),
-  }
+pub fn hostapi_type_id_to_hostapi_index(pa: &PortAudio, type_id: u32) -> Result<u32, PaError> {
+  pa.backend().hostapi_type_id_to_hostapi_index(type_id).map_err(|e| e.context(pa, "hostapi_type_id_to_hostapi_index"))
 }
 
 
@@ -95,57 +88,41 @@ pub fn hostapi_type_id_to_hostapi_index(type_id: u32) -> Result<u32, PaError> {
 ///
 /// The values in this structure will only be valid if a PortAudio function has previously returned
 /// the UnanticipatedHostError error code.
-pub fn last_host_error() -> Option<PaHostErrorInfo> {
-  unsafe {
-    match raw_portaudio::Pa_GetLastHostErrorInfo() {
-      p if p.is_null() => None,
-      p => Some(PaHostErrorInfo::from_raw(&*p)),
-    }
-  }
+pub fn last_host_error(pa: &PortAudio) -> Option<PaHostErrorInfo> {
+  pa.backend().last_host_error()
 }
 
 
 /// Retrieve the number of available devices.
-pub fn device_count() -> Result<u32, PaError> {
-  match unsafe { raw_portaudio::Pa_GetDeviceCount() } {
-    n if n >= 0 => Ok(n as u32),
-    m => kit::to_pa_result(m).map(|_| 0),
-  }
+pub fn device_count(pa: &PortAudio) -> Result<u32, PaError> {
+  pa.backend().device_count().map_err(|e| e.context(pa, "device_count"))
 }
 
 
 /// Retrieve the index of the default input device
 ///
 /// Will return None when none are available.
-pub fn default_input_device() -> Option<DeviceIndex> {
-  match unsafe { raw_portaudio::Pa_GetDefaultInputDevice() } {
-    n if n >= 0 => Some(n as u32),
-    _ => None,
-  }
+pub fn default_input_device(pa: &PortAudio) -> Option<DeviceIndex> {
+  pa.backend().default_input_device()
 }
 
 
 /// Retrieve the index of the default output device
 ///
 /// Will return None when none are available.
-pub fn default_output_device() -> Option<DeviceIndex> {
-  match unsafe { raw_portaudio::Pa_GetDefaultOutputDevice() } {
-    n if n >= 0 => Some(n as u32),
-    _ => None,
-  }
+pub fn default_output_device(pa: &PortAudio) -> Option<DeviceIndex> {
+  pa.backend().default_output_device()
 }
 
 
 /// Get info about a particular device
 ///
 /// Returns None when the index is out of range.
-pub fn device_info(index: DeviceIndex) -> Option<PaDeviceInfo> {
-  unsafe {
-    match raw_portaudio::Pa_GetDeviceInfo(index as i32) {
-      p if p.is_null() => None,
-      p => Some(PaDeviceInfo::from_raw(&*p)),
-    }
-  }
+pub fn device_info(pa: &PortAudio, index: DeviceIndex) -> Option<PaDeviceInfo> {
+  let mut info = pa.backend().device_info(index)?;
+  info.is_default_input = pa.backend().default_input_device() == Some(index);
+  info.is_default_output = pa.backend().default_output_device() == Some(index);
+  Some(info)
 }
 
 
@@ -154,122 +131,193 @@ pub fn device_info(index: DeviceIndex) -> Option<PaDeviceInfo> {
 /// Returns Err(InvalidHostApi) when the host_api is out of range, and Err(InvalidDevice) when
 /// host_api_device_index is out of range.
 ///
-/// ```
+/// ```no_run
 /// // We retrieve the index of device 3 of api 1
-/// let device_index = match rportaudio::hostapi_device_index_to_device_index(1, 3) {
+/// let pa = rportaudio::PortAudio::new().unwrap();
+/// let device_index = match rportaudio::device::hostapi_device_index_to_device_index(&pa, 1, 3) {
 ///   Ok(n) => n,
 ///   Err(e) => { println!("Error: {:?}", e); return; }
 /// };
 /// ```
-pub fn hostapi_device_index_to_device_index(hostapi: u32, hostapi_device_index: u32) -> Result<u32, PaError> {
-  match unsafe { raw_portaudio::Pa_HostApiDeviceIndexToDeviceIndex(hostapi as i32, hostapi_device_index as i32) } {
-    n if n >= 0 => Ok(n as u32),
-    m => kit::to_pa_result(m).map(|_| 0),
-  }
+pub fn hostapi_device_index_to_device_index(pa: &PortAudio, hostapi: u32, hostapi_device_index: u32) -> Result<u32, PaError> {
+  pa.backend().hostapi_device_index_to_device_index(hostapi, hostapi_device_index).map_err(|e| e.context(pa, "hostapi_device_index_to_device_index"))
 }
 
 
 /// Returns Ok when the StreamParameters are supported. This ignores the latency field.
-pub fn is_format_supported<I: SampleType, O: SampleType>(input: Option<PaStreamParameters<I>>, output: Option<PaStreamParameters<O>>, sample_rate: f64) -> PaResult {
-  let input_obj;
-  let input_ptr;
-  let output_obj;
-  let output_ptr;
-  match input {
-    Some(sp) => {
-      input_obj = sp.to_raw();
-      input_ptr = &input_obj as *const _
-    }
-    None => input_ptr = ptr::null(),
+pub fn is_format_supported<I: SampleType, O: SampleType>(pa: &PortAudio, input: Option<PaStreamParameters<I>>, output: Option<PaStreamParameters<O>>, sample_rate: f64) -> PaResult {
+  let input = input.map(|sp| sp.to_raw());
+  let output = output.map(|sp| sp.to_raw());
+  is_raw_format_supported(pa, input.as_ref(), output.as_ref(), sample_rate)
+}
+
+pub(crate) fn is_raw_format_supported(pa: &PortAudio, input: Option<&RawStreamParameters>, output: Option<&RawStreamParameters>, sample_rate: f64) -> PaResult {
+  pa.backend().is_format_supported(input, output, sample_rate).map_err(|e| e.context(pa, "is_format_supported"))
+}
+
+
+/// Probe what a device supports, or take it from the cache of the session
+pub fn device_capabilities(pa: &PortAudio, index: DeviceIndex) -> Result<DeviceCapabilities, PaError> {
+  let mut cache = pa.session.capabilities.lock().unwrap_or_else(|e| e.into_inner());
+  if let Some(capabilities) = cache.get(&index) {
+    return Ok(capabilities.clone());
+  }
+
+  let info = device_info(pa, index).ok_or(ErrorCode::PaInvalidDevice)?;
+  let capabilities = DeviceCapabilities {
+    input: probe_direction(pa, index, &info, info.max_input_channels, info.default_low_input_latency, true),
+    output: probe_direction(pa, index, &info, info.max_output_channels, info.default_low_output_latency, false),
   };
-  match output {
-    Some(sp) => {
-      output_obj = sp.to_raw();
-      output_ptr = &output_obj as *const _
+  cache.insert(index, capabilities.clone());
+  Ok(capabilities)
+}
+
+fn probe_direction(pa: &PortAudio,
+                   device: DeviceIndex,
+                   info: &PaDeviceInfo,
+                   max_channels: u32,
+                   suggested_latency: Duration,
+                   input: bool) -> Option<DirectionCapabilities> {
+  if max_channels == 0 {
+    return None;
+  }
+  let supported = |channel_count: u32, format: SampleFormat, sample_rate: f64| {
+    let parameters = RawStreamParameters { device, channel_count, sample_format: format.sample_format(), suggested_latency };
+    match input {
+      true => pa.backend().is_format_supported(Some(&parameters), None, sample_rate).is_ok(),
+      false => pa.backend().is_format_supported(None, Some(&parameters), sample_rate).is_ok(),
     }
-    None => output_ptr = ptr::null(),
   };
 
-  kit::to_pa_result(unsafe { raw_portaudio::Pa_IsFormatSupported(input_ptr, output_ptr, sample_rate) })
+  let rate = info.default_sample_rate;
+  let channels = max_channels.min(2);
+  let format = SampleFormat::ALL.iter().cloned().find(|&f| supported(channels, f, rate)).unwrap_or(SampleFormat::F32);
+
+  Some(DirectionCapabilities {
+    sample_rates: STANDARD_SAMPLE_RATES.iter().cloned().filter(|&r| supported(channels, format, r)).collect(),
+    sample_formats: SampleFormat::ALL.iter().cloned().filter(|&f| supported(channels, f, rate)).collect(),
+    channel_counts: (1..=max_channels).filter(|&c| supported(c, format, rate)).collect(),
+  })
 }
 
 
 pub fn open_stream<'a, I, O>(
+  pa: &'a PortAudio,
   input: Option<PaStreamParameters<I>>,
   output: Option<PaStreamParameters<O>>,
   sample_rate: f64,
   frames_per_buffer: u64,
   flags: PaStreamFlags,
   callback: Option<Box<StreamCallback<'a, I, O>>>,
+) -> Result<Stream<'a, I, O>, PaError>
+  where I: SampleType, O: SampleType {
+  let input = input.map(|sp| sp.to_raw());
+  let output = output.map(|sp| sp.to_raw());
+  open_raw_stream(pa, input, output, sample_rate, frames_per_buffer, flags, callback)
+}
+
+/// Open a stream from parameters which already carry the sample format of I and O
+pub(crate) fn open_raw_stream<'a, I, O>(
+  pa: &'a PortAudio,
+  input: Option<RawStreamParameters>,
+  output: Option<RawStreamParameters>,
+  sample_rate: f64,
+  frames_per_buffer: u64,
+  flags: PaStreamFlags,
+  callback: Option<Box<StreamCallback<'a, I, O>>>,
 ) -> Result<Stream<'a, I, O>, PaError>
   where I: SampleType, O: SampleType {
   let callback_pointer = match callback {
-    Some(_) => Some(stream_callback::<I, O> as StreamCallbackType),
+    Some(_) => Some(stream_callback::<I, O> as RawStreamCallback),
     None => None,
   };
 
-  let input_cnt;
-  let input_obj;
-  let input_ptr;
-  let output_cnt;
-  let output_obj;
-  let output_ptr;
-  match input {
-    Some(sp) => {
-      input_cnt = sp.channel_count;
-      input_obj = sp.to_raw();
-      input_ptr = &input_obj as *const _
-    }
-    None => {
-      input_cnt = 0;
-      input_ptr = ptr::null()
-    }
-  };
-  match output {
-    Some(sp) => {
-      output_cnt = sp.channel_count;
-      output_obj = sp.to_raw();
-      output_ptr = &output_obj as *const _
-    }
-    None => {
-      output_cnt = 0;
-      output_ptr = ptr::null()
-    }
+  let input_cnt = input.map_or(0, |sp| sp.channel_count);
+  let output_cnt = output.map_or(0, |sp| sp.channel_count);
+
+  let user_data = Box::new(StreamUserData::new(input_cnt, output_cnt, callback));
+  open_user_data_stream(pa, input, output, sample_rate, frames_per_buffer, flags, callback_pointer, user_data)
+}
+
+/// Open a non-interleaved stream, whose callback gets one slice per channel
+pub fn open_planar_stream<'a, I, O>(
+  pa: &'a PortAudio,
+  input: Option<PaStreamParameters<I>>,
+  output: Option<PaStreamParameters<O>>,
+  sample_rate: f64,
+  frames_per_buffer: u64,
+  flags: PaStreamFlags,
+  callback: Option<Box<PlanarStreamCallback<'a, I, O>>>,
+) -> Result<PlanarStream<'a, I, O>, PaError>
+  where I: SampleType, O: SampleType {
+  let planar = |mut raw: RawStreamParameters| {
+    raw.sample_format |= NON_INTERLEAVED;
+    raw
   };
+  let input = input.map(|sp| planar(sp.to_raw()));
+  let output = output.map(|sp| planar(sp.to_raw()));
+  let callback_pointer = callback.as_ref().map(|_| planar_stream_callback::<I, O> as RawStreamCallback);
 
-  let mut user_data = Box::new(StreamUserData {
-    num_input: input_cnt,
-    num_output: output_cnt,
-    callback,
-    finished_callback: None,
-  });
+  let input_cnt = input.map_or(0, |sp| sp.channel_count);
+  let output_cnt = output.map_or(0, |sp| sp.channel_count);
+
+  let user_data = Box::new(StreamUserData::new_planar(input_cnt, output_cnt, callback));
+  let stream = open_user_data_stream(pa, input, output, sample_rate, frames_per_buffer, flags, callback_pointer, user_data)?;
+  Ok(PlanarStream { stream })
+}
 
-  let mut pa_stream = ::std::ptr::null_mut();
-  let pointer_for_callback: *mut c_void = &mut *user_data as *mut StreamUserData<I, O> as *mut c_void;
-
-  let result = unsafe {
-    raw_portaudio::Pa_OpenStream(&mut pa_stream,
-                                 input_ptr,
-                                 output_ptr,
-                                 sample_rate,
-                                 frames_per_buffer as c_ulong,
-                                 flags.bits() as c_ulong,
-                                 callback_pointer,
-                                 pointer_for_callback)
+#[allow(clippy::too_many_arguments)]
+fn open_user_data_stream<'a, I, O>(
+  pa: &'a PortAudio,
+  input: Option<RawStreamParameters>,
+  output: Option<RawStreamParameters>,
+  sample_rate: f64,
+  frames_per_buffer: u64,
+  flags: PaStreamFlags,
+  callback_pointer: Option<RawStreamCallback>,
+  user_data: Box<StreamUserData<'a, I, O>>,
+) -> Result<Stream<'a, I, O>, PaError>
+  where I: SampleType, O: SampleType {
+  if input.is_some() {
+    check_sample_type::<I>()?;
+  }
+  if output.is_some() {
+    check_sample_type::<O>()?;
+  }
+  let pointer_for_callback: *mut c_void = &*user_data as *const StreamUserData<I, O> as *mut c_void;
+
+  let pa_stream = unsafe {
+    pa.backend().open_stream(input.as_ref(),
+                             output.as_ref(),
+                             sample_rate,
+                             frames_per_buffer,
+                             flags,
+                             callback_pointer,
+                             pointer_for_callback)
+      .map_err(|e| e.context(pa, "open_stream"))?
   };
-  match kit::to_pa_result(result) {
-    Ok(()) => Ok(Stream {
-      pa_stream,
-      user_data,
-      inputs: input_cnt,
-      outputs: output_cnt,
-    }),
-    Err(v) => Err(v),
+  user_data.stats.set_sample_rate(sample_rate);
+  Ok(Stream {
+    portaudio: pa,
+    pa_stream,
+    inputs: user_data.num_input,
+    outputs: user_data.num_output,
+    user_data,
+    xruns: XrunCounters::default(),
+  })
+}
+
+
+/// Buffers of packed samples only line up with slices of T when T is exactly one sample large
+fn check_sample_type<T: SampleType>() -> PaResult {
+  match kit::sample_size(T::sample_format()) {
+    Some(size) if size == ::std::mem::size_of::<T>() => Ok(()),
+    _ => Err(ErrorCode::PaSampleFormatNotSupported.into()),
   }
 }
 
-
 pub fn open_default_stream<'a, T>(
+  pa: &'a PortAudio,
   num_input_channels: u32,
   num_output_channels: u32,
   sample_rate: f64,
@@ -278,39 +326,62 @@ pub fn open_default_stream<'a, T>(
 ) -> Result<Stream<'a, T, T>, PaError>
   where T: SampleType {
   let callback_pointer = match callback {
-    Some(_) => Some(stream_callback::<T, T> as StreamCallbackType),
+    Some(_) => Some(stream_callback::<T, T> as RawStreamCallback),
     None => None,
   };
-  let mut userdata = Box::new(StreamUserData {
-    num_input: num_input_channels,
-    num_output: num_output_channels,
-    callback,
-    finished_callback: None,
-  });
-  let mut pa_stream = ::std::ptr::null_mut();
-
-  let pointer_for_callback: *mut c_void = &mut *userdata as *mut StreamUserData<T, T> as *mut c_void;
-
-  let code = unsafe {
-    raw_portaudio::Pa_OpenDefaultStream(&mut pa_stream,
-                                        num_input_channels as i32,
-                                        num_output_channels as i32,
-                                        <T as SampleType>::sample_format() as c_ulong,
-                                        sample_rate,
-                                        frames_per_buffer as c_ulong,
-                                        callback_pointer,
-                                        pointer_for_callback)
+  let userdata = Box::new(StreamUserData::new(num_input_channels, num_output_channels, callback));
+  open_default_user_data_stream(pa, <T as SampleType>::sample_format(), sample_rate, frames_per_buffer, callback_pointer, userdata)
+}
+
+/// Open a non-interleaved stream using the default input and output devices
+pub fn open_planar_default_stream<'a, T>(
+  pa: &'a PortAudio,
+  num_input_channels: u32,
+  num_output_channels: u32,
+  sample_rate: f64,
+  frames_per_buffer: u64,
+  callback: Option<Box<PlanarStreamCallback<'a, T, T>>>,
+) -> Result<PlanarStream<'a, T, T>, PaError>
+  where T: SampleType {
+  let callback_pointer = callback.as_ref().map(|_| planar_stream_callback::<T, T> as RawStreamCallback);
+  let userdata = Box::new(StreamUserData::new_planar(num_input_channels, num_output_channels, callback));
+  let sample_format = <T as SampleType>::sample_format() | NON_INTERLEAVED;
+  let stream = open_default_user_data_stream(pa, sample_format, sample_rate, frames_per_buffer, callback_pointer, userdata)?;
+  Ok(PlanarStream { stream })
+}
+
+fn open_default_user_data_stream<'a, T>(
+  pa: &'a PortAudio,
+  sample_format: u64,
+  sample_rate: f64,
+  frames_per_buffer: u64,
+  callback_pointer: Option<RawStreamCallback>,
+  userdata: Box<StreamUserData<'a, T, T>>,
+) -> Result<Stream<'a, T, T>, PaError>
+  where T: SampleType {
+  check_sample_type::<T>()?;
+  let pointer_for_callback: *mut c_void = &*userdata as *const StreamUserData<T, T> as *mut c_void;
+
+  let pa_stream = unsafe {
+    pa.backend().open_default_stream(userdata.num_input,
+                                     userdata.num_output,
+                                     sample_format,
+                                     sample_rate,
+                                     frames_per_buffer,
+                                     callback_pointer,
+                                     pointer_for_callback)
+      .map_err(|e| e.context(pa, "open_default_stream"))?
   };
+  userdata.stats.set_sample_rate(sample_rate);
 
-  match kit::to_pa_result(code) {
-    Ok(()) => Ok(Stream {
-      pa_stream,
-      user_data: userdata,
-      inputs: num_input_channels,
-      outputs: num_output_channels,
-    }),
-    Err(v) => Err(v),
-  }
+  Ok(Stream {
+    portaudio: pa,
+    pa_stream,
+    inputs: userdata.num_input,
+    outputs: userdata.num_output,
+    user_data: userdata,
+    xruns: XrunCounters::default(),
+  })
 }
 
 
@@ -318,24 +389,43 @@ pub fn open_default_stream<'a, T>(
 pub fn set_stream_finished_callback<'a, I, O>(stream: &mut Stream<'a, I, O>, finished_callback: Box<StreamFinishedCallback<'a>>) -> PaResult
   where
     I: SampleType, O: SampleType {
-  stream.user_data.finished_callback = Some(finished_callback);
-  let callback_pointer = Some(stream_finished_callback::<I, O> as StreamFinishedCallbackType);
-  kit::to_pa_result(unsafe { raw_portaudio::Pa_SetStreamFinishedCallback(stream.pa_stream, callback_pointer) })
+  // Only a stopped stream leaves the finished callback alone, a running one may call it any time
+  if !is_stream_stopped(stream)? {
+    return Err(PaError::from(ErrorCode::PaStreamIsNotStopped).context(stream.portaudio, "set_finished_callback"));
+  }
+  let callback_pointer = Some(stream_finished_callback::<I, O> as RawStreamFinishedCallback);
+  unsafe { stream.portaudio.backend().set_stream_finished_callback(stream.pa_stream, callback_pointer) }
+    .map_err(|e| e.context(stream.portaudio, "set_finished_callback"))?;
+  unsafe { *stream.user_data.finished_callback.get() = Some(finished_callback); }
+  Ok(())
 }
 
 /// Remove any previously attached finish callback
 pub fn unset_stream_finished_callback<I, O>(stream: &mut Stream<I, O>) -> PaResult
   where
     I: SampleType, O: SampleType {
-  stream.user_data.finished_callback = None;
-  kit::to_pa_result(unsafe { raw_portaudio::Pa_SetStreamFinishedCallback(stream.pa_stream, None) })
+  let result = unsafe { stream.portaudio.backend().set_stream_finished_callback(stream.pa_stream, None) }
+    .map_err(|e| e.context(stream.portaudio, "unset_finished_callback"));
+  unsafe { *stream.user_data.finished_callback.get() = None; }
+  result
+}
+
+/// Take the payload of a panic caught in one of the callbacks of the stream
+pub fn take_stream_panic<I, O>(stream: &Stream<I, O>) -> Option<Box<dyn Any + Send>>
+  where
+    I: SampleType, O: SampleType {
+  match stream.user_data.panic.lock() {
+    Ok(mut slot) => slot.take(),
+    Err(poisoned) => poisoned.into_inner().take(),
+  }
 }
 
 /// Starts the stream
 pub fn start_stream<I, O>(stream: &Stream<I, O>) -> PaResult
   where
     I: SampleType, O: SampleType {
-  kit::to_pa_result(unsafe { raw_portaudio::Pa_StartStream(stream.pa_stream) })
+  unsafe { stream.portaudio.backend().start_stream(stream.pa_stream) }
+    .map_err(|e| e.context(stream.portaudio, "start"))
 }
 
 
@@ -343,21 +433,39 @@ pub fn start_stream<I, O>(stream: &Stream<I, O>) -> PaResult
 pub fn stop_stream<I, O>(stream: &Stream<I, O>) -> PaResult
   where
     I: SampleType, O: SampleType {
-  kit::to_pa_result(unsafe { raw_portaudio::Pa_StopStream(stream.pa_stream) })
+  unsafe { stream.portaudio.backend().stop_stream(stream.pa_stream) }
+    .map_err(|e| e.context(stream.portaudio, "stop"))
 }
 
 /// Stop stream immediately without waiting for the buffers to complete
 pub fn abort_stream<I, O>(stream: &Stream<I, O>) -> PaResult
   where
     I: SampleType, O: SampleType {
-  kit::to_pa_result(unsafe { raw_portaudio::Pa_AbortStream(stream.pa_stream) })
+  unsafe { stream.portaudio.backend().abort_stream(stream.pa_stream) }
+    .map_err(|e| e.context(stream.portaudio, "abort"))
 }
 
 
-pub fn close_stream<I, O>(stream: &Stream<I, O>) -> PaResult
+/// Closes the stream, aborting it first when it is still running
+///
+/// PortAudio guarantees the callback has returned for the last time once Pa_CloseStream is done,
+/// so the user data may be released afterwards. Closing an already closed stream is a no-op.
+pub fn close_stream<I, O>(stream: &mut Stream<I, O>) -> PaResult
   where
     I: SampleType, O: SampleType {
-  kit::to_pa_result(unsafe { raw_portaudio::Pa_CloseStream(stream.pa_stream) })
+  if stream.pa_stream.is_null() {
+    return Ok(());
+  }
+
+  if let Ok(false) = is_stream_stopped(stream) {
+    // Pa_CloseStream aborts too, but we do not want to depend on that for the ordering.
+    let _ = abort_stream(stream);
+  }
+
+  let result = unsafe { stream.portaudio.backend().close_stream(stream.pa_stream) }
+    .map_err(|e| e.context(stream.portaudio, "close"));
+  stream.pa_stream = ptr::null_mut();
+  result
 }
 
 
@@ -365,20 +473,16 @@ pub fn close_stream<I, O>(stream: &Stream<I, O>) -> PaResult
 pub fn is_stream_stopped<I, O>(stream: &Stream<I, O>) -> Result<bool, PaError>
   where
     I: SampleType, O: SampleType {
-  match unsafe { raw_portaudio::Pa_IsStreamStopped(stream.pa_stream) } {
-    1 => Ok(true),
-    n => kit::to_pa_result(n).map(|_| false),
-  }
+  unsafe { stream.portaudio.backend().is_stream_stopped(stream.pa_stream) }
+    .map_err(|e| e.context(stream.portaudio, "is_stopped"))
 }
 
 /// Returns wether the stream is active
 pub fn is_stream_active<I, O>(stream: &Stream<I, O>) -> Result<bool, PaError>
   where
     I: SampleType, O: SampleType {
-  match unsafe { raw_portaudio::Pa_IsStreamActive(stream.pa_stream) } {
-    1 => Ok(true),
-    n => kit::to_pa_result(n).map(|_| false),
-  }
+  unsafe { stream.portaudio.backend().is_stream_active(stream.pa_stream) }
+    .map_err(|e| e.context(stream.portaudio, "is_active"))
 }
 
 
@@ -386,20 +490,16 @@ pub fn is_stream_active<I, O>(stream: &Stream<I, O>) -> Result<bool, PaError>
 pub fn stream_num_read_available<I, O>(stream: &Stream<I, O>) -> Result<u32, PaError>
   where
     I: SampleType, O: SampleType {
-  match unsafe { raw_portaudio::Pa_GetStreamReadAvailable(stream.pa_stream) } {
-    n if n >= 0 => { Ok(n as u32) }
-    n => kit::to_pa_result(n as i32).map(|_| 0),
-  }
+  unsafe { stream.portaudio.backend().stream_read_available(stream.pa_stream) }
+    .map_err(|e| e.context(stream.portaudio, "num_read_available"))
 }
 
 /// Get the number of frames that can be written to the stream without waiting
 pub fn stream_num_write_available<I, O>(stream: &Stream<I, O>) -> Result<u32, PaError>
   where
     I: SampleType, O: SampleType {
-  match unsafe { raw_portaudio::Pa_GetStreamWriteAvailable(stream.pa_stream) } {
-    n if n >= 0 => { Ok(n as u32) }
-    n => kit::to_pa_result(n as i32).map(|_| 0),
-  }
+  unsafe { stream.portaudio.backend().stream_write_available(stream.pa_stream) }
+    .map_err(|e| e.context(stream.portaudio, "num_write_available"))
 }
 
 
@@ -410,32 +510,35 @@ pub fn stream_num_write_available<I, O>(stream: &Stream<I, O>) -> Result<u32, Pa
 /// * `CanNotWriteToAnInputOnlyStream`: when num_output_channels = 0
 /// * `BadBufferPtr`: when buffer.len() is not a multiple of num_output_channels
 /// * Some other error given by PortAudio
-pub fn write_stream<I, O>(stream: &Stream<I, O>, buffer: &[O]) -> PaResult
+pub fn write_stream<I, O>(stream: &Stream<I, O>, buffer: &[O]) -> Result<IoStatus, PaError>
   where
     I: SampleType, O: SampleType {
   if stream.outputs == 0 {
-    return Err(PaError::PaCanNotWriteToAnInputOnlyStream);
+    return Err(PaError::from(ErrorCode::PaCanNotWriteToAnInputOnlyStream).context(stream.portaudio, "write"));
   }
 
   // Ensure the buffer is the correct size.
   if buffer.len() % stream.outputs as usize != 0 {
-    return Err(PaError::PaBadBufferPtr);
+    return Err(PaError::from(ErrorCode::PaBadBufferPtr).context(stream.portaudio, "write"));
   }
 
   let pointer = buffer.as_ptr() as *const c_void;
-  let frames = (buffer.len() / stream.outputs as usize) as c_ulong;
+  let frames = (buffer.len() / stream.outputs as usize) as u64;
 
-  kit::to_pa_result(unsafe { raw_portaudio::Pa_WriteStream(stream.pa_stream, pointer, frames) })
+  let result = unsafe { stream.portaudio.backend().write_stream(stream.pa_stream, pointer, frames) };
+  io_status(stream, result, "write")
 }
 
 /// Reads the requested number of frames from the input devices. This function blocks until
 /// the whole buffer has been filled.
 ///
 /// Will return `CanNotReadFromAnOutputOnlyStream` if num_input_channels = 0.
-pub fn read_stream<I, O>(stream: &Stream<I, O>, frames: u32) -> Result<Vec<I>, PaError>
+pub fn read_stream<I, O>(stream: &Stream<I, O>, frames: u32) -> Result<(Vec<I>, IoStatus), PaError>
   where
     I: SampleType, O: SampleType {
-  if stream.inputs == 0 { return Err(PaError::PaCanNotReadFromAnOutputOnlyStream); }
+  if stream.inputs == 0 {
+    return Err(PaError::from(ErrorCode::PaCanNotReadFromAnOutputOnlyStream).context(stream.portaudio, "read"));
+  }
 
   // We create a buffer with the needed capacity. Then we feed that to the library, which
   // will fill the buffer accordingly. Afterwards, we set the length of the vector as all its
@@ -444,13 +547,210 @@ pub fn read_stream<I, O>(stream: &Stream<I, O>, frames: u32) -> Result<Vec<I>, P
   let mut buffer = Vec::with_capacity(vec_len as usize);
 
   let buffer_ptr = buffer.as_mut_ptr() as *mut c_void;
-  match kit::to_pa_result(unsafe { raw_portaudio::Pa_ReadStream(stream.pa_stream, buffer_ptr, frames as c_ulong) }) {
-    Ok(()) => {
-      unsafe { buffer.set_len(vec_len as usize); }
-      Ok(buffer)
-    }
-    Err(e) => Err(e),
+  let result = unsafe { stream.portaudio.backend().read_stream(stream.pa_stream, buffer_ptr, frames as u64) };
+  let status = io_status(stream, result, "read")?;
+  unsafe { buffer.set_len(vec_len as usize); }
+  Ok((buffer, status))
+}
+
+/// Writes frame views, which have to have as many channels as the stream outputs
+pub fn write_stream_frames<I, O>(stream: &Stream<I, O>, frames: Frames<O>) -> Result<IoStatus, PaError>
+  where
+    I: SampleType, O: SampleType {
+  if frames.channels() != stream.outputs as usize {
+    return Err(PaError::from(ErrorCode::PaInvalidChannelCount).context(stream.portaudio, "write_frames"));
+  }
+  write_stream(stream, frames.as_slice())
+}
+
+/// Reads into frame views, which have to have as many channels as the stream inputs
+pub fn read_stream_frames_into<I, O>(stream: &Stream<I, O>, mut frames: FramesMut<I>) -> Result<(usize, IoStatus), PaError>
+  where
+    I: SampleType, O: SampleType {
+  if frames.channels() != stream.inputs as usize {
+    return Err(PaError::from(ErrorCode::PaInvalidChannelCount).context(stream.portaudio, "read_frames_into"));
+  }
+  let frame_count = frames.frame_count();
+  read_frames(stream, frames.as_mut_slice(), frame_count, "read_frames_into")
+}
+
+/// Reads as many frames as fit in the buffer, blocking until it has been filled
+///
+/// Returns the number of frames read. The buffer length must be a multiple of num_input_channels.
+pub fn read_stream_into<I, O>(stream: &Stream<I, O>, buffer: &mut [I]) -> Result<(usize, IoStatus), PaError>
+  where
+    I: SampleType, O: SampleType {
+  let frames = input_frames(stream, buffer.len(), "read_into")?;
+  read_frames(stream, buffer, frames, "read_into")
+}
+
+/// Reads only the frames which are available without waiting, at most as many as fit in the buffer
+///
+/// Returns the number of frames read. The buffer length must be a multiple of num_input_channels.
+pub fn try_read_stream<I, O>(stream: &Stream<I, O>, buffer: &mut [I]) -> Result<(usize, IoStatus), PaError>
+  where
+    I: SampleType, O: SampleType {
+  let frames = input_frames(stream, buffer.len(), "try_read")?;
+  let available = stream_num_read_available(stream)? as usize;
+  read_frames(stream, buffer, frames.min(available), "try_read")
+}
+
+/// Writes only the frames for which there is room without waiting
+///
+/// Returns the number of frames written. The buffer length must be a multiple of
+/// num_output_channels.
+// usize::is_multiple_of is newer than the rust-version of the crate
+#[allow(clippy::manual_is_multiple_of)]
+pub fn try_write_stream<I, O>(stream: &Stream<I, O>, buffer: &[O]) -> Result<(usize, IoStatus), PaError>
+  where
+    I: SampleType, O: SampleType {
+  if stream.outputs == 0 {
+    return Err(PaError::from(ErrorCode::PaCanNotWriteToAnInputOnlyStream).context(stream.portaudio, "try_write"));
+  }
+  if buffer.len() % stream.outputs as usize != 0 {
+    return Err(PaError::from(ErrorCode::PaBadBufferPtr).context(stream.portaudio, "try_write"));
+  }
+
+  let available = stream_num_write_available(stream)? as usize;
+  let frames = (buffer.len() / stream.outputs as usize).min(available);
+  if frames == 0 {
+    return Ok((0, IoStatus::Ok));
+  }
+  let pointer = buffer.as_ptr() as *const c_void;
+  let result = unsafe { stream.portaudio.backend().write_stream(stream.pa_stream, pointer, frames as u64) };
+  Ok((frames, io_status(stream, result, "try_write")?))
+}
+
+/// Checks that the stream has input and the buffer holds whole frames, returning their number
+// usize::is_multiple_of is newer than the rust-version of the crate
+#[allow(clippy::manual_is_multiple_of)]
+fn input_frames<I, O>(stream: &Stream<I, O>, len: usize, operation: &'static str) -> Result<usize, PaError>
+  where
+    I: SampleType, O: SampleType {
+  if stream.inputs == 0 {
+    return Err(PaError::from(ErrorCode::PaCanNotReadFromAnOutputOnlyStream).context(stream.portaudio, operation));
+  }
+  if len % stream.inputs as usize != 0 {
+    return Err(PaError::from(ErrorCode::PaBadBufferPtr).context(stream.portaudio, operation));
+  }
+  Ok(len / stream.inputs as usize)
+}
+
+fn read_frames<I, O>(stream: &Stream<I, O>, buffer: &mut [I], frames: usize, operation: &'static str) -> Result<(usize, IoStatus), PaError>
+  where
+    I: SampleType, O: SampleType {
+  if frames == 0 {
+    return Ok((0, IoStatus::Ok));
+  }
+  let pointer = buffer.as_mut_ptr() as *mut c_void;
+  let result = unsafe { stream.portaudio.backend().read_stream(stream.pa_stream, pointer, frames as u64) };
+  Ok((frames, io_status(stream, result, operation)?))
+}
+
+/// Reads the requested number of frames of a non-interleaved stream, one Vec per channel
+pub fn read_planar_stream<I, O>(stream: &Stream<I, O>, frames: u32) -> Result<(Vec<Vec<I>>, IoStatus), PaError>
+  where
+    I: SampleType, O: SampleType {
+  if stream.inputs == 0 {
+    return Err(PaError::from(ErrorCode::PaCanNotReadFromAnOutputOnlyStream).context(stream.portaudio, "read"));
+  }
+
+  // As in read_stream, the lengths are only set once the buffers are filled
+  let mut buffers: Vec<Vec<I>> = (0..stream.inputs).map(|_| Vec::with_capacity(frames as usize)).collect();
+  let mut pointers: Vec<*mut I> = buffers.iter_mut().map(|buffer| buffer.as_mut_ptr()).collect();
+  let result = unsafe { stream.portaudio.backend().read_stream(stream.pa_stream, pointers.as_mut_ptr() as *mut c_void, frames as u64) };
+  let status = io_status(stream, result, "read")?;
+  for buffer in buffers.iter_mut() {
+    unsafe { buffer.set_len(frames as usize); }
+  }
+  Ok((buffers, status))
+}
+
+/// Reads as many frames of a non-interleaved stream as the channel buffers hold
+///
+/// There has to be one buffer per input channel, all of the same length. Returns the number of
+/// frames read.
+pub fn read_planar_stream_into<I, O>(stream: &Stream<I, O>, buffers: &mut [&mut [I]]) -> Result<(usize, IoStatus), PaError>
+  where
+    I: SampleType, O: SampleType {
+  if stream.inputs == 0 {
+    return Err(PaError::from(ErrorCode::PaCanNotReadFromAnOutputOnlyStream).context(stream.portaudio, "read_into"));
   }
+  let frames = planar_frames(stream, stream.inputs, buffers.iter().map(|buffer| buffer.len()), "read_into")?;
+  if frames == 0 {
+    return Ok((0, IoStatus::Ok));
+  }
+  let mut pointers: Vec<*mut I> = buffers.iter_mut().map(|buffer| buffer.as_mut_ptr()).collect();
+  let result = unsafe { stream.portaudio.backend().read_stream(stream.pa_stream, pointers.as_mut_ptr() as *mut c_void, frames as u64) };
+  Ok((frames, io_status(stream, result, "read_into")?))
+}
+
+/// Writes the channel buffers to a non-interleaved stream, blocking until they are consumed
+///
+/// There has to be one buffer per output channel, all of the same length.
+pub fn write_planar_stream<I, O>(stream: &Stream<I, O>, buffers: &[&[O]]) -> Result<IoStatus, PaError>
+  where
+    I: SampleType, O: SampleType {
+  if stream.outputs == 0 {
+    return Err(PaError::from(ErrorCode::PaCanNotWriteToAnInputOnlyStream).context(stream.portaudio, "write"));
+  }
+  let frames = planar_frames(stream, stream.outputs, buffers.iter().map(|buffer| buffer.len()), "write")?;
+  let pointers: Vec<*const O> = buffers.iter().map(|buffer| buffer.as_ptr()).collect();
+  let result = unsafe { stream.portaudio.backend().write_stream(stream.pa_stream, pointers.as_ptr() as *const c_void, frames as u64) };
+  io_status(stream, result, "write")
+}
+
+/// Checks that there is a buffer per channel and that they are equally long, returning the length
+fn planar_frames<I, O, L>(stream: &Stream<I, O>, channels: u32, mut lengths: L, operation: &'static str) -> Result<usize, PaError>
+  where
+    I: SampleType, O: SampleType, L: ExactSizeIterator<Item = usize> {
+  let frames = lengths.next().unwrap_or(0);
+  if lengths.len() + 1 != channels as usize || lengths.any(|len| len != frames) {
+    return Err(PaError::from(ErrorCode::PaBadBufferPtr).context(stream.portaudio, operation));
+  }
+  Ok(frames)
+}
+
+/// Turns the overflow and underflow errors of a transfer which did happen into a status, and
+/// counts them
+fn io_status<I, O>(stream: &Stream<I, O>, result: PaResult, operation: &'static str) -> Result<IoStatus, PaError>
+  where
+    I: SampleType, O: SampleType {
+  match result {
+    Ok(()) => Ok(IoStatus::Ok),
+    Err(e) => match e.code() {
+      ErrorCode::PaInputOverflowed => {
+        stream.xruns.input_overflows.fetch_add(1, Ordering::Relaxed);
+        Ok(IoStatus::InputOverflowed)
+      }
+      ErrorCode::PaOutputUnderflowed => {
+        stream.xruns.output_underflows.fetch_add(1, Ordering::Relaxed);
+        Ok(IoStatus::OutputUnderflowed)
+      }
+      _ => Err(e.context(stream.portaudio, operation)),
+    },
+  }
+}
+
+/// The statistics the callback of the stream collects
+pub fn stream_statistics<I, O>(stream: &Stream<I, O>) -> Arc<StreamStatistics>
+  where
+    I: SampleType, O: SampleType {
+  stream.user_data.stats.clone()
+}
+
+/// Number of reads which reported an input overflow
+pub fn stream_input_overflows<I, O>(stream: &Stream<I, O>) -> u64
+  where
+    I: SampleType, O: SampleType {
+  stream.xruns.input_overflows.load(Ordering::Relaxed)
+}
+
+/// Number of writes which reported an output underflow
+pub fn stream_output_underflows<I, O>(stream: &Stream<I, O>) -> u64
+  where
+    I: SampleType, O: SampleType {
+  stream.xruns.output_underflows.load(Ordering::Relaxed)
 }
 
 /// Returns the cpu load the stream callback consumes. This will return 0.0 if the stream uses
@@ -458,15 +758,14 @@ pub fn read_stream<I, O>(stream: &Stream<I, O>, frames: u32) -> Result<Vec<I>, P
 pub fn stream_cpu_load<I, O>(stream: &Stream<I, O>) -> f64
   where
     I: SampleType, O: SampleType {
-  unsafe { raw_portaudio::Pa_GetStreamCpuLoad(stream.pa_stream) }
+  unsafe { stream.portaudio.backend().stream_cpu_load(stream.pa_stream) }
 }
 
 /// Get the current timestamp of the stream
 pub fn stream_time<I, O>(stream: &Stream<I, O>) -> Duration
   where
     I: SampleType, O: SampleType {
-  let time = unsafe { raw_portaudio::Pa_GetStreamTime(stream.pa_stream) };
-  kit::pa_time_to_duration(time)
+  unsafe { stream.portaudio.backend().stream_time(stream.pa_stream) }
 }
 
 /// Get the actual latencies and sample rate
@@ -475,12 +774,7 @@ pub fn stream_time<I, O>(stream: &Stream<I, O>) -> Duration
 pub fn stream_info<I, O>(stream: &Stream<I, O>) -> Option<PaStreamInfo>
   where
     I: SampleType, O: SampleType {
-  unsafe {
-    match raw_portaudio::Pa_GetStreamInfo(stream.pa_stream) {
-      p if p.is_null() => None,
-      p => Some(PaStreamInfo::from_raw(&*p)),
-    }
-  }
+  unsafe { stream.portaudio.backend().stream_info(stream.pa_stream) }
 }
 
 pub fn sample_size<T: SampleType>() -> Result<u32, PaError> {
@@ -498,48 +792,121 @@ pub fn sample_size<T: SampleType>() -> Result<u32, PaError> {
 //}
 
 
-type StreamCallbackType = extern "C" fn(*const c_void, *mut c_void, ::libc::c_ulong, *const raw_portaudio::PaStreamCallbackTimeInfo, raw_portaudio::PaStreamCallbackFlags, *mut c_void) -> ::libc::c_int;
-type StreamFinishedCallbackType = extern "C" fn(*mut c_void);
-
-extern "C" fn stream_callback<I, O>(input: *const c_void,
-                                    output: *mut c_void,
-                                    frame_count: ::libc::c_ulong,
-                                    time_info: *const raw_portaudio::PaStreamCallbackTimeInfo,
-                                    status_flags: raw_portaudio::PaStreamCallbackFlags,
-                                    user_data: *mut c_void) -> ::libc::c_int {
-  // TODO: use Box::from_raw once it is stable
-  let mut stream_data: Box<StreamUserData<I, O>> = unsafe { mem::transmute(user_data) };
+pub(crate) extern "C" fn stream_callback<I, O>(input: *const c_void,
+                                               output: *mut c_void,
+                                               frame_count: ::libc::c_ulong,
+                                               time_info: *const raw_portaudio::PaStreamCallbackTimeInfo,
+                                               status_flags: raw_portaudio::PaStreamCallbackFlags,
+                                               user_data: *mut c_void) -> ::libc::c_int {
+  // The user data is owned by the Stream, which closes the stream before releasing it.
+  let stream_data: &StreamUserData<I, O> = unsafe { &*(user_data as *const StreamUserData<I, O>) };
   let input_buffer: &[I] = unsafe {
-    ::std::slice::from_raw_parts(input as *const I, frame_count as usize * stream_data.num_input as usize)
+    kit::buffer_slice(input as *const I, frame_count as usize * stream_data.num_input as usize)
   };
   let output_buffer: &mut [O] = unsafe {
-    ::std::slice::from_raw_parts_mut(output as *mut O, frame_count as usize * stream_data.num_output as usize)
+    kit::buffer_slice_mut(output as *mut O, frame_count as usize * stream_data.num_output as usize)
   };
 
-  let flags = PaStreamCallbackFlags::from_bits_truncate(status_flags as u64);
+  // Only the audio thread touches the callback while the stream is running
+  let callback = unsafe { &mut *stream_data.callback.get() };
+  run_callback(stream_data, frame_count, time_info, status_flags, |timeinfo, flags| {
+    match *callback {
+      Some(ref mut f) => (*f)(input_buffer, output_buffer, timeinfo, flags),
+      None => PaStreamCallbackResult::Abort,
+    }
+  })
+}
+
+pub(crate) extern "C" fn planar_stream_callback<I, O>(input: *const c_void,
+                                                      output: *mut c_void,
+                                                      frame_count: ::libc::c_ulong,
+                                                      time_info: *const raw_portaudio::PaStreamCallbackTimeInfo,
+                                                      status_flags: raw_portaudio::PaStreamCallbackFlags,
+                                                      user_data: *mut c_void) -> ::libc::c_int {
+  // The user data is owned by the Stream, which closes the stream before releasing it.
+  let stream_data: &StreamUserData<I, O> = unsafe { &*(user_data as *const StreamUserData<I, O>) };
+  // Only the audio thread touches the callback while the stream is running
+  let planar = match unsafe { &mut *stream_data.planar_callback.get() } {
+    Some(planar) => planar,
+    None => return PaStreamCallbackResult::Abort as i32,
+  };
+  let PlanarCallback { ref mut callback, ref mut inputs, ref mut outputs } = *planar;
 
-  assert!(!time_info.is_null());
-  let time_info_ll = unsafe { &*time_info };
-  let timeinfo = PaStreamTimeInfo::from_raw(time_info_ll);
+  // Non-interleaved buffers are arrays with a pointer to the samples of each channel
+  let frames = frame_count as usize;
+  let input_pointers = unsafe { kit::buffer_slice(input as *const *const I, inputs.len()) };
+  for (slice, &pointer) in inputs.iter_mut().zip(input_pointers) {
+    *slice = unsafe { kit::buffer_slice(pointer, frames) };
+  }
+  let output_pointers = unsafe { kit::buffer_slice(output as *const *mut O, outputs.len()) };
+  for (slice, &pointer) in outputs.iter_mut().zip(output_pointers) {
+    *slice = unsafe { kit::buffer_slice_mut(pointer, frames) };
+  }
 
-  let result = match stream_data.callback {
-    Some(ref mut f) => (*f)(input_buffer, output_buffer, timeinfo, flags),
-    None => PaStreamCallbackResult::Abort,
+  let result = run_callback(stream_data, frame_count, time_info, status_flags, |timeinfo, flags| {
+    (*callback)(inputs, outputs, timeinfo, flags)
+  });
+
+  // The buffers are only lent for this call
+  for slice in inputs.iter_mut() {
+    *slice = &[];
+  }
+  for slice in outputs.iter_mut() {
+    *slice = &mut [];
+  }
+  result
+}
+
+/// Invoke a stream callback, catching a panic and recording the statistics
+fn run_callback<I, O, F>(stream_data: &StreamUserData<I, O>,
+                         frame_count: ::libc::c_ulong,
+                         time_info: *const raw_portaudio::PaStreamCallbackTimeInfo,
+                         status_flags: raw_portaudio::PaStreamCallbackFlags,
+                         callback: F) -> ::libc::c_int
+  where F: FnOnce(PaStreamTimeInfo, PaStreamCallbackFlags) -> PaStreamCallbackResult {
+  let flags = PaStreamCallbackFlags::from_bits_truncate(status_flags as u64);
+
+  let timeinfo = match unsafe { time_info.as_ref() } {
+    Some(time_info_ll) => PaStreamTimeInfo::from_raw(time_info_ll),
+    None => PaStreamTimeInfo::default(),
   };
 
-  mem::forget(stream_data);
+  let started = Instant::now();
+  let result = panic::catch_unwind(AssertUnwindSafe(|| callback(timeinfo, flags)));
 
-  result as i32
+  // The CPU load is measured here, the audio thread never calls back into the backend
+  stream_data.stats.record(flags, frame_count as u64, started, Instant::now());
+
+  match result {
+    Ok(result) => result as i32,
+    Err(payload) => {
+      report_callback_panic(stream_data, payload);
+      PaStreamCallbackResult::Abort as i32
+    }
+  }
 }
 
-extern "C" fn stream_finished_callback<I, O>(user_data: *mut c_void) {
-  // TODO: use Box::from_raw once it is stable
-  let mut stream_data: Box<StreamUserData<I, O>> = unsafe { mem::transmute(user_data) };
-  match stream_data.finished_callback {
-    Some(ref mut f) => (*f)(),
-    None => {}
-  };
+pub(crate) extern "C" fn stream_finished_callback<I, O>(user_data: *mut c_void) {
+  let stream_data: &StreamUserData<I, O> = unsafe { &*(user_data as *const StreamUserData<I, O>) };
+  let finished_callback = unsafe { &mut *stream_data.finished_callback.get() };
+  let result = panic::catch_unwind(AssertUnwindSafe(|| {
+    if let Some(ref mut f) = *finished_callback {
+      (*f)()
+    }
+  }));
 
-  mem::forget(stream_data);
+  if let Err(payload) = result {
+    report_callback_panic(stream_data, payload);
+  }
 }
 
+/// Keep the first panic payload around so the owner of the stream can find out what happened
+fn report_callback_panic<I, O>(stream_data: &StreamUserData<I, O>, payload: Box<dyn Any + Send>) {
+  let mut slot = match stream_data.panic.lock() {
+    Ok(slot) => slot,
+    Err(poisoned) => poisoned.into_inner(),
+  };
+  if slot.is_none() {
+    *slot = Some(payload);
+  }
+}
@@ -0,0 +1,322 @@
+//! Conversions between the sample types
+//!
+//! Every `Sample` converts to and from f32 in -1.0..1.0 and to and from a full scale i32, where
+//! the sample sits in the upper bits. Widening conversions are exact, narrowing ones round to the
+//! nearest value and clip. Bulk conversions can dither what they narrow, with a `Converter`.
+//!
+//! ```
+//! use rportaudio::sample::{self, Converter, Dither, Sample};
+//!
+//! assert_eq!(i16::from_f32(0.5), 16384);
+//! assert_eq!(u8::from_f32(0.0), 128);
+//! assert_eq!(sample::convert_sample::<i16, i32>(-2), -2 << 16);
+//!
+//! let mut samples = [0i16; 4];
+//! let mut converter = Converter::new(2, Dither::Tpdf);
+//! assert_eq!(converter.convert(&[0.25f32, -0.25, 0.5, -0.5], &mut samples), 4);
+//! ```
+
+use crate::types::*;
+
+/// Frames the f32 buffers of `f32_callback` have room for when the buffer size is unspecified
+const DEFAULT_FRAMES: usize = 4096;
+
+
+/// A SampleType which can be converted to any other
+pub trait Sample: SampleType + Copy + Default + Send + 'static {
+  /// Bits of resolution, 24 for f32 as its mantissa has that many
+  const BITS: u32;
+
+  /// Whether the samples are integers, which conversions to this type may dither
+  const INTEGER: bool;
+
+  /// The value of silence, which is not the default for u8
+  const EQUILIBRIUM: Self;
+
+  /// The sample as a float in -1.0..1.0
+  fn to_f32(self) -> f32;
+
+  /// The sample closest to a float in -1.0..1.0, clipping what is outside
+  fn from_f32(value: f32) -> Self;
+
+  /// The sample scaled to the full range of an i32
+  fn to_i32(self) -> i32;
+
+  /// The sample closest to a full scale i32
+  fn from_i32(value: i32) -> Self;
+}
+
+/// Round a float in -1.0..1.0 to an integer of `bits` bits, clipping what is outside and turning
+/// NaN into 0
+fn quantize(value: f32, bits: u32) -> i32 {
+  let scale = (1u64 << (bits - 1)) as f64;
+  (value as f64 * scale).round().clamp(-scale, scale - 1.0) as i32
+}
+
+/// Round a full scale i32 to its upper `32 - shift` bits, which saturate at the top
+fn narrow(value: i32, shift: u32) -> i32 {
+  let rounded = (value as i64 + (1 << (shift - 1))) >> shift;
+  rounded.min((i32::MAX >> shift) as i64) as i32
+}
+
+impl Sample for f32 {
+  const BITS: u32 = 24;
+  const INTEGER: bool = false;
+  const EQUILIBRIUM: f32 = 0.0;
+
+  fn to_f32(self) -> f32 { self }
+  fn from_f32(value: f32) -> f32 { value }
+  fn to_i32(self) -> i32 { quantize(self, 32) }
+  fn from_i32(value: i32) -> f32 { (value as f64 / 2147483648.0) as f32 }
+}
+
+impl Sample for i32 {
+  const BITS: u32 = 32;
+  const INTEGER: bool = true;
+  const EQUILIBRIUM: i32 = 0;
+
+  fn to_f32(self) -> f32 { (self as f64 / 2147483648.0) as f32 }
+  fn from_f32(value: f32) -> i32 { quantize(value, 32) }
+  fn to_i32(self) -> i32 { self }
+  fn from_i32(value: i32) -> i32 { value }
+}
+
+impl Sample for I24 {
+  const BITS: u32 = 24;
+  const INTEGER: bool = true;
+  const EQUILIBRIUM: I24 = I24::ZERO;
+
+  fn to_f32(self) -> f32 { I24::to_f32(self) }
+  fn from_f32(value: f32) -> I24 { I24::from_f32(value) }
+  fn to_i32(self) -> i32 { I24::to_i32(self) << 8 }
+  fn from_i32(value: i32) -> I24 { I24::saturating_from_i32(narrow(value, 8)) }
+}
+
+impl Sample for i16 {
+  const BITS: u32 = 16;
+  const INTEGER: bool = true;
+  const EQUILIBRIUM: i16 = 0;
+
+  fn to_f32(self) -> f32 { self as f32 / 32768.0 }
+  fn from_f32(value: f32) -> i16 { quantize(value, 16) as i16 }
+  fn to_i32(self) -> i32 { (self as i32) << 16 }
+  fn from_i32(value: i32) -> i16 { narrow(value, 16) as i16 }
+}
+
+impl Sample for i8 {
+  const BITS: u32 = 8;
+  const INTEGER: bool = true;
+  const EQUILIBRIUM: i8 = 0;
+
+  fn to_f32(self) -> f32 { self as f32 / 128.0 }
+  fn from_f32(value: f32) -> i8 { quantize(value, 8) as i8 }
+  fn to_i32(self) -> i32 { (self as i32) << 24 }
+  fn from_i32(value: i32) -> i8 { narrow(value, 24) as i8 }
+}
+
+/// Unsigned samples are offset by 128, which is silence
+impl Sample for u8 {
+  const BITS: u32 = 8;
+  const INTEGER: bool = true;
+  const EQUILIBRIUM: u8 = 128;
+
+  fn to_f32(self) -> f32 { (self as f32 - 128.0) / 128.0 }
+  fn from_f32(value: f32) -> u8 { (quantize(value, 8) + 128) as u8 }
+  fn to_i32(self) -> i32 { (self as i32 - 128) << 24 }
+  fn from_i32(value: i32) -> u8 { (narrow(value, 24) + 128) as u8 }
+}
+
+
+/// Convert one sample, exactly when the target is at least as wide
+///
+/// Integers are converted through i32, anything involving f32 through f32.
+pub fn convert_sample<S: Sample, D: Sample>(sample: S) -> D {
+  match S::INTEGER && D::INTEGER {
+    true => D::from_i32(sample.to_i32()),
+    false => D::from_f32(sample.to_f32()),
+  }
+}
+
+/// Convert as many samples as both slices hold, returning their number
+pub fn convert<S: Sample, D: Sample>(input: &[S], output: &mut [D]) -> usize {
+  for (s, d) in input.iter().zip(output.iter_mut()) {
+    *d = convert_sample(*s);
+  }
+  input.len().min(output.len())
+}
+
+
+/// Dither to apply when a conversion loses resolution
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum Dither {
+  /// Round to the nearest value
+  #[default]
+  None,
+
+  /// Add triangular noise of up to one step either way before rounding, which turns the rounding
+  /// error into a constant noise floor instead of distortion of the signal
+  Tpdf,
+
+  /// TPDF dither with the error of every sample subtracted from the next one of its channel,
+  /// which moves the noise up to the high frequencies where it is heard less
+  NoiseShaped,
+}
+
+/// Converts interleaved samples, dithering conversions to a narrower integer type
+///
+/// The noise shaping state is kept per channel between calls, so a stream should have its own
+/// converter. Nothing is allocated after construction, so it can be used in a stream callback.
+pub struct Converter {
+  dither: Dither,
+  errors: Vec<f64>,
+  channel: usize,
+  random: u32,
+}
+
+impl Converter {
+  /// A converter for samples of `channels` interleaved channels
+  pub fn new(channels: u32, dither: Dither) -> Self {
+    Converter {
+      dither,
+      errors: vec![0.0; channels.max(1) as usize],
+      channel: 0,
+      random: 0x9e3779b9,
+    }
+  }
+
+  /// The dither which is applied
+  pub fn dither(&self) -> Dither {
+    self.dither
+  }
+
+  /// Convert as many samples as both slices hold, returning their number
+  ///
+  /// Samples are only dithered when D is an integer type narrower than S. The slices do not have
+  /// to hold whole frames, the next call continues with the next channel.
+  pub fn convert<S: Sample, D: Sample>(&mut self, input: &[S], output: &mut [D]) -> usize {
+    if self.dither == Dither::None || !D::INTEGER || (S::INTEGER && S::BITS <= D::BITS) {
+      return convert(input, output);
+    }
+
+    // Work in steps of the target, where rounding to an integer is what the conversion does
+    let scale = (1u64 << (D::BITS - 1)) as f64;
+    let shift = 32 - D::BITS;
+    for (s, d) in input.iter().zip(output.iter_mut()) {
+      let value = match S::INTEGER {
+        true => s.to_i32() as f64 / (1u64 << shift) as f64,
+        false => s.to_f32() as f64 * scale,
+      };
+      let wanted = match self.dither {
+        Dither::NoiseShaped => value - self.errors[self.channel],
+        _ => value,
+      };
+      let noise = self.uniform() - self.uniform();
+      let quantized = (wanted + noise).round().clamp(-scale, scale - 1.0);
+      // The error is bounded, so a clipped sample does not throw the feedback off
+      self.errors[self.channel] = (quantized - wanted).clamp(-1.5, 1.5);
+      self.channel = (self.channel + 1) % self.errors.len();
+      *d = D::from_i32((quantized as i32) << shift);
+    }
+    input.len().min(output.len())
+  }
+
+  /// A uniformly distributed number in 0.0..1.0, from a xorshift generator
+  fn uniform(&mut self) -> f64 {
+    self.random ^= self.random << 13;
+    self.random ^= self.random >> 17;
+    self.random ^= self.random << 5;
+    self.random as f64 / 4294967296.0
+  }
+}
+
+
+/// Wrap a callback which works in f32 for a stream of I and O samples
+///
+/// The input is converted exactly where f32 allows, the output with the given dither. The f32
+/// buffers are allocated up front for `frames_per_buffer` frames, and only grow on the audio
+/// thread when a larger buffer arrives.
+pub fn f32_callback<'a, I: Sample, O: Sample>(input_channels: u32,
+                                              output_channels: u32,
+                                              frames_per_buffer: u64,
+                                              dither: Dither,
+                                              mut callback: Box<StreamCallback<'a, f32, f32>>)
+                                              -> Box<StreamCallback<'a, I, O>> {
+  let frames = match frames_per_buffer {
+    0 => DEFAULT_FRAMES,
+    n => n as usize,
+  };
+  let mut input_f32 = Vec::with_capacity(frames * input_channels as usize);
+  let mut output_f32 = Vec::with_capacity(frames * output_channels as usize);
+  let mut converter = Converter::new(output_channels, dither);
+  Box::new(move |input: &[I], output: &mut [O], time, flags| {
+    input_f32.clear();
+    input_f32.extend(input.iter().map(|sample| sample.to_f32()));
+    output_f32.clear();
+    output_f32.resize(output.len(), 0.0);
+    let result = callback(&input_f32, &mut output_f32, time, flags);
+    converter.convert(&output_f32, output);
+    result
+  })
+}
+
+
+#[cfg(test)]
+mod test {
+  use crate::types::I24;
+
+  use super::{convert, convert_sample, Converter, Dither, Sample};
+
+  #[test]
+  fn test_sample() {
+    assert_eq!(i16::from_f32(1.0), i16::MAX);
+    assert_eq!(i16::from_f32(-1.5), i16::MIN);
+    assert_eq!(i16::from_f32(f32::NAN), 0);
+    assert_eq!(i32::from_f32(1.0), i32::MAX);
+    assert_eq!((-16384i16).to_f32(), -0.5);
+
+    // The offset of u8 is kept apart from the value
+    assert_eq!(u8::EQUILIBRIUM.to_f32(), 0.0);
+    assert_eq!(u8::from_f32(-1.0), 0);
+    assert_eq!(0u8.to_i32(), i32::MIN);
+    assert_eq!(convert_sample::<i8, u8>(-1), 127);
+    assert_eq!(convert_sample::<u8, i16>(255), 127 << 8);
+
+    // Widening is exact and narrowing rounds, saturating at the top
+    assert_eq!(convert_sample::<i16, I24>(-3).to_i32(), -3 << 8);
+    assert_eq!(convert_sample::<I24, i16>(I24::new(0x180).unwrap()), 2);
+    assert_eq!(convert_sample::<i32, i16>(i32::MAX), i16::MAX);
+    assert_eq!(convert_sample::<i32, i8>(i32::MIN), i8::MIN);
+    assert_eq!(convert_sample::<f32, I24>(-1.0), I24::MIN);
+
+    let mut output = [0.0f32; 2];
+    assert_eq!(convert(&[i16::MIN, 8192, 1], &mut output), 2);
+    assert_eq!(output, [-1.0, 0.25]);
+  }
+
+  #[test]
+  fn test_dither() {
+    // A level between two steps of an i8
+    let level = 0.3 / 128.0;
+    let input = vec![level; 20000];
+    let mean = |samples: &[i8]| samples.iter().map(|&s| s as f64).sum::<f64>() / samples.len() as f64;
+
+    let mut rounded = vec![0i8; input.len()];
+    Converter::new(1, Dither::None).convert(&input, &mut rounded);
+    assert!(rounded.iter().all(|&s| s == 0));
+
+    // Dithering keeps the level on average, with a noise floor of a few steps
+    for &dither in &[Dither::Tpdf, Dither::NoiseShaped] {
+      let mut dithered = vec![0i8; input.len()];
+      let mut converter = Converter::new(2, dither);
+      assert_eq!(converter.convert(&input, &mut dithered[..15000]), 15000);
+      converter.convert(&input, &mut dithered[15000..]);
+      assert!((mean(&dithered) - 0.3).abs() < 0.05, "{:?}: {}", dither, mean(&dithered));
+      assert!(dithered.iter().all(|&s| (-3..=3).contains(&s)));
+    }
+
+    // Widening conversions are never dithered
+    let mut wide = [0i32; 2];
+    Converter::new(1, Dither::Tpdf).convert(&[3i16, -3], &mut wide);
+    assert_eq!(wide, [3 << 16, -3 << 16]);
+  }
+}
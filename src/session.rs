@@ -0,0 +1,65 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+pub use crate::types::PortAudio;
+use crate::backend::{AudioBackend, PortAudioBackend};
+use crate::rpa_error::{PaError, PaResult};
+use crate::types::PortAudioSession;
+
+impl PortAudio {
+  /// Initialize the PortAudio API
+  ///
+  /// PortAudio stays initialized for as long as this handle, or any clone of it, is alive.
+  ///
+  /// ```no_run
+  /// let pa = rportaudio::PortAudio::new().unwrap();
+  /// println!("{:?}", rportaudio::device::count(&pa));
+  /// ```
+  pub fn new() -> Result<PortAudio, PaError> {
+    PortAudio::with_backend(PortAudioBackend)
+  }
+
+  /// Start a session on another backend, such as the `VirtualBackend`
+  ///
+  /// ```
+  /// use rportaudio::backend::VirtualBackend;
+  ///
+  /// let pa = rportaudio::PortAudio::with_backend(VirtualBackend::default()).unwrap();
+  /// assert_eq!(rportaudio::device::count(&pa), Ok(1));
+  /// ```
+  pub fn with_backend<B: AudioBackend + 'static>(backend: B) -> Result<PortAudio, PaError> {
+    backend.initialize()?;
+    Ok(PortAudio {
+      session: Arc::new(PortAudioSession {
+        backend: Box::new(backend),
+        capabilities: Mutex::new(HashMap::new()),
+        terminated: false,
+      }),
+    })
+  }
+
+  /// Release this handle, terminating PortAudio when it was the last one
+  ///
+  /// Dropping the handle does the same, but any error is lost there.
+  pub fn terminate(self) -> PaResult {
+    match Arc::try_unwrap(self.session) {
+      Ok(mut session) => {
+        session.terminated = true;
+        session.backend.terminate()
+      }
+      Err(_) => Ok(()),
+    }
+  }
+
+  pub(crate) fn backend(&self) -> &dyn AudioBackend {
+    &*self.session.backend
+  }
+}
+
+impl Drop for PortAudioSession {
+  fn drop(&mut self) {
+    if !self.terminated {
+      let _ = self.backend.terminate();
+    }
+  }
+}
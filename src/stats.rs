@@ -0,0 +1,236 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::types::*;
+
+/// Distinct frame counts which are counted one by one, any others are only counted together
+const FRAME_COUNT_SLOTS: usize = 16;
+
+/// Number of CPU load samples that are kept
+const CPU_LOAD_HISTORY: usize = 64;
+
+/// Time between two CPU load samples
+const CPU_LOAD_PERIOD: Duration = Duration::from_millis(100);
+
+const FLAGS: [PaStreamCallbackFlags; 5] = [INPUT_UNDERFLOW, INPUT_OVERFLOW, OUTPUT_UNDERFLOW, OUTPUT_OVERFLOW, PRIMING_OUTPUT];
+
+
+/// Statistics the stream callback collects, readable from any thread without locking
+///
+/// Every counter is updated atomically by the audio thread, a snapshot taken while the callback
+/// runs may mix values from before and after that callback.
+pub struct StreamStatistics {
+  origin: Instant,
+  callbacks: AtomicU64,
+  flags: [AtomicU64; 5],
+  // A frame count of 0 marks a free slot, and a slot is never freed once taken
+  frame_counts: [(AtomicU64, AtomicU64); FRAME_COUNT_SLOTS],
+  other_frame_counts: AtomicU64,
+  total_nanos: AtomicU64,
+  min_nanos: AtomicU64,
+  max_nanos: AtomicU64,
+  // Bits of the f64 loads, the most recent at cpu_load_samples - 1 modulo the history
+  cpu_loads: [AtomicU64; CPU_LOAD_HISTORY],
+  cpu_load_samples: AtomicU64,
+  next_cpu_load_nanos: AtomicU64,
+  // Bits of the f64 sample rate, which gives the duration of a buffer
+  sample_rate: AtomicU64,
+}
+
+/// The state of a StreamStatistics at one point in time
+#[derive(Debug, Clone, PartialEq)]
+pub struct StatsSnapshot {
+  /// Number of times the callback was invoked
+  pub callbacks: u64,
+
+  /// Number of callbacks which were passed INPUT_UNDERFLOW
+  pub input_underflows: u64,
+
+  /// Number of callbacks which were passed INPUT_OVERFLOW
+  pub input_overflows: u64,
+
+  /// Number of callbacks which were passed OUTPUT_UNDERFLOW
+  pub output_underflows: u64,
+
+  /// Number of callbacks which were passed OUTPUT_OVERFLOW
+  pub output_overflows: u64,
+
+  /// Number of callbacks which were passed PRIMING_OUTPUT
+  pub priming_output: u64,
+
+  /// Pairs of a frame count and the number of callbacks which got it, by frame count
+  pub frame_counts: Vec<(u64, u64)>,
+
+  /// Callbacks whose frame count did not fit in the distribution, which tracks 16 distinct values
+  pub other_frame_counts: u64,
+
+  /// Shortest time a callback took
+  pub min_callback_time: Duration,
+
+  /// Average time a callback took
+  pub mean_callback_time: Duration,
+
+  /// Longest time a callback took
+  pub max_callback_time: Duration,
+
+  /// CPU load of the callback, the time it took as a fraction of the duration of its buffer,
+  /// sampled every 100 ms while the callback runs, oldest first
+  pub cpu_load: Vec<f64>,
+}
+
+impl StreamStatistics {
+  pub(crate) fn new() -> Self {
+    StreamStatistics {
+      origin: Instant::now(),
+      callbacks: AtomicU64::new(0),
+      flags: Default::default(),
+      frame_counts: Default::default(),
+      other_frame_counts: AtomicU64::new(0),
+      total_nanos: AtomicU64::new(0),
+      min_nanos: AtomicU64::new(u64::MAX),
+      max_nanos: AtomicU64::new(0),
+      cpu_loads: [(); CPU_LOAD_HISTORY].map(|_| AtomicU64::new(0)),
+      cpu_load_samples: AtomicU64::new(0),
+      next_cpu_load_nanos: AtomicU64::new(0),
+      sample_rate: AtomicU64::new(0),
+    }
+  }
+
+  /// Set the sample rate of the stream, CPU load is only sampled once it is known
+  pub(crate) fn set_sample_rate(&self, sample_rate: f64) {
+    self.sample_rate.store(sample_rate.to_bits(), Ordering::Relaxed);
+  }
+
+  /// Account for one callback, returns true when it was sampled for the CPU load
+  pub(crate) fn record(&self, flags: PaStreamCallbackFlags, frame_count: u64, started: Instant, finished: Instant) -> bool {
+    self.callbacks.fetch_add(1, Ordering::Relaxed);
+    for (counter, flag) in self.flags.iter().zip(FLAGS.iter()) {
+      if flags.contains(*flag) {
+        counter.fetch_add(1, Ordering::Relaxed);
+      }
+    }
+    self.record_frame_count(frame_count);
+
+    let nanos = finished.duration_since(started).as_nanos() as u64;
+    self.total_nanos.fetch_add(nanos, Ordering::Relaxed);
+    self.min_nanos.fetch_min(nanos, Ordering::Relaxed);
+    self.max_nanos.fetch_max(nanos, Ordering::Relaxed);
+
+    // Only the audio thread moves the next sample time, so a plain store is enough
+    let now = finished.duration_since(self.origin).as_nanos() as u64;
+    let buffer_nanos = frame_count as f64 / f64::from_bits(self.sample_rate.load(Ordering::Relaxed)) * 1e9;
+    if now < self.next_cpu_load_nanos.load(Ordering::Relaxed) || !(buffer_nanos > 0.0 && buffer_nanos.is_finite()) {
+      return false;
+    }
+    self.next_cpu_load_nanos.store(now + CPU_LOAD_PERIOD.as_nanos() as u64, Ordering::Relaxed);
+    self.push_cpu_load(nanos as f64 / buffer_nanos);
+    true
+  }
+
+  fn push_cpu_load(&self, load: f64) {
+    let samples = self.cpu_load_samples.load(Ordering::Relaxed);
+    self.cpu_loads[samples as usize % CPU_LOAD_HISTORY].store(load.to_bits(), Ordering::Relaxed);
+    self.cpu_load_samples.store(samples + 1, Ordering::Release);
+  }
+
+  fn record_frame_count(&self, frame_count: u64) {
+    if frame_count > 0 {
+      for (value, count) in self.frame_counts.iter() {
+        match value.compare_exchange(0, frame_count, Ordering::Relaxed, Ordering::Relaxed) {
+          Ok(_) => {}
+          Err(taken) if taken == frame_count => {}
+          Err(_) => continue,
+        }
+        count.fetch_add(1, Ordering::Relaxed);
+        return;
+      }
+    }
+    self.other_frame_counts.fetch_add(1, Ordering::Relaxed);
+  }
+
+  /// Read all the statistics
+  pub fn snapshot(&self) -> StatsSnapshot {
+    let callbacks = self.callbacks.load(Ordering::Relaxed);
+    let flag = |i: usize| self.flags[i].load(Ordering::Relaxed);
+
+    let mut frame_counts: Vec<(u64, u64)> = self.frame_counts.iter()
+      .map(|(value, count)| (value.load(Ordering::Relaxed), count.load(Ordering::Relaxed)))
+      .filter(|&(value, count)| value > 0 && count > 0)
+      .collect();
+    frame_counts.sort();
+
+    let samples = self.cpu_load_samples.load(Ordering::Acquire);
+    let kept = samples.min(CPU_LOAD_HISTORY as u64);
+    let cpu_load = (samples - kept..samples)
+      .map(|i| f64::from_bits(self.cpu_loads[i as usize % CPU_LOAD_HISTORY].load(Ordering::Relaxed)))
+      .collect();
+
+    StatsSnapshot {
+      callbacks,
+      input_underflows: flag(0),
+      input_overflows: flag(1),
+      output_underflows: flag(2),
+      output_overflows: flag(3),
+      priming_output: flag(4),
+      frame_counts,
+      other_frame_counts: self.other_frame_counts.load(Ordering::Relaxed),
+      min_callback_time: match callbacks {
+        0 => Duration::from_secs(0),
+        _ => Duration::from_nanos(self.min_nanos.load(Ordering::Relaxed)),
+      },
+      mean_callback_time: match callbacks {
+        0 => Duration::from_secs(0),
+        n => Duration::from_nanos(self.total_nanos.load(Ordering::Relaxed) / n),
+      },
+      max_callback_time: Duration::from_nanos(self.max_nanos.load(Ordering::Relaxed)),
+      cpu_load,
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod test {
+  use std::time::{Duration, Instant};
+
+  use crate::backend::{SimulatedBuffer, SimulatedStream};
+  use crate::types::*;
+
+  use super::StreamStatistics;
+
+  #[test]
+  fn test_record() {
+    let stats = StreamStatistics::new();
+    let start = Instant::now();
+    stats.set_sample_rate(50000.0);
+    assert!(stats.record(INPUT_OVERFLOW | PRIMING_OUTPUT, 250, start, start + Duration::from_micros(30)));
+    assert!(!stats.record(PaStreamCallbackFlags::empty(), 128, start, start + Duration::from_micros(10)));
+    assert!(!stats.record(OUTPUT_UNDERFLOW, 250, start, start + Duration::from_micros(20)));
+
+    let snapshot = stats.snapshot();
+    assert_eq!(snapshot.callbacks, 3);
+    assert_eq!((snapshot.input_overflows, snapshot.output_underflows, snapshot.priming_output), (1, 1, 1));
+    assert_eq!(snapshot.input_underflows, 0);
+    assert_eq!(snapshot.frame_counts, vec![(128, 1), (250, 2)]);
+    assert_eq!(snapshot.min_callback_time, Duration::from_micros(10));
+    assert_eq!(snapshot.mean_callback_time, Duration::from_micros(20));
+    assert_eq!(snapshot.max_callback_time, Duration::from_micros(30));
+    // 30 us of a 5 ms buffer
+    assert_eq!(snapshot.cpu_load.len(), 1);
+    assert!((snapshot.cpu_load[0] - 0.006).abs() < 1e-12);
+
+    // Without a sample rate there is no buffer duration to measure the load against
+    assert!(!StreamStatistics::new().record(PaStreamCallbackFlags::empty(), 250, start, start));
+  }
+
+  #[test]
+  fn test_simulated() {
+    let mut stream = SimulatedStream::new(0, 1, 48000.0, Box::new(|_input: &[f32], _output: &mut [f32], _time, _flags| PaStreamCallbackResult::Continue));
+    stream.run(&[SimulatedBuffer::new(64), SimulatedBuffer::new(32).with_flags(OUTPUT_UNDERFLOW), SimulatedBuffer::new(64)]);
+
+    let snapshot = stream.statistics().snapshot();
+    assert_eq!(snapshot.callbacks, 3);
+    assert_eq!(snapshot.output_underflows, 1);
+    assert_eq!(snapshot.frame_counts, vec![(32, 1), (64, 2)]);
+  }
+}
@@ -1,6 +1,19 @@
+use std::any::Any;
+use std::sync::Arc;
 use std::time::Duration;
 
+// The async IO of streams, read_async and write_async, is on the handles of a Bridge
+pub use crate::bridge::{Bridge, InputReceiver, OutputSender};
+#[cfg(feature = "futures")]
+pub use crate::bridge::{InputBlocks, OutputSink};
+pub use crate::builder::{Latency, StreamBuilder};
+pub use crate::stats::{StatsSnapshot, StreamStatistics};
+pub use crate::lifecycle::{Finished, LifecycleStream, Running, Stopped, StreamProgress, StreamState, TransitionError};
+pub use crate::planar::PlanarStream;
+pub use crate::typed::{TypedStream, TypedStreamCallback};
+pub use crate::negotiate::{AnyStream, FormatNegotiator, NegotiatedStream, StreamConfig};
 pub use crate::types::Stream;
+use crate::frames::{Frames, FramesMut};
 use crate::rpa_error::{PaError, PaResult};
 use crate::rportaudio;
 use crate::types::*;
@@ -13,6 +26,7 @@ impl<'a, T: SampleType> Stream<'a, T, T> {
   /// Constructs a stream using the default input and output devices
   ///
   /// ## Arguments
+  /// * pa: The PortAudio session the stream belongs to
   /// * num_input_channels: Desired number of input channels
   /// * num_output_channels: Desired number of output channels
   /// * sample_rate: Sample rate of the stream
@@ -20,13 +34,15 @@ impl<'a, T: SampleType> Stream<'a, T, T> {
   /// portaudio determine the optimal number.
   /// * callback: Some(callback) which PortAudio will call to read/write the buffers, or None
   /// when using the read and write methods
-  pub fn open_default(num_input_channels: u32,
+  pub fn open_default(pa: &'a PortAudio,
+                      num_input_channels: u32,
                       num_output_channels: u32,
                       sample_rate: f64,
                       frames_per_buffer: u64,
                       callback: Option<Box<StreamCallback<'a, T, T>>>)
                       -> Result<Stream<'a, T, T>, PaError> {
     rportaudio::open_default_stream(
+      pa,
       num_input_channels,
       num_output_channels,
       sample_rate,
@@ -41,6 +57,7 @@ impl<'a, I: SampleType, O: SampleType> Stream<'a, I, O> {
   /// Constructs a stream with the desired input and output specifications
   ///
   /// ## Arguments
+  /// * pa: The PortAudio session the stream belongs to
   /// * input: Specification for the input channel, or None for an output-only stream
   /// * output: Specification for the output channel, or None for an input-only stream
   /// * sample_rate: Sample rate of the stream
@@ -49,7 +66,8 @@ impl<'a, I: SampleType, O: SampleType> Stream<'a, I, O> {
   /// * flags: Additional flags for the behaviour of the stream
   /// * callback: Some(callback) which PortAudio will call to read/write the buffers, or None
   /// when using the read and write methods
-  pub fn open(input: Option<PaStreamParameters<I>>,
+  pub fn open(pa: &'a PortAudio,
+              input: Option<PaStreamParameters<I>>,
               output: Option<PaStreamParameters<O>>,
               sample_rate: f64,
               frames_per_buffer: u64,
@@ -57,6 +75,7 @@ impl<'a, I: SampleType, O: SampleType> Stream<'a, I, O> {
               callback: Option<Box<StreamCallback<'a, I, O>>>)
               -> Result<Stream<'a, I, O>, PaError> {
     rportaudio::open_stream(
+      pa,
       input,
       output,
       sample_rate,
@@ -81,8 +100,11 @@ impl<'a, I: SampleType, O: SampleType> Stream<'a, I, O> {
     rportaudio::abort_stream(self)
   }
 
-  fn close(&self) -> PaResult {
-    rportaudio::close_stream(self)
+  /// Aborts the stream if needed and closes it
+  ///
+  /// Dropping the stream does the same, but any error is lost there.
+  pub fn close(mut self) -> PaResult {
+    rportaudio::close_stream(&mut self)
   }
 
   /// Returns wether the stream is stopped
@@ -107,23 +129,98 @@ impl<'a, I: SampleType, O: SampleType> Stream<'a, I, O> {
 
   /// Write the given buffer to the stream. This function blocks
   ///
+  /// An output underflow before the write is not an error, it is reported as
+  /// `IoStatus::OutputUnderflowed` and counted in `output_underflows`.
+  ///
   /// Possible Error codes:
   ///
   /// * `CanNotWriteToAnInputOnlyStream`: when num_output_channels = 0
   /// * `BadBufferPtr`: when buffer.len() is not a multiple of num_output_channels
   /// * Some other error given by PortAudio
-  pub fn write(&self, buffer: &[O]) -> PaResult {
+  pub fn write(&self, buffer: &[O]) -> Result<IoStatus, PaError> {
     rportaudio::write_stream(self, buffer)
   }
 
   /// Reads the requested number of frames from the input devices. This function blocks until
   /// the whole buffer has been filled.
   ///
+  /// An input overflow before the read is not an error, it is reported as
+  /// `IoStatus::InputOverflowed` and counted in `input_overflows`.
+  ///
   /// Will return `CanNotReadFromAnOutputOnlyStream` if num_input_channels = 0.
-  pub fn read(&self, frames: u32) -> Result<Vec<I>, PaError> {
+  pub fn read(&self, frames: u32) -> Result<(Vec<I>, IoStatus), PaError> {
     rportaudio::read_stream(self, frames)
   }
 
+  /// Fills the whole buffer with input frames without allocating, blocking until it is full
+  ///
+  /// Returns the number of frames read. Returns `BadBufferPtr` when buffer.len() is not a multiple
+  /// of num_input_channels.
+  pub fn read_into(&self, buffer: &mut [I]) -> Result<(usize, IoStatus), PaError> {
+    rportaudio::read_stream_into(self, buffer)
+  }
+
+  /// Reads the frames which are available without blocking, as far as they fit in the buffer
+  ///
+  /// Returns the number of frames read, which may be 0.
+  pub fn try_read(&self, buffer: &mut [I]) -> Result<(usize, IoStatus), PaError> {
+    rportaudio::try_read_stream(self, buffer)
+  }
+
+  /// Writes as many frames from the buffer as can be written without blocking
+  ///
+  /// Returns the number of frames written, which may be 0.
+  pub fn try_write(&self, buffer: &[O]) -> Result<(usize, IoStatus), PaError> {
+    rportaudio::try_write_stream(self, buffer)
+  }
+
+  /// Write frames of as many channels as the stream outputs, blocking like `write`
+  ///
+  /// Returns `InvalidChannelCount` when the channels of the frames differ from the stream.
+  pub fn write_frames(&self, frames: Frames<O>) -> Result<IoStatus, PaError> {
+    rportaudio::write_stream_frames(self, frames)
+  }
+
+  /// Fill frames of as many channels as the stream inputs, blocking like `read_into`
+  ///
+  /// Returns the number of frames read, and `InvalidChannelCount` when the channels of the frames
+  /// differ from the stream.
+  pub fn read_frames_into(&self, frames: FramesMut<I>) -> Result<(usize, IoStatus), PaError> {
+    rportaudio::read_stream_frames_into(self, frames)
+  }
+
+  /// Number of input channels, 0 for an output-only stream
+  pub fn input_channels(&self) -> u32 {
+    self.inputs
+  }
+
+  /// Number of output channels, 0 for an input-only stream
+  pub fn output_channels(&self) -> u32 {
+    self.outputs
+  }
+
+  /// Statistics of the callback, which can be shared with threads that can not use the stream
+  ///
+  /// They stay empty for streams using the blocking read and write methods.
+  pub fn statistics(&self) -> Arc<StreamStatistics> {
+    rportaudio::stream_statistics(self)
+  }
+
+  /// A snapshot of the statistics of the callback
+  pub fn stats(&self) -> StatsSnapshot {
+    rportaudio::stream_statistics(self).snapshot()
+  }
+
+  /// Number of reads since the stream was opened which reported an input overflow
+  pub fn input_overflows(&self) -> u64 {
+    rportaudio::stream_input_overflows(self)
+  }
+
+  /// Number of writes since the stream was opened which reported an output underflow
+  pub fn output_underflows(&self) -> u64 {
+    rportaudio::stream_output_underflows(self)
+  }
+
   /// Returns the cpu load the stream callback consumes. This will return 0.0 if the stream uses
   /// blocking read/write, or if an error occured.
   pub fn cpu_load(&self) -> f64 {
@@ -143,6 +240,8 @@ impl<'a, I: SampleType, O: SampleType> Stream<'a, I, O> {
   }
 
   /// Set a callback which is to be called when the StreamCallback finishes
+  ///
+  /// The stream has to be stopped, otherwise PaStreamIsNotStopped is returned.
   pub fn set_finished_callback(&mut self, finished_callback: Box<StreamFinishedCallback<'a>>) -> PaResult {
     rportaudio::set_stream_finished_callback(self, finished_callback)
   }
@@ -151,12 +250,148 @@ impl<'a, I: SampleType, O: SampleType> Stream<'a, I, O> {
   pub fn unset_finished_callback(&mut self) -> PaResult {
     rportaudio::unset_stream_finished_callback(self)
   }
+
+  /// Take the payload of a panic raised by the callback or the finished callback
+  ///
+  /// A panicking callback aborts the stream, this tells why it stopped. Only the first panic is
+  /// kept until it is taken.
+  pub fn take_panic(&self) -> Option<Box<dyn Any + Send>> {
+    rportaudio::take_stream_panic(self)
+  }
 }
 
 
+impl<'a, I: SampleType, O: SampleType> Drop for Stream<'a, I, O> {
+  fn drop(&mut self) {
+    // The user data is only released after this returns, when the callback can no longer run.
+    let _ = rportaudio::close_stream(self);
+  }
+}
+
 
 /// Returns Ok when the StreamParameters are supported. This ignores the latency field.
-pub fn is_format_supported<I: SampleType, O: SampleType>(input: Option<PaStreamParameters<I>>, output: Option<PaStreamParameters<O>>, sample_rate: f64) -> PaResult {
-  rportaudio::is_format_supported(input, output, sample_rate)
+pub fn is_format_supported<I: SampleType, O: SampleType>(pa: &PortAudio, input: Option<PaStreamParameters<I>>, output: Option<PaStreamParameters<O>>, sample_rate: f64) -> PaResult {
+  rportaudio::is_format_supported(pa, input, output, sample_rate)
 }
 
+
+#[cfg(test)]
+mod test {
+  use std::sync::Arc;
+  use std::sync::atomic::{AtomicUsize, Ordering};
+  use std::thread;
+  use std::time::Duration;
+
+  use crate::backend::VirtualBackend;
+  use crate::frames::{Frames, FramesMut};
+  use crate::rpa_error::ErrorCode;
+  use crate::types::*;
+
+  use super::StreamBuilder;
+
+  #[test]
+  fn test_read_into() {
+    let pa = PortAudio::with_backend(VirtualBackend::default()).unwrap();
+    let stream = StreamBuilder::<i16, i16>::new(&pa).input(0, 2).output(0, 2).sample_rate(8000.0).blocking().unwrap();
+    stream.start().unwrap();
+
+    let ramp: Vec<i16> = (1..=64).collect();
+    let (written, _) = stream.try_write(&ramp).unwrap();
+    assert!(written > 0 && written <= 32);
+
+    let mut buffer = [0i16; 16];
+    assert_eq!(stream.read_into(&mut buffer), Ok((8, IoStatus::Ok)));
+    assert_eq!(stream.read_into(&mut buffer[..3]).unwrap_err(), ErrorCode::PaBadBufferPtr);
+    assert_eq!(stream.try_read(&mut buffer[..0]), Ok((0, IoStatus::Ok)));
+    assert!(stream.try_read(&mut buffer).unwrap().0 <= 8);
+    assert_eq!(stream.try_write(&ramp[..3]).unwrap_err().operation(), Some("try_write"));
+  }
+
+  #[test]
+  fn test_xruns() {
+    let pa = PortAudio::with_backend(VirtualBackend::default()).unwrap();
+    let stream = StreamBuilder::<f32, f32>::new(&pa).input(0, 1).output(0, 1).sample_rate(8000.0).blocking().unwrap();
+    stream.start().unwrap();
+
+    assert_eq!(stream.write(&[0.5; 16]), Ok(IoStatus::Ok));
+    assert_eq!(stream.read(8).unwrap().1, IoStatus::Ok);
+
+    // Falling behind loses input and starves the output, but the transfers still happen
+    thread::sleep(Duration::from_millis(200));
+    let (input, status) = stream.read(8).unwrap();
+    assert_eq!((input.len(), status), (8, IoStatus::InputOverflowed));
+    assert_eq!(stream.write(&[0.5; 8]), Ok(IoStatus::OutputUnderflowed));
+    assert_eq!((stream.input_overflows(), stream.output_underflows()), (1, 1));
+  }
+
+  #[test]
+  fn test_stats_while_stopping() {
+    let pa = PortAudio::with_backend(VirtualBackend::default()).unwrap();
+    let callback = Box::new(|_input: &[f32], _output: &mut [f32], _time, _flags| PaStreamCallbackResult::Continue);
+    let stream = StreamBuilder::<f32, f32>::new(&pa).output(0, 2).frames_per_buffer(16).callback(callback).unwrap();
+    let statistics = stream.statistics();
+    let sampling = Arc::new(AtomicUsize::new(1));
+    let sampler_sampling = sampling.clone();
+    let sampler = thread::spawn(move || {
+      let mut snapshot = statistics.snapshot();
+      while sampler_sampling.load(Ordering::SeqCst) == 1 {
+        snapshot = statistics.snapshot();
+      }
+      snapshot
+    });
+
+    // Stopping joins the audio thread while it records the statistics
+    for _ in 0..5 {
+      stream.start().unwrap();
+      thread::sleep(Duration::from_millis(5));
+      stream.stop().unwrap();
+    }
+    sampling.store(0, Ordering::SeqCst);
+    let snapshot = sampler.join().unwrap();
+    assert!(snapshot.callbacks > 0);
+    assert!(!snapshot.cpu_load.is_empty());
+    assert!(snapshot.cpu_load.iter().all(|load| load.is_finite() && *load >= 0.0));
+  }
+
+  #[test]
+  fn test_i24() {
+    let pa = PortAudio::with_backend(VirtualBackend::default()).unwrap();
+    let stream = StreamBuilder::<I24, I24>::new(&pa).input(0, 2).output(0, 2).sample_rate(8000.0).blocking().unwrap();
+    stream.start().unwrap();
+
+    let samples = [I24::MIN, I24::MAX, I24::from_f32(0.5), I24::new(-0x123456).unwrap()];
+    assert_eq!(stream.write(&samples), Ok(IoStatus::Ok));
+    assert_eq!(stream.read(2).unwrap(), (samples.to_vec(), IoStatus::Ok));
+  }
+
+  #[test]
+  fn test_frames() {
+    let pa = PortAudio::with_backend(VirtualBackend::default()).unwrap();
+    let stream = StreamBuilder::<i16, i16>::new(&pa).input(0, 2).output(0, 2).sample_rate(8000.0).blocking().unwrap();
+    stream.start().unwrap();
+
+    let samples = [1, -1, 2, -2];
+    assert_eq!(stream.write_frames(Frames::new(&samples, 2).unwrap()), Ok(IoStatus::Ok));
+    assert_eq!(stream.write_frames(Frames::new(&samples, 1).unwrap()).unwrap_err(), ErrorCode::PaInvalidChannelCount);
+
+    let mut buffer = [0i16; 4];
+    assert_eq!(stream.read_frames_into(FramesMut::new(&mut buffer, 2).unwrap()), Ok((2, IoStatus::Ok)));
+    assert_eq!(buffer, samples);
+
+    let played = Arc::new(AtomicUsize::new(0));
+    let callback_played = played.clone();
+    let callback = Box::new(move |_input: Frames<f32>, mut output: FramesMut<f32>, _time, _flags| {
+      for sample in output.channel_mut(1) {
+        *sample = 0.5;
+      }
+      callback_played.fetch_add(output.frame_count(), Ordering::Relaxed);
+      PaStreamCallbackResult::Complete
+    });
+    let stream = StreamBuilder::<f32, f32>::new(&pa).output(0, 2).frames_per_buffer(32).frames_callback(callback).unwrap();
+    stream.start().unwrap();
+    while stream.is_active() == Ok(true) {
+      thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(played.load(Ordering::Relaxed), 32);
+  }
+}
@@ -0,0 +1,302 @@
+use std::any::Any;
+use std::slice;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::builder::StreamBuilder;
+use crate::rpa_error::{ErrorCode, PaError, PaResult, StreamBuilderError};
+use crate::rportaudio;
+use crate::stats::{StatsSnapshot, StreamStatistics};
+use crate::types::*;
+
+/// Callback of a TypedStream, which gets the buffers as frames of IN and OUT samples
+pub type TypedStreamCallback<'a, T, const IN: usize, const OUT: usize> =
+  dyn FnMut(&[[T; IN]], &mut [[T; OUT]], PaStreamTimeInfo, PaStreamCallbackFlags) -> PaStreamCallbackResult + 'a;
+
+/// A stream whose channel counts are part of its type
+///
+/// IN and OUT are the number of input and output channels, 0 leaves a direction out. Buffers are
+/// slices of frames, so a sample is found by frame and channel without any index arithmetic.
+/// The channel counts are checked against the devices when the stream is opened.
+///
+/// ```no_run
+/// use rportaudio::PortAudio;
+/// use rportaudio::stream::{TypedStream, FRAMES_PER_BUFFER_UNSPECIFIED};
+/// use rportaudio::types::PaStreamCallbackResult;
+///
+/// let pa = PortAudio::new().unwrap();
+/// let callback = Box::new(|input: &[[f32; 2]], output: &mut [[f32; 2]], _time, _flags| {
+///   for ([left, right], out) in input.iter().zip(output.iter_mut()) {
+///     *out = [*right, *left];
+///   }
+///   PaStreamCallbackResult::Continue
+/// });
+/// let stream = TypedStream::<f32, 2, 2>::open_default(&pa, 44100.0, FRAMES_PER_BUFFER_UNSPECIFIED, Some(callback)).unwrap();
+/// stream.start().unwrap();
+/// ```
+pub struct TypedStream<'a, T: SampleType, const IN: usize, const OUT: usize> {
+  stream: Stream<'a, T, T>,
+}
+
+impl<'a, T: SampleType + 'a, const IN: usize, const OUT: usize> TypedStream<'a, T, IN, OUT> {
+  /// Constructs a stream on the given devices
+  ///
+  /// A device is only used when its direction has channels, its channel count is checked like
+  /// in a StreamBuilder.
+  pub fn open(pa: &'a PortAudio,
+              input_device: DeviceIndex,
+              output_device: DeviceIndex,
+              sample_rate: f64,
+              frames_per_buffer: u64,
+              callback: Option<Box<TypedStreamCallback<'a, T, IN, OUT>>>)
+              -> Result<Self, StreamBuilderError> {
+    let mut builder = StreamBuilder::<T, T>::new(pa).sample_rate(sample_rate).frames_per_buffer(frames_per_buffer);
+    if IN > 0 {
+      builder = builder.input(input_device, IN as u32);
+    }
+    if OUT > 0 {
+      builder = builder.output(output_device, OUT as u32);
+    }
+    let stream = match callback {
+      Some(callback) => builder.callback(typed_callback(callback))?,
+      None => builder.blocking()?,
+    };
+    Ok(TypedStream { stream })
+  }
+
+  /// Constructs a stream using the default input and output devices
+  pub fn open_default(pa: &'a PortAudio,
+                      sample_rate: f64,
+                      frames_per_buffer: u64,
+                      callback: Option<Box<TypedStreamCallback<'a, T, IN, OUT>>>)
+                      -> Result<Self, StreamBuilderError> {
+    let unavailable = || StreamBuilderError::Open(ErrorCode::PaDeviceUnavailable.into());
+    let input_device = match IN {
+      0 => 0,
+      _ => rportaudio::default_input_device(pa).ok_or_else(unavailable)?,
+    };
+    let output_device = match OUT {
+      0 => 0,
+      _ => rportaudio::default_output_device(pa).ok_or_else(unavailable)?,
+    };
+    Self::open(pa, input_device, output_device, sample_rate, frames_per_buffer, callback)
+  }
+
+  /// The dynamically checked stream, whose buffers are flat slices of samples
+  pub fn into_inner(self) -> Stream<'a, T, T> {
+    self.stream
+  }
+
+  /// Starts the stream
+  pub fn start(&self) -> PaResult {
+    self.stream.start()
+  }
+
+  /// Stops the stream. It will block untill all audio has finished playing
+  pub fn stop(&self) -> PaResult {
+    self.stream.stop()
+  }
+
+  /// Stop stream immediately without waiting for the buffers to complete
+  pub fn abort(&self) -> PaResult {
+    self.stream.abort()
+  }
+
+  /// Aborts the stream if needed and closes it
+  pub fn close(self) -> PaResult {
+    self.stream.close()
+  }
+
+  /// Returns wether the stream is stopped
+  pub fn is_stopped(&self) -> Result<bool, PaError> {
+    self.stream.is_stopped()
+  }
+
+  /// Returns wether the stream is active
+  pub fn is_active(&self) -> Result<bool, PaError> {
+    self.stream.is_active()
+  }
+
+  /// Get the number of frames that can be read from the stream without waiting
+  pub fn num_read_available(&self) -> Result<u32, PaError> {
+    self.stream.num_read_available()
+  }
+
+  /// Get the number of frames that can be written to the stream without waiting
+  pub fn num_write_available(&self) -> Result<u32, PaError> {
+    self.stream.num_write_available()
+  }
+
+  /// Write the frames to the stream, blocking until they are consumed
+  pub fn write(&self, frames: &[[T; OUT]]) -> Result<IoStatus, PaError> {
+    self.stream.write(samples(frames))
+  }
+
+  /// Read the requested number of frames
+  pub fn read(&self, frames: u32) -> Result<(Vec<[T; IN]>, IoStatus), PaError>
+    where T: Copy + Default {
+    let mut buffer = vec![[T::default(); IN]; frames as usize];
+    let (_, status) = self.read_into(&mut buffer)?;
+    Ok((buffer, status))
+  }
+
+  /// Fill all the frames, blocking until they are read
+  ///
+  /// Returns the number of frames read.
+  pub fn read_into(&self, frames: &mut [[T; IN]]) -> Result<(usize, IoStatus), PaError> {
+    self.stream.read_into(samples_mut(frames))
+  }
+
+  /// Read the frames which are available without blocking, see `Stream::try_read`
+  pub fn try_read(&self, frames: &mut [[T; IN]]) -> Result<(usize, IoStatus), PaError> {
+    self.stream.try_read(samples_mut(frames))
+  }
+
+  /// Write as many frames as possible without blocking, see `Stream::try_write`
+  pub fn try_write(&self, frames: &[[T; OUT]]) -> Result<(usize, IoStatus), PaError> {
+    self.stream.try_write(samples(frames))
+  }
+
+  /// Number of reads which reported an input overflow
+  pub fn input_overflows(&self) -> u64 {
+    self.stream.input_overflows()
+  }
+
+  /// Number of writes which reported an output underflow
+  pub fn output_underflows(&self) -> u64 {
+    self.stream.output_underflows()
+  }
+
+  /// Returns the cpu load the stream callback consumes
+  pub fn cpu_load(&self) -> f64 {
+    self.stream.cpu_load()
+  }
+
+  /// Get the current timestamp of the stream
+  pub fn time(&self) -> Duration {
+    self.stream.time()
+  }
+
+  /// Get the actual latencies and sample rate
+  pub fn info(&self) -> Option<PaStreamInfo> {
+    self.stream.info()
+  }
+
+  /// Statistics of the callback, see `Stream::statistics`
+  pub fn statistics(&self) -> Arc<StreamStatistics> {
+    self.stream.statistics()
+  }
+
+  /// A snapshot of the statistics of the callback
+  pub fn stats(&self) -> StatsSnapshot {
+    self.stream.stats()
+  }
+
+  /// Set a callback which is to be called when the TypedStreamCallback finishes
+  pub fn set_finished_callback(&mut self, finished_callback: Box<StreamFinishedCallback<'a>>) -> PaResult {
+    self.stream.set_finished_callback(finished_callback)
+  }
+
+  /// Remove any previously attached finish callback
+  pub fn unset_finished_callback(&mut self) -> PaResult {
+    self.stream.unset_finished_callback()
+  }
+
+  /// Take the payload of a panic raised by the callback or the finished callback
+  pub fn take_panic(&self) -> Option<Box<dyn Any + Send>> {
+    self.stream.take_panic()
+  }
+}
+
+/// Hand the flat buffers of the stream to the callback as frames
+fn typed_callback<'a, T: 'a, const IN: usize, const OUT: usize>(mut callback: Box<TypedStreamCallback<'a, T, IN, OUT>>)
+                                                                -> Box<StreamCallback<'a, T, T>> {
+  Box::new(move |input: &[T], output: &mut [T], time, flags| {
+    callback(frames(input), frames_mut(output), time, flags)
+  })
+}
+
+/// The samples as frames of N samples, PortAudio always hands over whole frames
+fn frames<T, const N: usize>(samples: &[T]) -> &[[T; N]] {
+  // A direction without channels has no buffer
+  if N == 0 {
+    return &[];
+  }
+  debug_assert_eq!(samples.len() % N, 0);
+  // An array has the layout of N samples in a row
+  unsafe { slice::from_raw_parts(samples.as_ptr() as *const [T; N], samples.len() / N) }
+}
+
+/// The samples as frames of N samples, to be changed
+fn frames_mut<T, const N: usize>(samples: &mut [T]) -> &mut [[T; N]] {
+  if N == 0 {
+    return &mut [];
+  }
+  debug_assert_eq!(samples.len() % N, 0);
+  unsafe { slice::from_raw_parts_mut(samples.as_mut_ptr() as *mut [T; N], samples.len() / N) }
+}
+
+/// The frames as interleaved samples
+fn samples<T, const N: usize>(frames: &[[T; N]]) -> &[T] {
+  unsafe { slice::from_raw_parts(frames.as_ptr() as *const T, frames.len() * N) }
+}
+
+/// The frames as interleaved samples, to be changed
+fn samples_mut<T, const N: usize>(frames: &mut [[T; N]]) -> &mut [T] {
+  unsafe { slice::from_raw_parts_mut(frames.as_mut_ptr() as *mut T, frames.len() * N) }
+}
+
+#[cfg(test)]
+mod test {
+  use std::sync::{Arc, Mutex};
+  use std::thread;
+  use std::time::Duration;
+
+  use crate::backend::{VirtualBackend, VirtualDevice};
+  use crate::rpa_error::StreamBuilderError;
+  use crate::types::*;
+
+  use super::TypedStream;
+
+  #[test]
+  fn test_typed() {
+    let pa = PortAudio::with_backend(VirtualBackend::default()).unwrap();
+    let heard = Arc::new(Mutex::new(Vec::new()));
+    let callback_heard = heard.clone();
+    let callback = Box::new(move |input: &[[i16; 2]], output: &mut [[i16; 2]], _time, _flags| {
+      let mut heard = callback_heard.lock().unwrap();
+      heard.push(input[0]);
+      for frame in output.iter_mut() {
+        *frame = [heard.len() as i16, -(heard.len() as i16)];
+      }
+      match heard.len() {
+        3 => PaStreamCallbackResult::Complete,
+        _ => PaStreamCallbackResult::Continue,
+      }
+    });
+    let stream = TypedStream::<i16, 2, 2>::open_default(&pa, 48000.0, 32, Some(callback)).unwrap();
+    stream.start().unwrap();
+    while stream.is_active() == Ok(true) {
+      thread::sleep(Duration::from_millis(1));
+    }
+    stream.stop().unwrap();
+    assert_eq!(*heard.lock().unwrap(), vec![[0, 0], [1, -1], [2, -2]]);
+
+    // A fresh loopback, as the last buffers of the callback stream were not recorded
+    let pa = PortAudio::with_backend(VirtualBackend::default()).unwrap();
+    let stream = TypedStream::<f32, 2, 2>::open_default(&pa, 8000.0, 0, None).unwrap();
+    stream.start().unwrap();
+    assert_eq!(stream.write(&[[0.25, -0.25], [0.5, -0.5]]), Ok(IoStatus::Ok));
+    assert_eq!(stream.read(2).unwrap(), (vec![[0.25, -0.25], [0.5, -0.5]], IoStatus::Ok));
+  }
+
+  #[test]
+  fn test_channel_check() {
+    let backend = VirtualBackend::new().with_device(VirtualDevice::output("Mono", 1));
+    let pa = PortAudio::with_backend(backend).unwrap();
+    assert!(TypedStream::<f32, 0, 1>::open(&pa, 0, 0, 48000.0, 0, None).is_ok());
+    assert_eq!(TypedStream::<f32, 0, 2>::open(&pa, 0, 0, 48000.0, 0, None).err(),
+               Some(StreamBuilderError::InvalidOutputChannels { device: 0, requested: 2, max: 1 }));
+    assert_eq!(TypedStream::<f32, 0, 0>::open_default(&pa, 48000.0, 0, None).err(), Some(StreamBuilderError::NoDirection));
+  }
+}
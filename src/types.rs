@@ -1,8 +1,17 @@
+use std::any::Any;
+use std::cell::UnsafeCell;
+use std::collections::HashMap;
 use std::ffi::CStr;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::AtomicU64;
 use std::time::Duration;
 
 use crate::{kit, raw_portaudio};
-use crate::rpa_error::PaError;
+use crate::backend::{AudioBackend, RawStreamParameters, StreamHandle};
+use crate::rpa_error::{DeviceLookupError, PaError};
+use crate::stats::StreamStatistics;
 
 /// Index number of a Host API
 pub type HostApiIndex = u32;
@@ -12,37 +21,61 @@ pub type HostApiIndex = u32;
 pub type DeviceIndex = u32;
 
 
-//#[derive(Debug, Clone)]
-//pub struct PaVersionInfo {
-////  pub(crate) pa_version_info: *const raw_portaudio::PaVersionInfo
-//
-//  pub major: i32,
-//  pub minor: i32,
-//  pub sub_minor: i32,
-//  #[doc = "This is currently the Git revision hash but may change in the future."]
-//  #[doc = "The versionControlRevision is updated by running a script before compiling the library."]
-//  #[doc = "If the update does not occur, this value may refer to an earlier revision."]
-//  pub control_revision: String,
-//  #[doc = " Version as a string, for example \"PortAudio V19.5.0-devel, revision 1952M\""]
-//  pub text: String,
-//}
-//
-//impl PaVersionInfo {
-//  pub(crate) fn from_raw(raw: &raw_portaudio::PaVersionInfo) -> PaVersionInfo {
-//    PaVersionInfo {
-//      major: raw.versionMajor,
-//      minor: raw.versionMinor,
-//      sub_minor: raw.versionSubMinor,
-//      control_revision: String::from_utf8_lossy(unsafe { CStr::from_ptr(raw.versionControlRevision).to_bytes() }).into_owned(),
-//      text: String::from_utf8_lossy(unsafe { CStr::from_ptr(raw.versionText).to_bytes() }).into_owned(),
-//    }
-//  }
-//}
+/// Version of the PortAudio library
+///
+/// Versions are ordered by their numbers first.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VersionInfo {
+  pub major: i32,
+  pub minor: i32,
+  pub sub_minor: i32,
+  /// This is currently the Git revision hash but may change in the future
+  pub control_revision: String,
+  /// Version as a string, for example "PortAudio V19.5.0-devel, revision 1952M"
+  pub text: String,
+}
+
+impl VersionInfo {
+  pub(crate) fn from_raw(raw: &raw_portaudio::PaVersionInfo) -> VersionInfo {
+    let string = |p: *const ::libc::c_char| match p.is_null() {
+      true => String::new(),
+      false => String::from_utf8_lossy(unsafe { CStr::from_ptr(p).to_bytes() }).into_owned(),
+    };
+    VersionInfo {
+      major: raw.versionMajor,
+      minor: raw.versionMinor,
+      sub_minor: raw.versionSubMinor,
+      control_revision: string(raw.versionControlRevision),
+      text: string(raw.versionText),
+    }
+  }
+
+  /// Decode a version number of `Pa_GetVersion`, laid out as by `paMakeVersionNumber`
+  pub(crate) fn from_number(number: i32) -> VersionInfo {
+    VersionInfo {
+      major: (number >> 16) & 0xff,
+      minor: (number >> 8) & 0xff,
+      sub_minor: number & 0xff,
+      control_revision: String::new(),
+      text: String::new(),
+    }
+  }
+
+  /// Whether the bindings can use this version: the same major version as `HEADER_VERSION`, and
+  /// at least its minor and sub minor version
+  pub fn is_compatible(&self) -> bool {
+    let (major, minor, sub_minor) = HEADER_VERSION;
+    self.major == major && (self.minor, self.sub_minor) >= (minor, sub_minor)
+  }
+}
+
+/// Version of PortAudio the bundled `portaudio.h`, and so these bindings, describe
+pub const HEADER_VERSION: (i32, i32, i32) = (19, 6, 0);
 
 
 /// Possible Host API types
 #[repr(u32)]
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 #[allow(missing_docs)]
 pub enum HostApiType {
   InDevelopment = raw_portaudio::paInDevelopment,
@@ -83,8 +116,8 @@ pub enum HostApiType {
 
 impl HostApiType {
   /// Convert a static host API unique identifier, into a runtime host API index.
-  pub fn to_api_index(self) -> Result<u32, PaError> {
-    crate::rportaudio::hostapi_type_id_to_hostapi_index(self as u32)
+  pub fn to_api_index(self, pa: &PortAudio) -> Result<u32, PaError> {
+    crate::rportaudio::hostapi_type_id_to_hostapi_index(pa, self as u32)
   }
 
   /// Get the enum value corresponding to the u32
@@ -123,6 +156,53 @@ impl HostApiType {
       _ => HostApiType::Unknown,
     }
   }
+
+  /// Get the enum value corresponding to the name, as printed by Debug
+  pub fn from_name(name: &str) -> Option<HostApiType> {
+    const ALL: [HostApiType; 15] = [
+      HostApiType::InDevelopment, HostApiType::DirectSound, HostApiType::MME, HostApiType::ASIO,
+      HostApiType::SoundManager, HostApiType::CoreAudio, HostApiType::OSS, HostApiType::ALSA,
+      HostApiType::AL, HostApiType::BeOS, HostApiType::WDMKS, HostApiType::JACK,
+      HostApiType::WASAPI, HostApiType::AudioScienceHPI, HostApiType::Unknown,
+    ];
+    ALL.iter().cloned().find(|api| format!("{:?}", api) == name)
+  }
+}
+
+
+/// Identifies a device across restarts, unlike a DeviceIndex
+///
+/// The ordinal tells devices with the same name on the same host API apart, in the order of their
+/// indices. It serializes as `<host api>:<ordinal>:<name>`, for example `ALSA:0:default`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DeviceId {
+  /// Type of the host API the device belongs to
+  pub host_api: HostApiType,
+
+  /// Human readable name of the device
+  pub name: String,
+
+  /// Number of devices before this one with the same host API and name
+  pub ordinal: u32,
+}
+
+impl fmt::Display for DeviceId {
+  fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+    write!(f, "{:?}:{}:{}", self.host_api, self.ordinal, self.name)
+  }
+}
+
+impl FromStr for DeviceId {
+  type Err = DeviceLookupError;
+
+  fn from_str(s: &str) -> Result<DeviceId, DeviceLookupError> {
+    let invalid = || DeviceLookupError::InvalidId(s.to_string());
+    let mut parts = s.splitn(3, ':');
+    let host_api = parts.next().and_then(HostApiType::from_name).ok_or_else(invalid)?;
+    let ordinal = parts.next().and_then(|ordinal| ordinal.parse().ok()).ok_or_else(invalid)?;
+    let name = parts.next().ok_or_else(invalid)?;
+    Ok(DeviceId { host_api, name: name.to_string(), ordinal })
+  }
 }
 
 
@@ -170,7 +250,7 @@ impl PaHostApiInfo {
 
 
 /// Error info obtained by get_last_error
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct PaHostErrorInfo {
   /// The error code given
   pub code: i32,
@@ -192,6 +272,7 @@ impl PaHostErrorInfo {
 
 
 /// Information for a specific device
+#[derive(Debug, Clone)]
 pub struct PaDeviceInfo {
   pub struct_version: i32,
 
@@ -221,6 +302,12 @@ pub struct PaDeviceInfo {
 
   /// Default sample rate
   pub default_sample_rate: f64,
+
+  /// Whether this is the default input device
+  pub is_default_input: bool,
+
+  /// Whether this is the default output device
+  pub is_default_output: bool,
 }
 
 impl PaDeviceInfo {
@@ -236,6 +323,8 @@ impl PaDeviceInfo {
       default_high_input_latency: kit::pa_time_to_duration(raw.defaultHighInputLatency),
       default_high_output_latency: kit::pa_time_to_duration(raw.defaultHighOutputLatency),
       default_sample_rate: raw.defaultSampleRate,
+      is_default_input: false,
+      is_default_output: false,
     }
   }
 }
@@ -260,6 +349,177 @@ impl SampleType for i8 { fn sample_format() -> u64 { 0x00000010 } }
 
 impl SampleType for u8 { fn sample_format() -> u64 { 0x00000020 } }
 
+impl SampleType for I24 { fn sample_format() -> u64 { 0x00000004 } }
+
+
+/// A packed 24 bit sample, as PortAudio's paInt24
+///
+/// It is three bytes in the native byte order without any padding, so slices of it can be handed
+/// to PortAudio as they are. Floats map -1.0..1.0 onto the full range, like PortAudio does.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+#[repr(transparent)]
+pub struct I24([u8; 3]);
+
+impl I24 {
+  /// Silence
+  pub const ZERO: I24 = I24([0; 3]);
+
+  /// The smallest value, -2^23
+  pub const MIN: I24 = I24::from_i32_wrapping(-0x800000);
+
+  /// The largest value, 2^23 - 1
+  pub const MAX: I24 = I24::from_i32_wrapping(0x7fffff);
+
+  /// Keep the lower 24 bits of the value
+  const fn from_i32_wrapping(value: i32) -> I24 {
+    let bytes = value.to_ne_bytes();
+    if cfg!(target_endian = "little") {
+      I24([bytes[0], bytes[1], bytes[2]])
+    } else {
+      I24([bytes[1], bytes[2], bytes[3]])
+    }
+  }
+
+  /// The sample with the given value, None when it does not fit in 24 bits
+  pub fn new(value: i32) -> Option<I24> {
+    if (I24::MIN.to_i32()..=I24::MAX.to_i32()).contains(&value) {
+      Some(I24::from_i32_wrapping(value))
+    } else {
+      None
+    }
+  }
+
+  /// The sample closest to the given value
+  pub fn saturating_from_i32(value: i32) -> I24 {
+    I24::from_i32_wrapping(value.clamp(I24::MIN.to_i32(), I24::MAX.to_i32()))
+  }
+
+  /// The sample closest to a float in -1.0..1.0, NaN becomes 0
+  pub fn from_f32(value: f32) -> I24 {
+    // Converting a float to an integer saturates, and NaN becomes 0
+    I24::saturating_from_i32((value * 8388608.0).round() as i32)
+  }
+
+  /// The value, sign extended
+  pub fn to_i32(self) -> i32 {
+    let b = self.0;
+    let bytes = if cfg!(target_endian = "little") { [0, b[0], b[1], b[2]] } else { [b[0], b[1], b[2], 0] };
+    i32::from_ne_bytes(bytes) >> 8
+  }
+
+  /// The value as a float in -1.0..1.0
+  pub fn to_f32(self) -> f32 {
+    self.to_i32() as f32 / 8388608.0
+  }
+
+  /// The sample from its memory representation in native byte order
+  pub fn from_ne_bytes(bytes: [u8; 3]) -> I24 {
+    I24(bytes)
+  }
+
+  /// The memory representation in native byte order, as PortAudio uses it
+  pub fn to_ne_bytes(self) -> [u8; 3] {
+    self.0
+  }
+
+  /// The sample from its little endian representation
+  pub fn from_le_bytes(bytes: [u8; 3]) -> I24 {
+    I24::from_i32_wrapping(i32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]))
+  }
+
+  /// The little endian representation, as used in WAV files
+  pub fn to_le_bytes(self) -> [u8; 3] {
+    let bytes = self.to_i32().to_le_bytes();
+    [bytes[0], bytes[1], bytes[2]]
+  }
+}
+
+impl From<i16> for I24 {
+  fn from(value: i16) -> I24 {
+    I24::from_i32_wrapping(i32::from(value) << 8)
+  }
+}
+
+impl From<I24> for i32 {
+  fn from(value: I24) -> i32 {
+    value.to_i32()
+  }
+}
+
+impl From<I24> for f32 {
+  fn from(value: I24) -> f32 {
+    value.to_f32()
+  }
+}
+
+
+/// The sample formats of the SampleType implementations, to pick one at runtime
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum SampleFormat {
+  /// f32
+  F32,
+  /// i32
+  I32,
+  /// I24
+  I24,
+  /// i16
+  I16,
+  /// i8
+  I8,
+  /// u8
+  U8,
+}
+
+impl SampleFormat {
+  /// All formats, in order of preference
+  pub const ALL: [SampleFormat; 6] = [SampleFormat::F32, SampleFormat::I32, SampleFormat::I24, SampleFormat::I16, SampleFormat::I8, SampleFormat::U8];
+
+  /// The PortAudio flag which corresponds to the format
+  pub fn sample_format(self) -> u64 {
+    match self {
+      SampleFormat::F32 => <f32 as SampleType>::sample_format(),
+      SampleFormat::I32 => <i32 as SampleType>::sample_format(),
+      SampleFormat::I24 => <I24 as SampleType>::sample_format(),
+      SampleFormat::I16 => <i16 as SampleType>::sample_format(),
+      SampleFormat::I8 => <i8 as SampleType>::sample_format(),
+      SampleFormat::U8 => <u8 as SampleType>::sample_format(),
+    }
+  }
+}
+
+
+/// Sample rates probed by `device::capabilities`
+pub const STANDARD_SAMPLE_RATES: [f64; 13] = [
+  8000.0, 9600.0, 11025.0, 12000.0, 16000.0, 22050.0, 24000.0, 32000.0, 44100.0, 48000.0, 88200.0, 96000.0, 192000.0,
+];
+
+/// What one direction of a device supports, see `DeviceCapabilities`
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirectionCapabilities {
+  /// Supported standard sample rates
+  pub sample_rates: Vec<f64>,
+
+  /// Supported sample formats
+  pub sample_formats: Vec<SampleFormat>,
+
+  /// Supported channel counts
+  pub channel_counts: Vec<u32>,
+}
+
+/// What a device supports, as reported by `device::capabilities`
+///
+/// Every property is probed with the others at their defaults: the default sample rate of the
+/// device, the first supported format, and two channels or one for mono devices. The latency is
+/// the default low latency of the device.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceCapabilities {
+  /// Capabilities for input, None when the device has no input channels
+  pub input: Option<DirectionCapabilities>,
+
+  /// Capabilities for output, None when the device has no output channels
+  pub output: Option<DirectionCapabilities>,
+}
+
 
 /// Stream parameters to be used with Stream::open()
 #[derive(Copy, Clone)]
@@ -278,20 +538,19 @@ pub struct PaStreamParameters<T> {
 }
 
 impl<T: SampleType> PaStreamParameters<T> {
-  pub(crate) fn to_raw(&self) -> raw_portaudio::PaStreamParameters {
-    raw_portaudio::PaStreamParameters {
-      device: self.device as i32,
-      channelCount: self.channel_count as i32,
-      sampleFormat: <T as SampleType>::sample_format() as raw_portaudio::PaSampleFormat,
-      suggestedLatency: kit::duration_to_pa_time(self.suggested_latency),
-      hostApiSpecificStreamInfo: ::std::ptr::null_mut(),
+  pub(crate) fn to_raw(&self) -> RawStreamParameters {
+    RawStreamParameters {
+      device: self.device,
+      channel_count: self.channel_count,
+      sample_format: <T as SampleType>::sample_format(),
+      suggested_latency: self.suggested_latency,
     }
   }
 }
 
 
 /// Time information for various stream related values
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Default, Debug, PartialEq)]
 pub struct PaStreamTimeInfo {
   /// Timestamp for the ADC capture time of the first frame
   pub input_adc_time: Duration,
@@ -315,7 +574,7 @@ impl PaStreamTimeInfo {
 
 
 /// Information about the actual latency and sample rate values the stream uses
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct PaStreamInfo {
   /// Input latency
   pub input_latency: Duration,
@@ -341,6 +600,9 @@ impl PaStreamInfo {
 /// Callback to consume, process or generate audio
 pub type StreamCallback<'a, I, O> = FnMut(&[I], &mut [O], PaStreamTimeInfo, PaStreamCallbackFlags) -> PaStreamCallbackResult + 'a;
 
+/// Callback of a non-interleaved stream, which gets one slice per channel
+pub type PlanarStreamCallback<'a, I, O> = dyn FnMut(&[&[I]], &mut [&mut [O]], PaStreamTimeInfo, PaStreamCallbackFlags) -> PaStreamCallbackResult + 'a;
+
 
 
 
@@ -387,7 +649,7 @@ bitflags!(
 
 
 #[repr(u32)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum PaStreamCallbackResult {
   /// Continue invoking the callback
   Continue = raw_portaudio::paContinue,
@@ -413,21 +675,159 @@ pub enum PaStreamCallbackResult {
 /// Callback to be fired when a StreamCallback is stopped
 pub type StreamFinishedCallback<'a> = FnMut() + 'a;
 
+/// State shared with the callbacks running on the audio thread
+///
+/// The audio thread only ever gets a shared reference, the callbacks are reached through the
+/// UnsafeCells since only one side uses them at a time.
 pub(crate) struct StreamUserData<'a, I, O> {
   pub(crate) num_input: u32,
   pub(crate) num_output: u32,
-  pub(crate) callback: Option<Box<StreamCallback<'a, I, O>>>,
-  pub(crate) finished_callback: Option<Box<StreamFinishedCallback<'a>>>,
+  pub(crate) callback: UnsafeCell<Option<Box<StreamCallback<'a, I, O>>>>,
+  pub(crate) planar_callback: UnsafeCell<Option<PlanarCallback<'a, I, O>>>,
+  pub(crate) finished_callback: UnsafeCell<Option<Box<StreamFinishedCallback<'a>>>>,
+  /// Payload of the first panic caught in one of the callbacks
+  pub(crate) panic: Mutex<Option<Box<dyn Any + Send>>>,
+  pub(crate) stats: Arc<StreamStatistics>,
+}
+
+impl<'a, I, O> StreamUserData<'a, I, O> {
+  pub(crate) fn new(num_input: u32, num_output: u32, callback: Option<Box<StreamCallback<'a, I, O>>>) -> Self {
+    Self {
+      num_input,
+      num_output,
+      callback: UnsafeCell::new(callback),
+      planar_callback: UnsafeCell::new(None),
+      finished_callback: UnsafeCell::new(None),
+      panic: Mutex::new(None),
+      stats: Arc::new(StreamStatistics::new()),
+    }
+  }
+
+  pub(crate) fn new_planar(num_input: u32, num_output: u32, callback: Option<Box<PlanarStreamCallback<'a, I, O>>>) -> Self {
+    let planar_callback = callback.map(|callback| PlanarCallback {
+      callback,
+      inputs: (0..num_input).map(|_| Default::default()).collect(),
+      outputs: (0..num_output).map(|_| Default::default()).collect(),
+    });
+    Self { planar_callback: UnsafeCell::new(planar_callback), ..Self::new(num_input, num_output, None) }
+  }
+}
+
+/// A non-interleaved callback, with room for the channel slices so the audio thread does not
+/// allocate. The slices only point into the buffers of PortAudio during a call.
+pub(crate) struct PlanarCallback<'a, I, O> {
+  pub(crate) callback: Box<PlanarStreamCallback<'a, I, O>>,
+  pub(crate) inputs: Vec<&'a [I]>,
+  pub(crate) outputs: Vec<&'a mut [O]>,
+}
+
+
+/// A handle on an initialized PortAudio library
+///
+/// Every clone shares the same session. PortAudio is terminated once the last handle is dropped,
+/// and devices, host APIs and streams borrow a handle so they can not outlive it.
+#[derive(Clone)]
+pub struct PortAudio {
+  pub(crate) session: Arc<PortAudioSession>,
+}
+
+pub(crate) struct PortAudioSession {
+  pub(crate) backend: Box<dyn AudioBackend>,
+  /// Results of `device::capabilities`, device indices are stable within a session
+  pub(crate) capabilities: Mutex<HashMap<DeviceIndex, DeviceCapabilities>>,
+  /// Set once the backend was terminated explicitly
+  pub(crate) terminated: bool,
 }
 
 
 /// An object for an PortAudio stream
 ///
 /// Streams can have an input type I and output type O.
+///
+/// A Stream only has blocking reads and writes. For async IO open a bridged stream with
+/// `StreamBuilder::bridge`: its `InputReceiver` and `OutputSender` have `read_async` and
+/// `write_async`, and turn into a `futures::Stream` and `futures::Sink`. These only exist with
+/// the `futures` feature.
 pub struct Stream<'a, I: SampleType, O: SampleType> {
-  pub(crate) pa_stream: *mut raw_portaudio::PaStream,
+  pub(crate) portaudio: &'a PortAudio,
+  pub(crate) pa_stream: StreamHandle,
   pub(crate) inputs: u32,
   pub(crate) outputs: u32,
   pub(crate) user_data: Box<StreamUserData<'a, I, O>>,
+  pub(crate) xruns: XrunCounters,
+}
+
+
+/// How a blocking read or write went, when the data was transferred
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum IoStatus {
+  /// Nothing was lost
+  Ok,
+
+  /// Input was discarded before this read, so the data read is not contiguous with the last read
+  InputOverflowed,
+
+  /// The output ran dry before this write, so there was a gap in playback
+  OutputUnderflowed,
+}
+
+impl IoStatus {
+  /// Returns true on an overflow or underflow
+  pub fn is_xrun(self) -> bool {
+    self != IoStatus::Ok
+  }
+}
+
+
+/// Overflows and underflows reported by the blocking reads and writes of a stream
+#[derive(Debug, Default)]
+pub(crate) struct XrunCounters {
+  pub(crate) input_overflows: AtomicU64,
+  pub(crate) output_underflows: AtomicU64,
 }
 
+
+
+#[cfg(test)]
+mod test {
+  use super::{I24, VersionInfo};
+
+  fn version(major: i32, minor: i32, sub_minor: i32) -> VersionInfo {
+    VersionInfo { major, minor, sub_minor, control_revision: String::new(), text: String::new() }
+  }
+
+  #[test]
+  fn test_version_info() {
+    assert!(version(19, 6, 0) < version(19, 6, 1));
+    assert!(version(19, 7, 0) > version(19, 6, 9));
+    assert!(version(19, 6, 0).is_compatible());
+    assert!(version(19, 7, 0).is_compatible());
+    assert!(!version(19, 5, 0).is_compatible());
+    assert!(!version(20, 0, 0).is_compatible());
+    assert_eq!(VersionInfo::from_number(0x130600), version(19, 6, 0));
+    assert_eq!(VersionInfo::from_number(0x130401), version(19, 4, 1));
+  }
+
+  #[test]
+  fn test_i24() {
+    assert_eq!(::std::mem::size_of::<I24>(), 3);
+    assert_eq!(I24::MIN.to_i32(), -8388608);
+    assert_eq!(I24::MAX.to_i32(), 8388607);
+    assert_eq!(I24::new(8388608), None);
+    assert_eq!(I24::new(-1).map(I24::to_i32), Some(-1));
+    assert_eq!(I24::saturating_from_i32(i32::MIN), I24::MIN);
+    assert_eq!(I24::from(-2i16).to_i32(), -512);
+
+    let sample = I24::new(-0x123456).unwrap();
+    assert_eq!(sample.to_le_bytes(), [0xaa, 0xcb, 0xed]);
+    assert_eq!(I24::from_le_bytes(sample.to_le_bytes()), sample);
+    if cfg!(target_endian = "little") {
+      assert_eq!(sample.to_ne_bytes(), sample.to_le_bytes());
+    }
+
+    assert_eq!(I24::from_f32(-1.0), I24::MIN);
+    assert_eq!(I24::from_f32(2.0), I24::MAX);
+    assert_eq!(I24::from_f32(f32::NAN).to_i32(), 0);
+    assert_eq!(I24::from_f32(0.5).to_f32(), 0.5);
+  }
+}